@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+
+use bevy::{input::{keyboard::{Key, KeyboardInput}, ButtonState}, prelude::*};
+use bevy_renet::renet::{DefaultChannel, RenetClient};
+use boxman_shared::{chat::{ChatChannel, ChatLine, ChatLineKind, MAX_CHAT_MESSAGE_LEN}, data::ControlsConfig, protocol::ClientToServerMessage};
+
+use crate::controls::InputDevices;
+
+/// How many lines of chat we keep to scroll back through.
+const CHAT_SCROLLBACK: usize = 100;
+
+/// How many lines of chat the overlay shows at once.
+const CHAT_VISIBLE_LINES: usize = 8;
+
+/// Every line of chat we've received, oldest first.
+#[derive(Resource, Default)]
+pub struct ChatLog {
+    pub lines: VecDeque<ChatLine>,
+}
+
+impl ChatLog {
+    pub fn push(&mut self, line: ChatLine) {
+        self.lines.push_back(line);
+        if self.lines.len() > CHAT_SCROLLBACK {
+            self.lines.pop_front();
+        }
+    }
+}
+
+/// Whether the player is typing a message, and what they've typed so far.
+#[derive(Resource, Default)]
+pub struct ChatState {
+    /// The channel the message goes to, None while chat is closed.
+    pub channel: Option<ChatChannel>,
+    pub draft: String,
+
+    /// How many lines back from the newest the overlay is scrolled.
+    pub scroll: usize,
+}
+
+impl ChatState {
+    /// While chat is open, keys type into it instead of moving the character.
+    pub fn is_open(&self) -> bool {
+        self.channel.is_some()
+    }
+}
+
+#[derive(Component)]
+pub struct ChatText;
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ChatLine>();
+        app.init_resource::<ChatLog>();
+        app.init_resource::<ChatState>();
+        app.add_systems(Startup, spawn_chat_system);
+        app.add_systems(Update, (
+            chat_received_system,
+            chat_input_system.run_if(resource_exists::<ControlsConfig>),
+            chat_scroll_system,
+            chat_overlay_system,
+        ).chain());
+    }
+}
+
+fn spawn_chat_system(mut commands: Commands) {
+    commands.spawn((
+        ChatText,
+        Text::new(""),
+        TextFont {
+            font_size: 16.0,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(48.0),
+            left: Val::Px(12.0),
+            max_width: Val::Percent(50.0),
+            ..default()
+        },
+    ));
+}
+
+fn chat_received_system(
+    mut chat_lines: EventReader<ChatLine>,
+    mut chat_log: ResMut<ChatLog>,
+    mut chat_state: ResMut<ChatState>,
+) {
+    for line in chat_lines.read() {
+        chat_log.push(line.clone());
+
+        // Keep what the player is reading in view when scrolled back.
+        if chat_state.scroll > 0 {
+            chat_state.scroll = (chat_state.scroll + 1).min(chat_log.lines.len().saturating_sub(1));
+        }
+    }
+}
+
+/// Opens chat with its bindings, then types into it until Enter sends or Escape cancels.
+fn chat_input_system(
+    controls_config: Res<ControlsConfig>,
+    devices: InputDevices,
+    mut keyboard_events: EventReader<KeyboardInput>,
+    mut chat_state: ResMut<ChatState>,
+    client: Option<ResMut<RenetClient>>,
+) {
+    let Some(channel) = chat_state.channel else {
+        // The key that opens chat shouldn't be typed into it.
+        keyboard_events.clear();
+
+        let controls = &controls_config.controls;
+        if devices.just_pressed_input(&controls.chat) {
+            chat_state.channel = Some(ChatChannel::All);
+        } else if devices.just_pressed_input(&controls.team_chat) {
+            chat_state.channel = Some(ChatChannel::Team);
+        }
+        return;
+    };
+
+    for event in keyboard_events.read() {
+        if event.state != ButtonState::Pressed {
+            continue;
+        }
+
+        match &event.logical_key {
+            Key::Enter => {
+                let text = std::mem::take(&mut chat_state.draft);
+                chat_state.channel = None;
+                if let Some(mut client) = client {
+                    send_chat(&mut client, channel, text);
+                }
+                return;
+            }
+            Key::Escape => {
+                chat_state.draft.clear();
+                chat_state.channel = None;
+                return;
+            }
+            Key::Backspace => {
+                chat_state.draft.pop();
+            }
+            Key::Space => {
+                push_draft(&mut chat_state.draft, " ");
+            }
+            Key::Character(characters) => {
+                push_draft(&mut chat_state.draft, characters);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn push_draft(draft: &mut String, text: &str) {
+    for c in text.chars().filter(|c| !c.is_control()) {
+        if draft.chars().count() >= MAX_CHAT_MESSAGE_LEN {
+            return;
+        }
+        draft.push(c);
+    }
+}
+
+fn send_chat(client: &mut RenetClient, channel: ChatChannel, text: String) {
+    if text.trim().is_empty() {
+        return;
+    }
+
+    match (ClientToServerMessage::Chat { channel, text }).to_bytes() {
+        Ok(serialized) => {
+            client.send_message(DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Failed to serialize chat message: {}", e);
+        }
+    }
+}
+
+/// Page Up and Page Down scroll through older chat.
+fn chat_scroll_system(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    chat_log: Res<ChatLog>,
+    mut chat_state: ResMut<ChatState>,
+) {
+    let max_scroll = chat_log.lines.len().saturating_sub(CHAT_VISIBLE_LINES);
+    if keyboard.just_pressed(KeyCode::PageUp) {
+        chat_state.scroll = (chat_state.scroll + CHAT_VISIBLE_LINES).min(max_scroll);
+    }
+    if keyboard.just_pressed(KeyCode::PageDown) {
+        chat_state.scroll = chat_state.scroll.saturating_sub(CHAT_VISIBLE_LINES);
+    }
+}
+
+fn chat_overlay_system(
+    chat_log: Res<ChatLog>,
+    chat_state: Res<ChatState>,
+    mut chat_text: Query<&mut Text, With<ChatText>>,
+) {
+    if !chat_log.is_changed() && !chat_state.is_changed() {
+        return;
+    }
+
+    let Ok(mut text) = chat_text.get_single_mut() else {
+        return;
+    };
+
+    let end = chat_log.lines.len().saturating_sub(chat_state.scroll);
+    let start = end.saturating_sub(CHAT_VISIBLE_LINES);
+    let mut lines: Vec<String> = chat_log.lines.range(start..end).map(format_chat_line).collect();
+
+    if chat_state.scroll > 0 {
+        lines.push(format!("({} newer, Page Down)", chat_state.scroll));
+    }
+
+    match chat_state.channel {
+        Some(ChatChannel::All) => lines.push(format!("Say: {}_", chat_state.draft)),
+        Some(ChatChannel::Team) => lines.push(format!("Team: {}_", chat_state.draft)),
+        None => {}
+    }
+
+    text.0 = lines.join("\n");
+}
+
+fn format_chat_line(line: &ChatLine) -> String {
+    match &line.kind {
+        ChatLineKind::Player { sender_name, channel: ChatChannel::All, .. } => format!("{}: {}", sender_name, line.text),
+        ChatLineKind::Player { sender_name, channel: ChatChannel::Team, .. } => format!("[Team] {}: {}", sender_name, line.text),
+        ChatLineKind::Announcement => format!("[Server] {}", line.text),
+        ChatLineKind::Notice => format!("* {}", line.text),
+    }
+}
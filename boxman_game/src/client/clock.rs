@@ -0,0 +1,175 @@
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetClient};
+use boxman_shared::{data::MultiplayerConfig, protocol::{ClientToServerMessage, MAX_TICK_RATE_ADJUSTMENT}};
+
+use crate::player::InputHistory;
+
+/// How much the tick rate changes per input we're off from the target buffer.
+const TICK_RATE_GAIN: f64 = 0.01;
+
+/// How many snapshot intervals remote characters are rendered behind at least,
+/// so there's always a newer snapshot to interpolate towards even when one is lost.
+const BUFFERED_SNAPSHOT_INTERVALS: f64 = 2.0;
+
+/// Our estimate of the server's clock and the connection to it, measured by ping/pong.
+#[derive(Resource, Default)]
+pub struct ServerClock {
+    /// Server time minus our own real time, None until the first pong.
+    pub offset: Option<f64>,
+
+    /// Smoothed round trip time in seconds.
+    pub rtt: f64,
+
+    /// Smoothed deviation of the round trip time in seconds.
+    pub jitter: f64,
+
+    /// Our input tick minus the server's current tick.
+    pub tick_offset: i64,
+
+    /// How many of our inputs were waiting in the server's queue when it answered the last ping.
+    pub buffered_inputs: u32,
+
+    /// Smoothed seconds between snapshots we've received. The server lowers our snapshot rate
+    /// when the connection struggles, and lost snapshots make the gaps longer too.
+    pub snapshot_interval: f64,
+}
+
+impl ServerClock {
+    /// Feeds in a pong. `client_time` is when we sent the ping and `receive_time` is now, both in our real time.
+    pub fn observe(&mut self, client_time: f64, server_time: f64, receive_time: f64, smoothing: f32) {
+        let smoothing = smoothing as f64;
+        let rtt = (receive_time - client_time).max(0.0);
+
+        // The server answered about half a round trip ago.
+        let sample = server_time + rtt * 0.5 - receive_time;
+
+        match self.offset {
+            Some(offset) => {
+                self.jitter += ((rtt - self.rtt).abs() - self.jitter) * smoothing;
+                self.rtt += (rtt - self.rtt) * smoothing;
+                self.offset = Some(offset + (sample - offset) * smoothing);
+            }
+            None => {
+                self.rtt = rtt;
+                self.jitter = 0.0;
+                self.offset = Some(sample);
+            }
+        }
+    }
+
+    /// Feeds in the time between the newest snapshot and the one before it, in server seconds.
+    pub fn observe_snapshot_interval(&mut self, interval: f64, smoothing: f32) {
+        if self.snapshot_interval == 0.0 {
+            self.snapshot_interval = interval;
+        } else {
+            self.snapshot_interval += (interval - self.snapshot_interval) * smoothing as f64;
+        }
+    }
+
+    /// The server's time right now.
+    pub fn server_time(&self, local_time: f64) -> Option<f64> {
+        self.offset.map(|offset| local_time + offset)
+    }
+
+    /// The server time remote characters are rendered at. That's behind what's arriving from the server
+    /// by `interpolation_delay`, plus the jitter so that late snapshots still make it in time.
+    /// The delay grows when snapshots arrive too far apart for it to cover.
+    pub fn render_time(&self, local_time: f64, interpolation_delay: f32) -> Option<f64> {
+        let delay = (interpolation_delay as f64).max(self.snapshot_interval * BUFFERED_SNAPSHOT_INTERVALS);
+        self.server_time(local_time)
+            .map(|server_time| server_time - self.rtt * 0.5 - self.jitter - delay)
+    }
+}
+
+/// A pong from the server, see `ServerToClientMessage::Pong`.
+#[derive(Event)]
+pub struct PongEvent {
+    pub client_time: f64,
+    pub server_tick: u64,
+    pub server_time: f64,
+    pub buffered_inputs: u32,
+}
+
+pub struct ClockPlugin;
+
+impl Plugin for ClockPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerClock>();
+        app.add_event::<PongEvent>();
+        app.add_systems(Update, (
+            ping_system.run_if(resource_exists::<RenetClient>),
+            pong_system,
+        ).run_if(resource_exists::<MultiplayerConfig>));
+    }
+}
+
+fn ping_system(
+    cfg: Res<MultiplayerConfig>,
+    real_time: Res<Time<Real>>,
+    mut client: ResMut<RenetClient>,
+    mut last_ping: Local<Option<f64>>,
+) {
+    if !client.is_connected() {
+        return;
+    }
+
+    let now = real_time.elapsed_secs_f64();
+    if last_ping.is_some_and(|last_ping| now - last_ping < cfg.ping_interval as f64) {
+        return;
+    }
+    *last_ping = Some(now);
+
+    match (ClientToServerMessage::Ping { client_time: now }).to_bytes() {
+        Ok(serialized) => {
+            client.send_message(DefaultChannel::Unreliable, serialized);
+        }
+        Err(e) => {
+            error!("Failed to serialize ping: {}", e);
+        }
+    }
+}
+
+/// Updates the clock estimate, then nudges how fast we run fixed ticks so that our inputs
+/// reach the server just before it needs them.
+fn pong_system(
+    cfg: Res<MultiplayerConfig>,
+    real_time: Res<Time<Real>>,
+    fixed_time: Res<Time<Fixed>>,
+    input_history: Option<Res<InputHistory>>,
+    mut virtual_time: ResMut<Time<Virtual>>,
+    mut server_clock: ResMut<ServerClock>,
+    mut pong_events: EventReader<PongEvent>,
+) {
+    let Some(pong) = pong_events.read().max_by(|a, b| a.client_time.total_cmp(&b.client_time)) else {
+        return;
+    };
+
+    let now = real_time.elapsed_secs_f64();
+    server_clock.observe(pong.client_time, pong.server_time, now, cfg.server_clock_smoothing);
+    server_clock.buffered_inputs = pong.buffered_inputs;
+
+    let timestep = fixed_time.timestep().as_secs_f64();
+    if let (Some(input_history), Some(server_time)) = (input_history, server_clock.server_time(now)) {
+        let server_tick = (server_time / timestep).round() as i64;
+        server_clock.tick_offset = input_history.next_input_id as i64 - server_tick;
+    }
+
+    // Fixed ticks are paced by virtual time, so speeding it up a little runs our ticks faster
+    // without changing the length of a tick that the simulation sees.
+    let target = cfg.input_buffer_target as f64 + server_clock.jitter / timestep;
+    let error = target - pong.buffered_inputs as f64;
+    // Any faster and the server takes us for flooding it with inputs.
+    let max_adjustment = cfg.max_tick_rate_adjustment.clamp(0.0, MAX_TICK_RATE_ADJUSTMENT) as f64;
+    let adjustment = (error * TICK_RATE_GAIN).clamp(-max_adjustment, max_adjustment);
+    virtual_time.set_relative_speed_f64(1.0 + adjustment);
+
+    debug!(
+        "rtt {:.1}ms jitter {:.1}ms tick offset {} (server tick {}) buffered {} speed {:.3}",
+        server_clock.rtt * 1000.0,
+        server_clock.jitter * 1000.0,
+        server_clock.tick_offset,
+        pong.server_tick,
+        pong.buffered_inputs,
+        1.0 + adjustment,
+    );
+}
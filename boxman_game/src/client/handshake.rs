@@ -0,0 +1,156 @@
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetClient};
+use boxman_shared::{level::CurrentLevel, protocol::{content_hash, ClientToServerMessage, Handshake, LevelHash}};
+
+/// The server's `Handshake` message.
+#[derive(Event)]
+pub struct ServerHandshakeEvent(pub Handshake);
+
+/// The level the server said it's running, our copy has to match it.
+#[derive(Resource)]
+pub struct ExpectedLevel(pub LevelHash);
+
+/// Why we were disconnected, shown to the player. The first reason sticks, it's usually the most useful one.
+#[derive(Resource, Default)]
+pub struct DisconnectReason(pub Option<String>);
+
+impl DisconnectReason {
+    pub fn set(&mut self, reason: impl Into<String>) {
+        if self.0.is_none() {
+            self.0 = Some(reason.into());
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct DisconnectReasonText;
+
+pub struct HandshakePlugin;
+
+impl Plugin for HandshakePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ServerHandshakeEvent>();
+        app.init_resource::<DisconnectReason>();
+        app.add_systems(Startup, spawn_disconnect_reason_system);
+        app.add_systems(Update, (
+            send_handshake_system.run_if(resource_exists::<RenetClient>),
+            server_handshake_system.run_if(resource_exists::<RenetClient>),
+            level_hash_system
+                .run_if(resource_exists::<RenetClient>)
+                .run_if(resource_exists::<ExpectedLevel>)
+                .run_if(resource_exists::<CurrentLevel>),
+            disconnected_system.run_if(resource_exists::<RenetClient>),
+            disconnect_reason_text_system.run_if(resource_changed::<DisconnectReason>),
+        ).chain());
+    }
+}
+
+/// Disconnects with a reason the player will see.
+fn disconnect(client: &mut RenetClient, disconnect_reason: &mut DisconnectReason, reason: String) {
+    error!("{}", reason);
+    disconnect_reason.set(reason);
+    client.disconnect();
+}
+
+/// Sends our handshake as soon as we're connected.
+fn send_handshake_system(
+    mut sent: Local<bool>,
+    mut client: ResMut<RenetClient>,
+) {
+    if *sent || !client.is_connected() {
+        return;
+    }
+    *sent = true;
+
+    match ClientToServerMessage::Handshake(Handshake::new(None)).to_bytes() {
+        Ok(serialized) => {
+            client.send_message(DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Error serializing message: {}", e);
+        }
+    }
+}
+
+fn server_handshake_system(
+    mut commands: Commands,
+    mut server_handshake_events: EventReader<ServerHandshakeEvent>,
+    mut client: ResMut<RenetClient>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+) {
+    let local = Handshake::new(None);
+
+    for ServerHandshakeEvent(handshake) in server_handshake_events.read() {
+        if let Err(reason) = handshake.check(&local) {
+            disconnect(&mut client, &mut disconnect_reason, format!("Incompatible server: {}", reason));
+            continue;
+        }
+
+        if let Some(level) = &handshake.level {
+            commands.insert_resource(ExpectedLevel(level.clone()));
+        }
+    }
+}
+
+/// Checks our copy of the level against the server's, once it's loaded.
+fn level_hash_system(
+    expected_level: Res<ExpectedLevel>,
+    current_level: Res<CurrentLevel>,
+    mut client: ResMut<RenetClient>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+) {
+    if !expected_level.is_changed() && !current_level.is_changed() {
+        return;
+    }
+
+    // Still loading the level the server asked for.
+    if current_level.name != expected_level.0.name || client.is_disconnected() {
+        return;
+    }
+
+    if content_hash(&current_level.level) != expected_level.0.hash {
+        disconnect(&mut client, &mut disconnect_reason, format!("Incompatible server: our copy of level {} is different", current_level.name));
+    }
+}
+
+/// Keeps renet's reason when we're disconnected without being told why, e.g. on a timeout.
+fn disconnected_system(
+    client: Res<RenetClient>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+) {
+    if disconnect_reason.0.is_none() {
+        if let Some(reason) = client.disconnect_reason() {
+            disconnect_reason.set(format!("Disconnected: {:?}", reason));
+        }
+    }
+}
+
+fn spawn_disconnect_reason_system(mut commands: Commands) {
+    commands.spawn(Node {
+        position_type: PositionType::Absolute,
+        width: Val::Percent(100.0),
+        height: Val::Percent(100.0),
+        justify_content: JustifyContent::Center,
+        align_items: AlignItems::Center,
+        ..default()
+    }).with_children(|parent| {
+        parent.spawn((
+            DisconnectReasonText,
+            Text::new(""),
+            TextFont {
+                font_size: 24.0,
+                ..default()
+            },
+            TextColor(Color::srgb(1.0, 0.4, 0.4)),
+        ));
+    });
+}
+
+fn disconnect_reason_text_system(
+    disconnect_reason: Res<DisconnectReason>,
+    mut texts: Query<&mut Text, With<DisconnectReasonText>>,
+) {
+    for mut text in texts.iter_mut() {
+        text.0 = disconnect_reason.0.clone().unwrap_or_default();
+    }
+}
@@ -0,0 +1,141 @@
+use std::{collections::VecDeque, f32::consts::{PI, TAU}};
+
+use bevy::prelude::*;
+use boxman_shared::{
+    character::Character,
+    data::MultiplayerConfig,
+    moveable_sim::{MoveableSimulation, SimulationDisabled},
+};
+
+use crate::moveable_vis::visuals_interpolation_system;
+use super::{clock::ServerClock, LocalClientId};
+
+/// A server state of a remote character, `time` is in server seconds.
+#[derive(Debug, Clone, Copy)]
+pub struct BufferedState {
+    pub time: f64,
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub yaw: f32,
+}
+
+/// Server states received for a remote character, oldest first.
+#[derive(Component, Default)]
+pub struct SnapshotBuffer {
+    pub states: VecDeque<BufferedState>,
+}
+
+impl SnapshotBuffer {
+    pub fn push(&mut self, state: BufferedState, max_len: usize) {
+        // Out of order states are dropped, they'd only make us go back in time.
+        if self.states.back().is_some_and(|newest| newest.time >= state.time) {
+            return;
+        }
+
+        self.states.push_back(state);
+        while self.states.len() > max_len.max(2) {
+            self.states.pop_front();
+        }
+    }
+
+    /// Drops states that can no longer be interpolated from, keeping the one right before `render_time`.
+    pub fn prune(&mut self, render_time: f64) {
+        while self.states.len() > 2 && self.states[1].time <= render_time {
+            self.states.pop_front();
+        }
+    }
+
+    /// The state at `render_time`, interpolated between the two states around it.
+    /// Past the newest state it extrapolates along the newest velocity for up to `max_extrapolation` seconds.
+    pub fn sample(&self, render_time: f64, max_extrapolation: f32) -> Option<BufferedState> {
+        let oldest = self.states.front()?;
+        let newest = self.states.back()?;
+
+        if render_time <= oldest.time {
+            return Some(*oldest);
+        }
+
+        if render_time >= newest.time {
+            let extrapolation = ((render_time - newest.time) as f32).min(max_extrapolation);
+            return Some(BufferedState {
+                time: render_time,
+                position: newest.position + newest.velocity * extrapolation,
+                ..*newest
+            });
+        }
+
+        let (from, to) = self.states.iter()
+            .zip(self.states.iter().skip(1))
+            .find(|(_, to)| to.time >= render_time)?;
+
+        let t = ((render_time - from.time) / (to.time - from.time)) as f32;
+        Some(BufferedState {
+            time: render_time,
+            position: from.position.lerp(to.position, t),
+            velocity: from.velocity.lerp(to.velocity, t),
+            yaw: lerp_angle(from.yaw, to.yaw, t),
+        })
+    }
+}
+
+/// Interpolates between two angles (in radians) the short way around.
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    let delta = (to - from + PI).rem_euclid(TAU) - PI;
+    from + delta * t
+}
+
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (
+            setup_remote_characters_system,
+            interpolate_remote_characters_system
+                .run_if(resource_exists::<MultiplayerConfig>)
+                .before(visuals_interpolation_system),
+        ));
+    }
+}
+
+/// Remote characters are driven by their buffered server states instead of the simulation.
+fn setup_remote_characters_system(
+    mut commands: Commands,
+    local_client_id: Option<Res<LocalClientId>>,
+    characters: Query<(Entity, &Character), Added<Character>>,
+) {
+    let Some(local_client_id) = local_client_id else {
+        return;
+    };
+
+    for (entity, character) in characters.iter() {
+        if character.client_id != local_client_id.0 {
+            commands.entity(entity).insert((
+                SnapshotBuffer::default(),
+                SimulationDisabled,
+            ));
+        }
+    }
+}
+
+fn interpolate_remote_characters_system(
+    real_time: Res<Time<Real>>,
+    cfg: Res<MultiplayerConfig>,
+    server_clock: Res<ServerClock>,
+    mut characters: Query<(&mut SnapshotBuffer, &mut Transform, &mut MoveableSimulation)>,
+) {
+    let Some(render_time) = server_clock.render_time(real_time.elapsed_secs_f64(), cfg.interpolation_delay) else {
+        return;
+    };
+
+    for (mut buffer, mut transform, mut simulation) in characters.iter_mut() {
+        buffer.prune(render_time);
+
+        if let Some(state) = buffer.sample(render_time, cfg.max_extrapolation) {
+            // Setting both ends means the visuals just show this exact state.
+            transform.translation = state.position;
+            transform.rotation = Quat::from_rotation_y(state.yaw);
+            simulation.last_translation = state.position;
+            simulation.last_rotation = transform.rotation;
+        }
+    }
+}
@@ -1,21 +1,44 @@
+pub mod chat;
+pub mod clock;
+pub mod handshake;
+pub mod interpolation;
+pub mod roster;
 pub mod snapshot;
 
 use std::{
     error::Error,
-    net::{SocketAddr, UdpSocket},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    path::PathBuf,
     time::SystemTime,
 };
 
 use bevy::prelude::*;
 use bevy_renet::{
-    netcode::{ClientAuthentication, NetcodeClientPlugin, NetcodeClientTransport},
+    netcode::{ClientAuthentication, ConnectToken, NetcodeClientPlugin, NetcodeClientTransport, NETCODE_KEY_BYTES},
     renet::{ConnectionConfig, DefaultChannel, RenetClient},
     RenetClientPlugin,
 };
-use boxman_shared::{prelude::{CharacterDespawnEvent, CharacterSpawnEvent}, protocol::{ClientToServerMessage, ServerToClientMessage}, utils::GameClient};
+use boxman_server::auth::{generate_player_key, request_connect_token, validate_player_key};
+use directories::ProjectDirs;
+use boxman_shared::{chat::ChatLine, conditioner::{LinkConditioner, NetworkConditions}, data::MultiplayerConfig, health::CharacterDeathEvent, level::LoadLevelEvent, prelude::{CharacterDespawnEvent, CharacterSpawnEvent}, protocol::{packed::MAX_INPUTS_PER_PACKET, ClientToServerMessage, LevelHash, PlayerUserData, ServerToClientMessage, PROTOCOL_ID}, roster::{PlayerProfile, Roster}, utils::{AuthPort, GameClient, InMemoryTransport, ServerIp, ServerPort}, weapons::WeaponFiredEvent};
 
-use crate::{player::InputHistory, ServerIp, ServerPort};
-use snapshot::{SnapshotDiffEvent, SnapshotPlugin};
+use crate::player::InputHistory;
+use chat::ChatPlugin;
+use clock::{ClockPlugin, PongEvent};
+use handshake::{DisconnectReason, ExpectedLevel, HandshakePlugin, ServerHandshakeEvent};
+use interpolation::InterpolationPlugin;
+use roster::RosterPlugin;
+use snapshot::{ReceivedSnapshots, SnapshotDiffEvent, SnapshotPlugin};
+
+/// How long the connect token we make for ourselves when simulating network conditions is valid, in seconds.
+const SIMULATED_TOKEN_EXPIRE_SECONDS: u64 = 300;
+
+/// Seconds without packets before that connection times out.
+const SIMULATED_TIMEOUT_SECONDS: i32 = 15;
+
+/// Our client id, the one the server knows us and our character by.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalClientId(pub u64);
 
 pub struct GameClientPlugin;
 
@@ -24,58 +47,166 @@ impl Plugin for GameClientPlugin {
         app.add_plugins((
             RenetClientPlugin, 
             NetcodeClientPlugin, 
-            SnapshotPlugin
+            SnapshotPlugin,
+            InterpolationPlugin,
+            ClockPlugin,
+            RosterPlugin,
+            ChatPlugin,
+            HandshakePlugin,
         ));
         app.insert_resource(GameClient);
-        app.add_systems(Startup, startup_system);
+        app.add_systems(Startup, startup_system.run_if(not(resource_exists::<InMemoryTransport>)));
         app.add_systems(Update, (
             message_receiver_system.run_if(resource_exists::<RenetClient>),
-            send_input_system.run_if(resource_exists::<RenetClient>)
+            send_input_system
+                .run_if(resource_exists::<RenetClient>)
+                .run_if(resource_exists::<MultiplayerConfig>),
         ));
     }
 }
 
-fn startup_system(mut commands: Commands, server_ip: Res<ServerIp>, server_port: Res<ServerPort>) {
-    if let Err(e) = connect_to_server(&mut commands, &server_ip, &server_port) {
+fn startup_system(
+    mut commands: Commands,
+    server_ip: Res<ServerIp>,
+    server_port: Res<ServerPort>,
+    profile: Res<PlayerProfile>,
+    auth_port: Option<Res<AuthPort>>,
+    network_conditions: Option<Res<NetworkConditions>>,
+) {
+    if let Err(e) = connect_to_server(&mut commands, &server_ip, &server_port, &profile, auth_port.as_deref(), network_conditions.as_deref()) {
         error!("Failed to connect to server: {}", e);
     }
 }
 
+/// Connects with a token from the server's token service if `auth_port` is given, unsecured otherwise.
+/// With `conditions` an unsecured connection goes through a `LinkConditioner`, secure tokens only allow the server's own address.
 pub fn connect_to_server(
     commands: &mut Commands,
     server_ip: &ServerIp,
     server_port: &ServerPort,
+    profile: &PlayerProfile,
+    auth_port: Option<&AuthPort>,
+    conditions: Option<&NetworkConditions>,
 ) -> Result<(), Box<dyn Error>> {
     info!("Connecting to server at {}", server_ip.0);
     let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-    let authentication = ClientAuthentication::Unsecure {
-        server_addr: SocketAddr::new(server_ip.0.parse()?, server_port.0),
-        user_data: None,
-        protocol_id: 0,
-        client_id: current_time.as_millis() as u64,
+    let server_addr = SocketAddr::new(server_ip.0.parse()?, server_port.0);
+    let authentication = match auth_port {
+        Some(auth_port) => ClientAuthentication::Secure {
+            connect_token: request_connect_token(SocketAddr::new(server_addr.ip(), auth_port.0), &player_key(), &profile.name)?,
+        },
+        None => {
+            let client_id = rand::random::<u64>();
+            let user_data = PlayerUserData {
+                player_id: client_id,
+                name: profile.name.clone(),
+            };
+
+            match conditions {
+                Some(conditions) => {
+                    let loopback: IpAddr = if server_addr.is_ipv6() { Ipv6Addr::LOCALHOST.into() } else { Ipv4Addr::LOCALHOST.into() };
+                    let conditioner = LinkConditioner::spawn(UdpSocket::bind(SocketAddr::new(loopback, 0))?, server_addr, conditions.clone())?;
+                    info!("Simulating network conditions: {:?}", conditioner.conditions());
+
+                    // What an unsecured connection would use, except we send to the conditioner first.
+                    // The server still finds its own address in the token, so it lets us in.
+                    let connect_token = ConnectToken::generate(
+                        current_time,
+                        PROTOCOL_ID,
+                        SIMULATED_TOKEN_EXPIRE_SECONDS,
+                        client_id,
+                        SIMULATED_TIMEOUT_SECONDS,
+                        vec![conditioner.local_addr(), server_addr],
+                        Some(&user_data.to_bytes()),
+                        &[0; NETCODE_KEY_BYTES],
+                    )?;
+                    commands.insert_resource(conditioner);
+                    ClientAuthentication::Secure { connect_token }
+                }
+                None => ClientAuthentication::Unsecure {
+                    server_addr,
+                    user_data: Some(user_data.to_bytes()),
+                    protocol_id: PROTOCOL_ID,
+                    client_id,
+                },
+            }
+        }
     };
 
-    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    if conditions.is_some() && auth_port.is_some() {
+        warn!("Network conditions can't be simulated with a secure connect token, connecting directly");
+    }
+
+    // Any local address of the same family as the server's, so IPv6 servers and other machines work too.
+    let local_ip = if server_addr.is_ipv6() { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() };
+    let socket = UdpSocket::bind(SocketAddr::new(local_ip, 0))?;
     let transport = NetcodeClientTransport::new(current_time, authentication, socket)?;
     let client = RenetClient::new(ConnectionConfig::default());
+    commands.insert_resource(LocalClientId(transport.client_id()));
     commands.insert_resource(transport);
     commands.insert_resource(client);
     Ok(())
 }
 
+/// Where our player key is kept, next to the user's controls.
+fn player_key_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "Riverside Games", "Boxman")
+        .map(|dirs| dirs.config_dir().join("player_key"))
+}
+
+/// The secret that gets us the same player id from a secure server's token service every time.
+/// It's made on first use. If it can't be saved we get a new id next time, but can still play.
+fn player_key() -> String {
+    let Some(path) = player_key_path() else {
+        warn!("No config directory available, using a player key for this session only");
+        return generate_player_key();
+    };
+
+    if let Some(player_key) = std::fs::read_to_string(&path).ok().as_deref().and_then(validate_player_key) {
+        return player_key.to_string();
+    }
+
+    let player_key = generate_player_key();
+    let saved = path.parent().map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, &player_key));
+    if let Err(e) = saved {
+        error!("Failed to save player key to {}: {}, using it for this session only", path.display(), e);
+    }
+    player_key
+}
+
 pub fn message_receiver_system(
+    mut commands: Commands,
     mut renet_client: ResMut<RenetClient>,
     mut snapshot_diff_events: EventWriter<SnapshotDiffEvent>,
     mut character_spawn_events: EventWriter<CharacterSpawnEvent>,
     mut character_despawn_events: EventWriter<CharacterDespawnEvent>,
+    mut load_level_events: EventWriter<LoadLevelEvent>,
+    mut weapon_fired_events: EventWriter<WeaponFiredEvent>,
+    mut character_death_events: EventWriter<CharacterDeathEvent>,
+    mut pong_events: EventWriter<PongEvent>,
+    mut chat_lines: EventWriter<ChatLine>,
+    mut server_handshake_events: EventWriter<ServerHandshakeEvent>,
+    mut disconnect_reason: ResMut<DisconnectReason>,
+    mut roster: ResMut<Roster>,
+    mut received_snapshots: ResMut<ReceivedSnapshots>,
+    mut fixed_time: ResMut<Time<Fixed>>,
 ) {
     while let Some(message) = renet_client.receive_message(DefaultChannel::Unreliable) {
-        match bincode::deserialize::<ServerToClientMessage>(&message) {
+        match ServerToClientMessage::from_bytes(&message) {
             Ok(ServerToClientMessage::SnapshotDiff(snapshot_diff)) => {
                 snapshot_diff_events.send(SnapshotDiffEvent(snapshot_diff));
             }
-            Ok(ServerToClientMessage::PlayerJoined { id, name }) => {
-                info!("Player joined: {} {}", id, name);
+            Ok(ServerToClientMessage::WeaponFired(weapon_fired_event)) => {
+                weapon_fired_events.send(weapon_fired_event);
+            }
+            Ok(ServerToClientMessage::Pong { client_time, server_tick, server_time, buffered_inputs }) => {
+                pong_events.send(PongEvent {
+                    client_time,
+                    server_tick,
+                    server_time,
+                    buffered_inputs,
+                });
             }
             Ok(_) => {
                 error!("Received unknown message from server on unreliable channel");
@@ -87,13 +218,55 @@ pub fn message_receiver_system(
     }
 
     while let Some(message) = renet_client.receive_message(DefaultChannel::ReliableOrdered) {
-        match bincode::deserialize::<ServerToClientMessage>(&message) {
+        match ServerToClientMessage::from_bytes(&message) {
+            Ok(ServerToClientMessage::Handshake(handshake)) => {
+                server_handshake_events.send(ServerHandshakeEvent(handshake));
+            }
             Ok(ServerToClientMessage::SpawnCharacter(character_spawn_event)) => {
                 character_spawn_events.send(character_spawn_event.clone());
             }
             Ok(ServerToClientMessage::DespawnCharacter(character_despawn_event)) => {
                 character_despawn_events.send(character_despawn_event.clone());
             }
+            Ok(ServerToClientMessage::GameplayConfig { tick_rate, snapshot_history, character, weapons }) => {
+                info!("Using the server's gameplay config at {} Hz", tick_rate);
+                if tick_rate.is_finite() && tick_rate > 0.0 {
+                    fixed_time.set_timestep_hz(tick_rate);
+                }
+                received_snapshots.capacity = snapshot_history as usize;
+                commands.insert_resource(character);
+                commands.insert_resource(weapons);
+            }
+            Ok(ServerToClientMessage::LoadLevel { name, hash }) => {
+                commands.insert_resource(ExpectedLevel(LevelHash {
+                    name: name.clone(),
+                    hash,
+                }));
+                load_level_events.send(LoadLevelEvent(name));
+            }
+            Ok(ServerToClientMessage::PlayerJoined(info)) => {
+                info!("Player joined: {} {}", info.id, info.name);
+                roster.insert(info);
+            }
+            Ok(ServerToClientMessage::PlayerUpdated(info)) => {
+                roster.insert(info);
+            }
+            Ok(ServerToClientMessage::PlayerLeft { id }) => {
+                if let Some(info) = roster.remove(id) {
+                    info!("Player left: {} {}", info.id, info.name);
+                }
+            }
+            Ok(ServerToClientMessage::CharacterDied(character_death_event)) => {
+                info!("Player {} was killed by {:?}", character_death_event.client_id, character_death_event.killer_id);
+                character_death_events.send(character_death_event);
+            }
+            Ok(ServerToClientMessage::Kicked { reason }) => {
+                warn!("Kicked from the server: {}", reason);
+                disconnect_reason.set(reason);
+            }
+            Ok(ServerToClientMessage::Chat(line)) => {
+                chat_lines.send(line);
+            }
             Ok(_) => {
                 error!("Received unknown message from server on reliable channel");
             }
@@ -104,25 +277,39 @@ pub fn message_receiver_system(
     }
 }
 
+/// Sends the newest inputs the server hasn't acked, whenever there's a new one.
+/// Each input goes out in several packets, so losing one doesn't lose the input.
 pub fn send_input_system(
+    cfg: Res<MultiplayerConfig>,
     client: Option<ResMut<RenetClient>>,
     player_inputs: Option<ResMut<InputHistory>>,
 ) {
     if let Some(mut client) = client {
         if let Some(mut player_inputs) = player_inputs {
-            for input in player_inputs.inputs.iter_mut() {
-                if input.send_count > 0 {
-                    continue;
-                }
-                match bincode::serialize(&ClientToServerMessage::PlayerInput(input.clone())) {
-                    Ok(serialized) => {
-                        client.send_message(DefaultChannel::Unreliable, serialized);
-                        input.send_count = 1;
-                    }
-                    Err(e) => {
-                        error!("Failed to serialize input: {}", e);
+            if !player_inputs.inputs.iter().any(|input| input.send_count == 0) {
+                return;
+            }
+
+            let acked_input_id = player_inputs.acked_input_id;
+            let len = player_inputs.inputs.len();
+            let first_unacked = player_inputs.inputs.iter()
+                .position(|input| acked_input_id.is_none_or(|acked_id| input.id > acked_id))
+                .unwrap_or(len);
+            // The server won't take more than its limit, whatever our config says.
+            let max_inputs = cfg.max_inputs_per_packet.clamp(1, MAX_INPUTS_PER_PACKET);
+            let start = first_unacked.max(len.saturating_sub(max_inputs));
+            let inputs = &mut player_inputs.inputs[start..];
+
+            match ClientToServerMessage::PlayerInputs(inputs.to_vec()).to_bytes() {
+                Ok(serialized) => {
+                    client.send_message(DefaultChannel::Unreliable, serialized);
+                    for input in inputs.iter_mut() {
+                        input.send_count += 1;
                     }
                 }
+                Err(e) => {
+                    error!("Failed to serialize input: {}", e);
+                }
             }
         }
     }
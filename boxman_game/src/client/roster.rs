@@ -0,0 +1,42 @@
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetClient};
+use boxman_shared::{protocol::ClientToServerMessage, roster::{PlayerProfile, Roster}};
+
+pub struct RosterPlugin;
+
+impl Plugin for RosterPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Roster>();
+        app.add_systems(Update,
+            send_profile_system
+                .run_if(resource_exists::<RenetClient>)
+                .run_if(resource_exists::<PlayerProfile>)
+        );
+    }
+}
+
+/// Sends our profile once we're connected, and again whenever it changes.
+fn send_profile_system(
+    profile: Res<PlayerProfile>,
+    mut client: ResMut<RenetClient>,
+    mut sent: Local<bool>,
+) {
+    if !client.is_connected() {
+        *sent = false;
+        return;
+    }
+
+    if *sent && !profile.is_changed() {
+        return;
+    }
+
+    match ClientToServerMessage::SetProfile(profile.clone()).to_bytes() {
+        Ok(serialized) => {
+            client.send_message(DefaultChannel::ReliableOrdered, serialized);
+            *sent = true;
+        }
+        Err(e) => {
+            error!("Failed to serialize profile: {}", e);
+        }
+    }
+}
@@ -1,17 +1,62 @@
+use std::collections::VecDeque;
+
 use avian3d::prelude::SpatialQuery;
 use bevy::prelude::*;
-use bevy_renet::netcode::NetcodeClientTransport;
 use boxman_shared::{
     moveable_sim::{move_simulation, MoveableSimulation, MoveableVisuals}, 
-    character::{alter_character_velocity, LocalCharacter, Character}, 
-    snapshot::{CharacterSnapshotDiff, SnapshotDiff}
+    character::{alter_character_velocity, CharacterDespawnEvent, CharacterJump, CharacterSpawnEvent, LocalCharacter, Character}, 
+    health::{Armor, Health},
+    replication::{EntitySnapshot, NetworkEntityMap, Replicated, ReplicationRegistry},
+    snapshot::{CharacterSnapshot, Snapshot, SnapshotDiff, POSITION_PRECISION}
 };
 use boxman_shared::data::{MultiplayerConfig, CharacterConfig};
 use crate::player::InputHistory;
+use super::{clock::ServerClock, interpolation::{BufferedState, SnapshotBuffer}, LocalClientId};
+
+/// How many decoded snapshots we hold on to until the server tells us how many it encodes against.
+const DEFAULT_RECEIVED_SNAPSHOT_CAPACITY: usize = 64;
 
 #[derive(Resource)]
 pub struct LastProcessedSnapshotId(pub Option<u64>);
 
+/// Snapshots we've decoded, oldest first. The server encodes new snapshots against one of these.
+#[derive(Resource)]
+pub struct ReceivedSnapshots {
+    pub snapshots: VecDeque<Snapshot>,
+
+    /// The server's snapshot history, from its gameplay config. Any fewer and it could encode against one we dropped.
+    pub capacity: usize,
+}
+
+impl Default for ReceivedSnapshots {
+    fn default() -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            capacity: DEFAULT_RECEIVED_SNAPSHOT_CAPACITY,
+        }
+    }
+}
+
+impl ReceivedSnapshots {
+    pub fn get(&self, id: u64) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.id == id)
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > self.capacity.max(1) {
+            self.snapshots.pop_front();
+        }
+    }
+}
+
+/// How often the server disagreed with our prediction and we had to replay inputs from its state.
+#[derive(Resource, Default, Debug)]
+pub struct Corrections {
+    pub count: u32,
+    pub last_distance: f32,
+}
+
 #[derive(Event)]
 pub struct SnapshotDiffEvent(pub SnapshotDiff);
 
@@ -20,12 +65,17 @@ pub struct SnapshotPlugin;
 impl Plugin for SnapshotPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(LastProcessedSnapshotId(None));
+        app.init_resource::<ReceivedSnapshots>();
+        app.init_resource::<Corrections>();
         app.add_event::<SnapshotDiffEvent>();
         app.add_systems(
             FixedPostUpdate, 
-            snapshot_system
-                .run_if(resource_exists::<MultiplayerConfig>)
-                .run_if(resource_exists::<CharacterConfig>)
+            (
+                snapshot_system
+                    .run_if(resource_exists::<MultiplayerConfig>)
+                    .run_if(resource_exists::<CharacterConfig>),
+                replicated_entities_system,
+            ).chain()
         );
     }
 }
@@ -35,137 +85,293 @@ fn snapshot_system(
     character_config: Res<CharacterConfig>,
     spatial_query: SpatialQuery,
     mut last_processed_snapshot_id: ResMut<LastProcessedSnapshotId>,
+    mut received_snapshots: ResMut<ReceivedSnapshots>,
     mut snapshot_diff_events: EventReader<SnapshotDiffEvent>,
-    mut characters: Query<(Entity, &mut Transform, &Character, &mut MoveableSimulation), (Without<LocalCharacter>, Without<MoveableVisuals>)>,
-    mut local_characters: Query<(Entity, &mut Transform, &mut MoveableSimulation), (With<LocalCharacter>, Without<MoveableVisuals>)>,
-    transport: Option<Res<NetcodeClientTransport>>,
+    mut characters: Query<(Entity, &mut Transform, &Character, &mut MoveableSimulation, &mut Health, &mut Armor, Option<&mut SnapshotBuffer>), (Without<LocalCharacter>, Without<MoveableVisuals>)>,
+    mut local_characters: Query<(Entity, &mut Transform, &mut MoveableSimulation, &mut CharacterJump, &mut Health, &mut Armor), (With<LocalCharacter>, Without<MoveableVisuals>)>,
+    local_client_id: Option<Res<LocalClientId>>,
     fixed_time: Res<Time<Fixed>>,
     mut input_history: ResMut<InputHistory>,
+    mut corrections: ResMut<Corrections>,
+    server_clock: Option<ResMut<ServerClock>>,
+    mut character_spawn_events: EventWriter<CharacterSpawnEvent>,
+    mut character_despawn_events: EventWriter<CharacterDespawnEvent>,
 ) {
-    if let Some(transport) = transport {
-        let latest_snapshot = snapshot_diff_events.read()
-            .max_by_key(|event| event.0.id);
+    if let Some(local_client_id) = local_client_id {
+        let previous_snapshot_id = last_processed_snapshot_id.0;
 
-        if let Some(event) = latest_snapshot {
-            let snapshot_diff = &event.0;
-            
+        let mut snapshot_diffs: Vec<&SnapshotDiff> = snapshot_diff_events.read()
+            .map(|event| &event.0)
+            .collect();
+        snapshot_diffs.sort_by_key(|snapshot_diff| snapshot_diff.id);
+
+        // Decode everything that arrived in order, an older diff may be the baseline of a newer one.
+        let mut latest_snapshot = None;
+        for snapshot_diff in snapshot_diffs {
             // Skip if we've already processed a newer snapshot
-            if let Some(last_id) = last_processed_snapshot_id.0 {
-                if snapshot_diff.id <= last_id {
-                    return;
-                }
+            if last_processed_snapshot_id.0.is_some_and(|last_id| snapshot_diff.id <= last_id) {
+                continue;
             }
-            
-            last_processed_snapshot_id.0 = Some(snapshot_diff.id);
-            
-            for player_snapshot_diff in snapshot_diff.character_snapshots.iter() {
-                let is_local = player_snapshot_diff.client_id == transport.client_id();
-
-                if is_local {
-                    reconcile_local_character(
-                        &cfg,
-                        &character_config,
-                        &spatial_query,
-                        &fixed_time,
-                        &mut local_characters,
-                        player_snapshot_diff,
-                        &mut input_history,
-                        snapshot_diff.acked_input_id,
-                    );
-                } else {
-                    let existing_controller = characters.iter_mut()
-                        .find(|(_, _, pc, _)| pc.client_id == player_snapshot_diff.client_id);
-    
-                    if let Some((_, mut transform, _, mut simulation)) = existing_controller {
-                        if let Some(position) = player_snapshot_diff.position {
-                            transform.translation = position;
+
+            let baseline = snapshot_diff.baseline_id.and_then(|baseline_id| received_snapshots.get(baseline_id));
+            let Some(snapshot) = snapshot_diff.apply(baseline) else {
+                // We no longer have what it was encoded against, the server moves on once it sees a newer ack.
+                warn!("Dropping snapshot {}, missing baseline {:?}", snapshot_diff.id, snapshot_diff.baseline_id);
+                continue;
+            };
+
+            last_processed_snapshot_id.0 = Some(snapshot.id);
+            received_snapshots.push(snapshot.clone());
+            latest_snapshot = Some((snapshot, snapshot_diff.acked_input_id, &snapshot_diff.stale_client_ids));
+        }
+
+        let Some((snapshot, acked_input_id, stale_client_ids)) = latest_snapshot else {
+            return;
+        };
+
+        if let Some(acked_input_id) = acked_input_id {
+            if input_history.acked_input_id.is_none_or(|last_acked_id| acked_input_id > last_acked_id) {
+                input_history.acked_input_id = Some(acked_input_id);
+            }
+        }
+
+        // The server takes one snapshot per fixed tick, so this is when it was taken in server time.
+        let server_time = snapshot.id as f64 * fixed_time.timestep().as_secs_f64();
+
+        // It doesn't send all of them though, interpolation has to cover the gaps.
+        if let (Some(mut server_clock), Some(previous_snapshot_id)) = (server_clock, previous_snapshot_id) {
+            let interval = (snapshot.id - previous_snapshot_id) as f64 * fixed_time.timestep().as_secs_f64();
+            server_clock.observe_snapshot_interval(interval, cfg.server_clock_smoothing);
+        }
+
+        // The server only sends characters that are relevant to us, the rest shouldn't linger around.
+        for (_, _, character, _, _, _, _) in characters.iter() {
+            if !snapshot.character_snapshots.iter().any(|c| c.client_id == character.client_id) {
+                character_despawn_events.send(CharacterDespawnEvent {
+                    client_id: character.client_id,
+                });
+            }
+        }
+
+        for character_snapshot in snapshot.character_snapshots.iter() {
+            let is_local = character_snapshot.client_id == local_client_id.0;
+
+            // Carried over from an older snapshot, there's nothing new to interpolate towards.
+            if !is_local && stale_client_ids.contains(&character_snapshot.client_id) {
+                continue;
+            }
+
+            if is_local {
+                reconcile_local_character(
+                    &character_config,
+                    &spatial_query,
+                    &fixed_time,
+                    &mut local_characters,
+                    character_snapshot,
+                    &mut input_history,
+                    &mut corrections,
+                    acked_input_id,
+                );
+            } else {
+                let existing_controller = characters.iter_mut()
+                    .find(|(_, _, pc, _, _, _, _)| pc.client_id == character_snapshot.client_id);
+
+                if let Some((_, mut transform, _, mut simulation, mut health, mut armor, buffer)) = existing_controller {
+                    let respawned = apply_character_status(&mut health, &mut armor, character_snapshot);
+                    simulation.velocity = character_snapshot.velocity();
+
+                    if let Some(mut buffer) = buffer {
+                        // Respawning is a teleport, don't interpolate across the map.
+                        if respawned {
+                            buffer.states.clear();
                         }
-                        if let Some(velocity) = player_snapshot_diff.velocity {
-                            simulation.velocity = velocity;
+
+                        buffer.push(BufferedState {
+                            time: server_time,
+                            position: character_snapshot.translation(),
+                            velocity: simulation.velocity,
+                            yaw: character_snapshot.yaw(),
+                        }, cfg.interpolation_buffer_size);
+                    } else {
+                        transform.translation = character_snapshot.translation();
+                        if respawned {
+                            simulation.last_translation = transform.translation;
                         }
                     }
+                } else {
+                    // Just became relevant, its visuals and interpolation buffer are added once it's spawned.
+                    character_spawn_events.send(CharacterSpawnEvent {
+                        client_id: character_snapshot.client_id,
+                        position: character_snapshot.translation(),
+                        yaw: character_snapshot.yaw(),
+                    });
                 }
             }
         }
     }
 }
 
+/// Spawns, updates and despawns replicated entities to match the newest snapshot.
+/// They take on the newest state straight away, only characters are interpolated.
+fn replicated_entities_system(
+    mut commands: Commands,
+    registry: Res<ReplicationRegistry>,
+    last_processed_snapshot_id: Res<LastProcessedSnapshotId>,
+    received_snapshots: Res<ReceivedSnapshots>,
+    mut entity_map: ResMut<NetworkEntityMap>,
+    mut applied_snapshot_id: Local<Option<u64>>,
+    mut applied_entities: Local<Vec<EntitySnapshot>>,
+) {
+    if last_processed_snapshot_id.0 == *applied_snapshot_id {
+        return;
+    }
+
+    let Some(snapshot) = last_processed_snapshot_id.0.and_then(|id| received_snapshots.get(id)) else {
+        return;
+    };
+    *applied_snapshot_id = Some(snapshot.id);
+
+    for entity_snapshot in snapshot.entities.iter() {
+        let mut previous = applied_entities.iter().find(|e| e.id == entity_snapshot.id);
+
+        let entity = match entity_map.get(entity_snapshot.id) {
+            Some(entity) => entity,
+            None => {
+                let entity = commands.spawn((entity_snapshot.id, Replicated)).id();
+                entity_map.insert(entity_snapshot.id, entity);
+                previous = None;
+                entity
+            }
+        };
+
+        let Some(diff) = entity_snapshot.diff(previous) else {
+            continue;
+        };
+
+        let Some(mut entity_commands) = commands.get_entity(entity) else {
+            continue;
+        };
+
+        for component in diff.changed.iter() {
+            if let Err(e) = registry.insert(&mut entity_commands, component) {
+                error!("Failed to apply replicated component to {:?}: {}", entity_snapshot.id, e);
+            }
+        }
+
+        for kind in diff.removed_kinds.iter() {
+            registry.remove(&mut entity_commands, *kind);
+        }
+    }
+
+    for previous in applied_entities.iter() {
+        if snapshot.entities.iter().any(|e| e.id == previous.id) {
+            continue;
+        }
+
+        if let Some(entity) = entity_map.remove(previous.id) {
+            if let Some(entity_commands) = commands.get_entity(entity) {
+                entity_commands.despawn_recursive();
+            }
+        }
+    }
+
+    *applied_entities = snapshot.entities.clone();
+}
+
 fn reconcile_local_character(
-    cfg: &MultiplayerConfig,
     character_config: &CharacterConfig,
     spatial_query: &SpatialQuery,
     fixed_time: &Time<Fixed>,
-    character_query: &mut Query<(Entity, &mut Transform, &mut MoveableSimulation), (With<LocalCharacter>, Without<MoveableVisuals>)>,
-    snapshot: &CharacterSnapshotDiff,
+    character_query: &mut Query<(Entity, &mut Transform, &mut MoveableSimulation, &mut CharacterJump, &mut Health, &mut Armor), (With<LocalCharacter>, Without<MoveableVisuals>)>,
+    snapshot: &CharacterSnapshot,
     input_history: &mut InputHistory,
+    corrections: &mut Corrections,
     acked_input_id: Option<u32>,
 ) {
-    if let Ok((entity, mut transform, mut simulation)) = character_query.get_single_mut() {
-        if let Some(position) = snapshot.position {
-            if let Some(acked_input_id) = acked_input_id {
-                let acked_input = input_history.inputs.iter().find(|input| input.id == acked_input_id);
-
-                if let Some(acked_input) = acked_input {
-                    let correction_distance = position.distance(acked_input.post_move_position);
-                    
-                    if correction_distance < 0.0001 {
-                        //info!("-");
-                        return;
-                    }
-                    //info!("Correction Distance: {}", correction_distance);
+    if let Ok((entity, mut transform, mut simulation, mut jump, mut health, mut armor)) = character_query.get_single_mut() {
+        let respawned = apply_character_status(&mut health, &mut armor, snapshot);
 
-                    simulation.is_visually_correcting = true;
+        let Some(acked_input_id) = acked_input_id else {
+            return;
+        };
 
-                    if snapshot.velocity.is_none() {
-                        simulation.velocity = acked_input.post_move_velocity;
-                    }
+        let position = snapshot.translation();
+        let acked_input = input_history.inputs.iter().find(|input| input.id == acked_input_id);
 
-                    if snapshot.grounded.is_none() {
-                        simulation.grounded = acked_input.post_move_grounded;
-                    }
-                }
+        if let Some(acked_input) = acked_input {
+            let correction_distance = position.distance(acked_input.post_move_position);
 
-                transform.translation = position;
+            // Quantization alone moves us by up to half a step per axis, that's not worth a replay.
+            if correction_distance < POSITION_PRECISION {
+                //info!("-");
+                return;
+            }
+            //info!("Correction Distance: {}", correction_distance);
+            corrections.count += 1;
+            corrections.last_distance = correction_distance;
 
-                if let Some(velocity) = snapshot.velocity {
-                    simulation.velocity = velocity;
-                }
-                if let Some(grounded) = snapshot.grounded {
-                    simulation.grounded = grounded;
-                }
+            simulation.is_visually_correcting = true;
 
-                let stored_rotation = transform.rotation;
+            // The server doesn't replicate jump timers, our own record of them is the best baseline.
+            *jump = acked_input.post_move_jump;
+        }
 
-                for input in input_history.inputs.iter_mut() {
-                    if input.id <= acked_input_id {
-                        continue;
-                    }
-                    
-                    alter_character_velocity(
-                        &mut simulation, 
-                        input, 
-                        fixed_time.delta_secs(), 
-                        character_config.speed,
-                        character_config.acceleration,
-                        character_config.friction,
-                    );
-
-                    move_simulation(
-                        &fixed_time,
-                        &spatial_query,
-                        &mut simulation,
-                        &mut transform,
-                        entity
-                    );
-                    
-                    input.post_move_velocity = simulation.velocity;
-                    input.post_move_position = transform.translation;
-                    input.post_move_grounded = simulation.grounded;
-                }
+        transform.translation = position;
+        simulation.velocity = snapshot.velocity();
+        simulation.grounded = snapshot.grounded;
+
+        let stored_rotation = transform.rotation;
 
-                // Since we moved a bunch, its just safe to reset the rotation to the stored value.
-                transform.rotation = stored_rotation;
+        for input in input_history.inputs.iter_mut() {
+            if input.id <= acked_input_id {
+                continue;
             }
+            
+            if !health.is_dead() {
+                alter_character_velocity(
+                    &mut simulation, 
+                    &mut jump,
+                    input, 
+                    fixed_time.delta_secs(), 
+                    character_config,
+                );
+            }
+
+            move_simulation(
+                &fixed_time,
+                &spatial_query,
+                &mut simulation,
+                &mut transform,
+                entity
+            );
+            
+            input.post_move_velocity = simulation.velocity;
+            input.post_move_position = transform.translation;
+            input.post_move_grounded = simulation.grounded;
+            input.post_move_jump = *jump;
+        }
+
+        // Since we moved a bunch, its just safe to reset the rotation to the stored value.
+        transform.rotation = stored_rotation;
+
+        // Respawning is a teleport, don't smooth the visuals across the map.
+        if respawned {
+            simulation.is_visually_correcting = false;
+            simulation.last_translation = transform.translation;
         }
     }
 }
+
+/// Applies replicated health and armor, returns true if the character just came back to life.
+fn apply_character_status(
+    health: &mut Health,
+    armor: &mut Armor,
+    snapshot: &CharacterSnapshot,
+) -> bool {
+    let was_dead = health.is_dead();
+
+    health.current = snapshot.health;
+    armor.current = snapshot.armor;
+
+    was_dead && !health.is_dead()
+}
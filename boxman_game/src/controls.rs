@@ -0,0 +1,187 @@
+use std::{error::Error, path::PathBuf};
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+use bevy_config_stack::prelude::ConfigAssetLoaderPlugin;
+use boxman_shared::data::{ControlsAction, ControlsConfig, ControlsInput, GamepadControl};
+use directories::ProjectDirs;
+
+/// Axis values past this count as a press when listening for a new binding.
+const REBIND_AXIS_THRESHOLD: f32 = 0.5;
+
+/// Send this to start listening for the next input, which then gets bound to the action.
+#[derive(Event)]
+pub struct RebindEvent(pub ControlsAction);
+
+/// The action currently waiting for an input to be bound to it. Escape cancels.
+#[derive(Resource, Default)]
+pub struct PendingRebind(pub Option<ControlsAction>);
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        // Bindings the user changed take priority over the defaults shipped in the assets.
+        match load_user_controls() {
+            Some(controls_config) => {
+                app.insert_resource(controls_config);
+            }
+            None => {
+                app.add_plugins(ConfigAssetLoaderPlugin::<ControlsConfig>::new("data/controls.ron"));
+            }
+        }
+
+        app.add_event::<RebindEvent>();
+        app.init_resource::<PendingRebind>();
+        app.add_systems(Update, (
+            start_rebind_system,
+            rebind_system.run_if(resource_exists::<ControlsConfig>),
+        ).chain());
+    }
+}
+
+/// Reads the current state of every device a [`ControlsInput`] can be bound to.
+#[derive(SystemParam)]
+pub struct InputDevices<'w, 's> {
+    keyboard: Res<'w, ButtonInput<KeyCode>>,
+    mouse: Res<'w, ButtonInput<MouseButton>>,
+    gamepads: Query<'w, 's, &'static Gamepad>,
+}
+
+impl InputDevices<'_, '_> {
+    /// How strongly the input is held [0.0 to 1.0]. Buttons and keys are either 0.0 or 1.0,
+    /// axes are analog once past the deadzone.
+    pub fn value(&self, input: &ControlsInput, deadzone: f32) -> f32 {
+        match input {
+            ControlsInput::Keyboard(key_code) => {
+                if self.keyboard.pressed(*key_code) { 1.0 } else { 0.0 }
+            }
+            ControlsInput::Mouse(mouse_button) => {
+                if self.mouse.pressed(*mouse_button) { 1.0 } else { 0.0 }
+            }
+            ControlsInput::Gamepad(GamepadControl::Button(button)) => {
+                if self.gamepads.iter().any(|gamepad| gamepad.pressed(*button)) { 1.0 } else { 0.0 }
+            }
+            ControlsInput::Gamepad(GamepadControl::Axis { axis, positive }) => {
+                self.gamepads.iter()
+                    .map(|gamepad| {
+                        let value = gamepad.get(*axis).unwrap_or(0.0);
+                        let value = if *positive { value } else { -value };
+                        if value > deadzone { value } else { 0.0 }
+                    })
+                    .fold(0.0, f32::max)
+            }
+        }
+    }
+
+    pub fn pressed(&self, input: &ControlsInput, deadzone: f32) -> bool {
+        self.value(input, deadzone) > 0.0
+    }
+
+    /// Whether the input started being held this frame. Axes don't have a press, so they never count.
+    pub fn just_pressed_input(&self, input: &ControlsInput) -> bool {
+        match input {
+            ControlsInput::Keyboard(key_code) => self.keyboard.just_pressed(*key_code),
+            ControlsInput::Mouse(mouse_button) => self.mouse.just_pressed(*mouse_button),
+            ControlsInput::Gamepad(GamepadControl::Button(button)) => {
+                self.gamepads.iter().any(|gamepad| gamepad.just_pressed(*button))
+            }
+            ControlsInput::Gamepad(GamepadControl::Axis { .. }) => false,
+        }
+    }
+
+    /// The first input that was pressed this frame, if any.
+    fn just_pressed(&self) -> Option<ControlsInput> {
+        if let Some(key_code) = self.keyboard.get_just_pressed().next() {
+            return Some(ControlsInput::Keyboard(*key_code));
+        }
+
+        if let Some(mouse_button) = self.mouse.get_just_pressed().next() {
+            return Some(ControlsInput::Mouse(*mouse_button));
+        }
+
+        for gamepad in self.gamepads.iter() {
+            if let Some(button) = gamepad.get_just_pressed().next() {
+                return Some(ControlsInput::Gamepad(GamepadControl::Button(*button)));
+            }
+
+            for axis in GamepadAxis::all() {
+                let value = gamepad.get(axis).unwrap_or(0.0);
+                if value.abs() > REBIND_AXIS_THRESHOLD {
+                    return Some(ControlsInput::Gamepad(GamepadControl::Axis {
+                        axis,
+                        positive: value > 0.0,
+                    }));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+fn start_rebind_system(
+    mut rebind_events: EventReader<RebindEvent>,
+    mut pending_rebind: ResMut<PendingRebind>,
+) {
+    if let Some(event) = rebind_events.read().last() {
+        info!("Press an input to bind to {:?}", event.0);
+        pending_rebind.0 = Some(event.0);
+    }
+}
+
+fn rebind_system(
+    devices: InputDevices,
+    mut pending_rebind: ResMut<PendingRebind>,
+    mut controls_config: ResMut<ControlsConfig>,
+) {
+    let Some(action) = pending_rebind.0 else {
+        return;
+    };
+
+    let Some(input) = devices.just_pressed() else {
+        return;
+    };
+
+    pending_rebind.0 = None;
+
+    if input == ControlsInput::Keyboard(KeyCode::Escape) {
+        info!("Cancelled rebinding {:?}", action);
+        return;
+    }
+
+    info!("Bound {:?} to {:?}", action, input);
+    *controls_config.controls.binding_mut(action) = input;
+
+    if let Err(e) = save_user_controls(&controls_config) {
+        error!("Failed to save controls: {}", e);
+    }
+}
+
+/// Where the user's own bindings are stored, outside of the game's assets.
+fn user_controls_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "Riverside Games", "Boxman")
+        .map(|dirs| dirs.config_dir().join("controls.ron"))
+}
+
+fn load_user_controls() -> Option<ControlsConfig> {
+    let path = user_controls_path()?;
+    let contents = std::fs::read_to_string(&path).ok()?;
+    match ron::from_str::<ControlsConfig>(&contents) {
+        Ok(controls_config) => Some(controls_config),
+        Err(e) => {
+            error!("Failed to parse {}: {}, using default controls", path.display(), e);
+            None
+        }
+    }
+}
+
+fn save_user_controls(controls_config: &ControlsConfig) -> Result<(), Box<dyn Error>> {
+    let path = user_controls_path().ok_or("No config directory available")?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let serialized = ron::ser::to_string_pretty(controls_config, ron::ser::PrettyConfig::default())?;
+    std::fs::write(&path, serialized)?;
+    info!("Saved controls to {}", path.display());
+    Ok(())
+}
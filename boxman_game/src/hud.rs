@@ -0,0 +1,48 @@
+use bevy::prelude::*;
+use boxman_shared::{character::LocalCharacter, health::{Armor, Health}};
+
+#[derive(Component)]
+pub struct HealthText;
+
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_hud_system);
+        app.add_systems(Update, health_text_system);
+    }
+}
+
+fn spawn_hud_system(mut commands: Commands) {
+    commands.spawn((
+        HealthText,
+        Text::new(""),
+        Node {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(12.0),
+            left: Val::Px(12.0),
+            ..default()
+        },
+    ));
+}
+
+fn health_text_system(
+    character: Query<(&Health, &Armor), With<LocalCharacter>>,
+    mut health_text: Query<&mut Text, With<HealthText>>,
+) {
+    if let Ok(mut text) = health_text.get_single_mut() {
+        let content = if let Ok((health, armor)) = character.get_single() {
+            if health.is_dead() {
+                "Dead, respawning...".to_string()
+            } else {
+                format!("Health {:.0}  Armor {:.0}", health.current, armor.current)
+            }
+        } else {
+            String::new()
+        };
+
+        if text.0 != content {
+            text.0 = content;
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use bevy::prelude::*;
+use boxman_shared::{
+    level::{LevelGeometry, LevelLight, LightKind},
+    types::{MaterialKind, MeshKind},
+};
+
+pub struct LevelVisualsPlugin;
+
+impl Plugin for LevelVisualsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(PostUpdate, (
+            spawn_geometry_visuals_system,
+            spawn_light_visuals_system,
+        ));
+    }
+}
+
+/// Listens for new level geometry and spawns its mesh as a child, the simulation only knows about colliders.
+fn spawn_geometry_visuals_system(
+    asset_server: Res<AssetServer>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+    geometry: Query<(Entity, &LevelGeometry), Added<LevelGeometry>>,
+) {
+    for (entity, geometry) in geometry.iter() {
+        let Some(mesh_config) = &geometry.mesh else {
+            continue;
+        };
+
+        let transform = Transform::from_translation(mesh_config.offset)
+            .with_rotation(mesh_config.rotation)
+            .with_scale(mesh_config.scale);
+
+        let material = match &mesh_config.material_kind {
+            MaterialKind::Standard { color } => materials.add(*color),
+        };
+
+        commands.entity(entity)
+            .insert(Visibility::default())
+            .with_children(|parent| {
+                let mesh = match &mesh_config.mesh_kind {
+                    MeshKind::Box { width, height, depth } => meshes.add(Cuboid::new(*width, *height, *depth)),
+                    MeshKind::Sphere { radius } => meshes.add(Sphere::new(*radius)),
+                    MeshKind::Capsule { radius, height } => meshes.add(Capsule3d::new(*radius, *height)),
+                    MeshKind::Model { path } => {
+                        parent.spawn((
+                            SceneRoot(asset_server.load(GltfAssetLabel::Scene(0).from_asset(path.clone()))),
+                            transform,
+                        ));
+                        return;
+                    }
+                };
+
+                parent.spawn((
+                    Mesh3d::from(mesh),
+                    MeshMaterial3d::from(material),
+                    transform,
+                ));
+            });
+    }
+}
+
+/// Listens for new level lights and adds the actual light to them.
+fn spawn_light_visuals_system(
+    mut commands: Commands,
+    lights: Query<(Entity, &LevelLight), Added<LevelLight>>,
+) {
+    for (entity, light) in lights.iter() {
+        match &light.light_kind {
+            LightKind::Point { color, intensity, range, shadows } => {
+                commands.entity(entity).insert(PointLight {
+                    color: *color,
+                    intensity: *intensity,
+                    range: *range,
+                    shadows_enabled: *shadows,
+                    ..default()
+                });
+            }
+            LightKind::Directional { color, illuminance, shadows } => {
+                commands.entity(entity).insert(DirectionalLight {
+                    color: *color,
+                    illuminance: *illuminance,
+                    shadows_enabled: *shadows,
+                    ..default()
+                });
+            }
+        }
+    }
+}
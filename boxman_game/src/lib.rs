@@ -0,0 +1,7 @@
+pub mod client;
+pub mod controls;
+pub mod hud;
+pub mod level_vis;
+pub mod moveable_vis;
+pub mod player;
+pub mod weapon_vis;
@@ -1,15 +1,12 @@
-mod moveable_vis;
-mod player;
-mod client;
-
-use avian3d::{prelude::ColliderConstructor, PhysicsPlugins};
+use avian3d::PhysicsPlugins;
 use bevy::prelude::*;
 use bevy_config_stack::prelude::*;
-use boxman_shared::{utils::{ServerIp, ServerPort}, SharedPlugin};
-use moveable_vis::MoveableVisualsPlugin;
-use player::PlayerPlugin;
+use bevy::asset::io::file::FileAssetReader;
+use boxman_shared::{level::LevelsDirectory, roster::{PlayerProfile, DEFAULT_PLAYER_COLOR}, utils::{AuthPort, ServerIp, ServerLevel, ServerPort}, SharedPlugin};
+use boxman_game::{client, hud::HudPlugin, level_vis::LevelVisualsPlugin, moveable_vis::MoveableVisualsPlugin, player::PlayerPlugin, weapon_vis::WeaponVisualsPlugin};
 use clap::Parser;
-use boxman_shared::data::{MultiplayerConfig, CharacterConfig};
+use boxman_shared::data::{MultiplayerConfig, CharacterConfig, ServerConfig};
+use boxman_shared::weapons::WeaponConfig;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None, name = "Boxman", author = "Riverside Games")]
@@ -22,6 +19,26 @@ pub struct CommandLineArgs {
 
     #[arg(long, default_value_t = 5000)]
     pub port: u16,
+
+    /// The level to host, only used with --server. Clients load whatever the server tells them to.
+    #[arg(long, default_value = "arena")]
+    pub level: String,
+
+    /// The name other players see.
+    #[arg(long, default_value = "Player")]
+    pub name: String,
+
+    /// The color of your character as a hex code, e.g. "e61a1a".
+    #[arg(long)]
+    pub color: Option<String>,
+
+    /// Get a connect token from the server's token service on this port, for servers running with --secure.
+    #[arg(long)]
+    pub auth_port: Option<u16>,
+
+    /// Simulates a bad network between us and the server (or every client, with --server).
+    #[command(flatten)]
+    pub network_sim: boxman_server::NetworkSimArgs,
 }
 
 fn main() {
@@ -31,97 +48,49 @@ fn main() {
     app.add_plugins((
         DefaultPlugins,
         PhysicsPlugins::default(),
-        ConfigAssetLoaderPlugin::<CharacterConfig>::new("data/character.ron"),
         ConfigAssetLoaderPlugin::<MultiplayerConfig>::new("data/multiplayer.ron"),
         PlayerPlugin,
         SharedPlugin,
         MoveableVisualsPlugin,
+        LevelVisualsPlugin,
+        WeaponVisualsPlugin,
+        HudPlugin,
     ));
 
     app.insert_resource(ServerPort(args.port));
+    app.insert_resource(LevelsDirectory(FileAssetReader::get_base_path().join("assets/levels")));
+    if let Some(network_conditions) = args.network_sim.conditions() {
+        app.insert_resource(network_conditions);
+    }
 
-    // let default_weapons_list_config = boxman_shared::weapons::WeaponConfig::default();
-    // let default_weapons_list_config_ron = ron::ser::to_string_pretty(&default_weapons_list_config, ron::ser::PrettyConfig::default()).unwrap();
-    // println!("{}", default_weapons_list_config_ron);
-    
     if args.server {
-        app.add_plugins(boxman_server::GameServerPlugin);
+        app.insert_resource(ServerLevel(args.level.clone()));
+        app.add_plugins((
+            ConfigAssetLoaderPlugin::<ServerConfig>::new("data/server.ron"),
+            // Clients get these from the server, so they always predict with the same values.
+            ConfigAssetLoaderPlugin::<CharacterConfig>::new("data/character.ron"),
+            ConfigAssetLoaderPlugin::<WeaponConfig>::new("data/weapons.ron"),
+            boxman_server::GameServerPlugin,
+        ));
     } else {
         app.insert_resource(ServerIp(args.server_ip.clone()));
+        let color = match args.color.as_deref().map(Srgba::hex) {
+            Some(Ok(color)) => color.into(),
+            Some(Err(e)) => {
+                warn!("Invalid color {:?}: {}", args.color, e);
+                DEFAULT_PLAYER_COLOR
+            }
+            None => DEFAULT_PLAYER_COLOR,
+        };
+        app.insert_resource(PlayerProfile {
+            name: args.name.clone(),
+            color,
+        });
+        if let Some(auth_port) = args.auth_port {
+            app.insert_resource(AuthPort(auth_port));
+        }
         app.add_plugins(client::GameClientPlugin);
     }
 
-    app.add_systems(Startup, (
-        startup_system,
-    ));
-
     app.run();
 }
-
-fn startup_system(
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut commands: Commands,
-) {
-    // Light
-    commands.spawn((
-        PointLight {
-            shadows_enabled: true,
-            ..default()
-        },
-        Transform::default()
-            .with_translation(Vec3::new(4.0, 4.0, 4.0)),
-    ));
-
-    // Floor
-    commands.spawn((
-        Mesh3d::from(meshes.add(Cuboid::new(40.0, 1.0, 40.0))),
-        MeshMaterial3d::from(materials.add(Color::WHITE)),
-        ColliderConstructor::ConvexHullFromMesh,
-        Transform::default()
-            .with_translation(Vec3::new(0.0, -1.0, 0.0)),
-    ));
-
-    // Box
-    commands.spawn((
-        Mesh3d::from(meshes.add(Cuboid::new(1.0, 1.0, 1.0))),
-        MeshMaterial3d::from(materials.add(Color::srgb(0.5, 0.5, 0.5))),
-        ColliderConstructor::ConvexHullFromMesh,
-    ));
-
-    // North wall
-    commands.spawn((
-        Mesh3d::from(meshes.add(Cuboid::new(40.0, 4.0, 1.0))),
-        MeshMaterial3d::from(materials.add(Color::srgb(0.5, 0.5, 0.5))),
-        ColliderConstructor::ConvexHullFromMesh,
-        Transform::default()
-            .with_translation(Vec3::new(0.0, 1.0, 20.0)),
-    ));
-    
-    // South wall
-    commands.spawn((
-        Mesh3d::from(meshes.add(Cuboid::new(40.0, 4.0, 1.0))),
-        MeshMaterial3d::from(materials.add(Color::srgb(0.5, 0.5, 0.5))),
-        ColliderConstructor::ConvexHullFromMesh,
-        Transform::default()
-            .with_translation(Vec3::new(0.0, 1.0, -20.0)),
-    ));
-
-    // East wall 
-    commands.spawn((
-        Mesh3d::from(meshes.add(Cuboid::new(1.0, 4.0, 40.0))),
-        MeshMaterial3d::from(materials.add(Color::srgb(0.5, 0.5, 0.5))),
-        ColliderConstructor::ConvexHullFromMesh,
-        Transform::default()
-            .with_translation(Vec3::new(20.0, 1.0, 0.0)),
-    ));
-
-    // West wall
-    commands.spawn((
-        Mesh3d::from(meshes.add(Cuboid::new(1.0, 4.0, 40.0))),
-        MeshMaterial3d::from(materials.add(Color::srgb(0.5, 0.5, 0.5))),
-        ColliderConstructor::ConvexHullFromMesh, 
-        Transform::default()
-            .with_translation(Vec3::new(-20.0, 1.0, 0.0)),
-    ));
-}
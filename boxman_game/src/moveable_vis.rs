@@ -19,7 +19,7 @@ impl Plugin for MoveableVisualsPlugin {
     }
 }
 
-fn visuals_interpolation_system(
+pub(crate) fn visuals_interpolation_system(
     time: Res<Time>,
     fixed_time: Res<Time<Fixed>>,
     server: Option<Res<RenetServer>>,
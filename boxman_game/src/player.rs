@@ -1,96 +1,143 @@
-use bevy::{prelude::*, window::PrimaryWindow};
-use bevy_config_stack::prelude::ConfigAssetLoaderPlugin;
-use bevy_renet::netcode::NetcodeClientTransport;
-use boxman_shared::{character::{alter_character_velocity, LocalCharacter, LocalCharacterVisuals, PlayerInput}, data::CharacterConfig, moveable_sim::MoveableSimulation, prelude::{Character, CharacterVisuals, MoveableVisuals}};
+use avian3d::prelude::SpatialQuery;
+use bevy::{input::mouse::AccumulatedMouseMotion, prelude::*, window::PrimaryWindow};
+use boxman_shared::{character::{alter_character_velocity, CharacterJump, LocalCharacter, LocalCharacterVisuals, PlayerInput}, data::CharacterConfig, moveable_sim::MoveableSimulation, health::Health, prelude::{Character, CharacterVisuals, MoveableVisuals}, roster::{Roster, DEFAULT_PLAYER_COLOR}, weapons::{shot_direction, trace_world, WeaponConfig, WeaponFiredEvent, WeaponState}};
 
-use crate::client::snapshot::LastProcessedSnapshotId;
-use boxman_shared::data::ControlsConfig;
+use crate::{client::{chat::ChatState, clock::ServerClock, snapshot::LastProcessedSnapshotId, LocalClientId}, controls::{ControlsPlugin, InputDevices}};
+use boxman_shared::data::{ControlsConfig, MultiplayerConfig};
+use boxman_shared::protocol::packed::quantize_player_input;
 
 const CAMERA_Y_OFFSET: f32 = 10.0;
 
+/// Inputs kept to replay when a snapshot arrives, one second worth.
+const INPUT_HISTORY: usize = 64;
+
+/// Inputs kept at most while waiting for the server to ack them.
+const MAX_INPUT_HISTORY: usize = 256;
+
 #[derive(Resource)]
 pub struct InputHistory {
     pub next_input_id: u32,
     pub inputs: Vec<PlayerInput>,
+
+    /// The newest input the server told us it has consumed, we stop resending anything up to it.
+    pub acked_input_id: Option<u32>,
+}
+
+impl InputHistory {
+    /// Adds the input for this tick, it should have `next_input_id` as its id.
+    pub fn push(&mut self, mut input: PlayerInput) {
+        // Predict with what the server will actually get.
+        quantize_player_input(&mut input);
+        self.inputs.push(input);
+        self.next_input_id += 1;
+
+        // Keep up to a second of input history, because we play these back when receiving a snapshot.
+        // Snapshots can be far apart, so inputs from the newest acked one on are kept longer, up to a limit.
+        while self.inputs.len() > INPUT_HISTORY {
+            let oldest_needed = self.acked_input_id.is_none_or(|acked_input_id| self.inputs[0].id >= acked_input_id);
+            if oldest_needed && self.inputs.len() <= MAX_INPUT_HISTORY {
+                break;
+            }
+            self.inputs.remove(0);
+        }
+    }
 }
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(ConfigAssetLoaderPlugin::<ControlsConfig>::new("data/controls.ron"));
+        app.add_plugins((ControlsPlugin, PredictionPlugin));
         app.add_systems(Startup, spawn_camera_system);
+        app.add_systems(Update, look_system.run_if(resource_exists::<ControlsConfig>));
+        app.add_systems(FixedPreUpdate, 
+            input_capture_system
+                .run_if(resource_exists::<ControlsConfig>)
+                .before(alter_velocity_system)
+        );
+        app.add_systems(PostUpdate,(
+            spawn_visuals_system,
+            character_visibility_system,
+            character_color_system.run_if(resource_exists_and_changed::<Roster>),
+            camera_follow_system
+        ));
+    }
+}
+
+/// Predicts our own character from the inputs in `InputHistory`, nothing in it needs a window.
+/// Headless clients (e.g. in tests) use this on its own and push the inputs themselves.
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
         app.add_systems(FixedPreUpdate, 
             (
-                input_capture_system
-                    .run_if(resource_exists::<ControlsConfig>),
                 alter_velocity_system
+                    .run_if(resource_exists::<CharacterConfig>),
+                predict_fire_system
+                    .run_if(resource_exists::<WeaponConfig>),
             )
             .chain()
         );
         app.add_systems(FixedPostUpdate, post_move_system);
-        app.add_systems(PostUpdate,(
-            tag_as_local_system,
-            spawn_visuals_system,
-            camera_follow_system
-        ));
+        app.add_systems(PostUpdate, tag_as_local_system);
         app.insert_resource(InputHistory {
             next_input_id: 0,
             inputs: Vec::new(),
+            acked_input_id: None,
         });
     }
 }
 
 fn input_capture_system(
     time: Res<Time<Fixed>>,
+    real_time: Res<Time<Real>>,
+    server_clock: Option<Res<ServerClock>>,
+    multiplayer_config: Option<Res<MultiplayerConfig>>,
     mut input_history: ResMut<InputHistory>,
-    snapshot_id: Option<ResMut<LastProcessedSnapshotId>>,
-    mouse_input: Res<ButtonInput<MouseButton>>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-    mut player_controller: Query<(Entity, &Transform, &MoveableSimulation), With<LocalCharacter>>,
+    snapshot_id: Option<Res<LastProcessedSnapshotId>>,
+    controls_config: Res<ControlsConfig>,
+    chat_state: Option<Res<ChatState>>,
+    devices: InputDevices,
+    mut player_controller: Query<&Transform, With<LocalCharacter>>,
 ) {
     // We always send an input to the server regardless of whether we have a player controller or not.
     // So always create an input history entry.
     let player_controller = player_controller.get_single_mut();
-    let wish_fire = mouse_input.pressed(MouseButton::Left);
+    let controls = &controls_config.controls;
+    let deadzone = controls_config.gamepad_deadzone;
+    // Keys type into chat while it's open, so the character stands still.
+    let typing = chat_state.is_some_and(|chat_state| chat_state.is_open());
+    let wish_fire = !typing && devices.pressed(&controls.fire, deadzone);
+    let view_snapshot_id = match (&server_clock, &multiplayer_config) {
+        (Some(server_clock), Some(multiplayer_config)) => server_clock
+            .render_time(real_time.elapsed_secs_f64(), multiplayer_config.interpolation_delay)
+            .filter(|render_time| *render_time >= 0.0)
+            .map(|render_time| (render_time / time.timestep().as_secs_f64()).round() as u64),
+        _ => None,
+    };
+    let yaw = player_controller.map_or(0.0, |player_transform| player_transform.rotation.to_euler(EulerRot::YXZ).0);
     let id = input_history.next_input_id;
-    input_history.inputs.push(PlayerInput {
+    let input = PlayerInput {
         id,
-        snapshot_id: if let Some(snapshot_id) = snapshot_id {
-            Some(snapshot_id.0.unwrap_or_default())
-        } else {
-            None
-        },
-        yaw: { 
-            if let Ok((_, player_transform, _)) = player_controller {
-                player_transform.rotation.to_euler(EulerRot::YXZ).0
-            } else {
-                0.0
-            }
-        },
-        wish_dir: {
+        // Only ack snapshots we actually hold, the server encodes against whatever we ack.
+        snapshot_id: snapshot_id.and_then(|snapshot_id| snapshot_id.0),
+        view_snapshot_id,
+        yaw,
+        wish_dir: if typing { Vec2::ZERO } else {
             let mut direction = Vec2::ZERO;
-            if keyboard_input.pressed(KeyCode::KeyW) {
-                direction += Vec2::NEG_Y; // Move up on screen (negative Z)
-            }
-            if keyboard_input.pressed(KeyCode::KeyS) {
-                direction += Vec2::Y; // Move down on screen (positive Z)
-            }
-            if keyboard_input.pressed(KeyCode::KeyA) {
-                direction += Vec2::NEG_X; // Move left on screen (negative X)
-            }
-            if keyboard_input.pressed(KeyCode::KeyD) {
-                direction += Vec2::X; // Move right on screen (positive X)
-            }
-            direction.normalize_or_zero()
-        },
-        wish_jump: {
-            if let Ok(( _, _, moveable_simulation)) = player_controller {
-                keyboard_input.pressed(KeyCode::Space) && moveable_simulation.grounded
-            } else {
-                false
-            }
+            direction += Vec2::NEG_Y * devices.value(&controls.move_forward, deadzone); // Move up on screen (negative Z)
+            direction += Vec2::Y * devices.value(&controls.move_backward, deadzone); // Move down on screen (positive Z)
+            direction += Vec2::NEG_X * devices.value(&controls.move_left, deadzone); // Move left on screen (negative X)
+            direction += Vec2::X * devices.value(&controls.move_right, deadzone); // Move right on screen (positive X)
+
+            // The movement code turns `wish_dir` by our yaw, so undo that here. The camera doesn't turn
+            // with the character, so the keys keep moving us the same way on screen whichever way we face.
+            let direction = Quat::from_rotation_y(-yaw) * Vec3::new(direction.x, 0.0, direction.y);
+            Vec2::new(direction.x, direction.z).normalize_or_zero()
         },
+        // Sent raw, whether a jump actually happens (coyote time, buffering) is decided by the shared movement code.
+        wish_jump: !typing && devices.pressed(&controls.jump, deadzone),
         active_weapon: 0,
         wish_fire,
         send_count: 0,
@@ -98,47 +145,93 @@ fn input_capture_system(
         post_move_velocity: Vec3::ZERO,
         post_move_position: Vec3::ZERO,
         post_move_grounded: false,
-    });
-    input_history.next_input_id += 1;
+        post_move_jump: CharacterJump::default(),
+    };
+
+    input_history.push(input);
+}
 
-    // Keep up to a second of input history, because we play these back when receiving a snapshot
-    if input_history.inputs.len() > 64 {
-        input_history.inputs.remove(0);
+/// Turns the local character with the mouse. This runs every frame, and the resulting yaw
+/// is picked up by the next input. It only changes where we aim, not which way the keys move us.
+fn look_system(
+    controls_config: Res<ControlsConfig>,
+    mouse_motion: Res<AccumulatedMouseMotion>,
+    mut player_controller: Query<&mut Transform, With<LocalCharacter>>,
+) {
+    if let Ok(mut player_transform) = player_controller.get_single_mut() {
+        let yaw_delta = -mouse_motion.delta.x * controls_config.mouse_sensitivity;
+        if yaw_delta != 0.0 {
+            player_transform.rotate_y(yaw_delta);
+        }
     }
 }
 
-fn alter_velocity_system(
+/// Predicts the local character with the newest input, inputs for a tick have to be pushed before this runs.
+pub fn alter_velocity_system(
     fixed_time: Res<Time<Fixed>>,
-    mut characters: Query<&mut MoveableSimulation, (With<LocalCharacter>, Without<Camera3d>)>,
+    mut characters: Query<(&mut MoveableSimulation, &mut CharacterJump, &Health), (With<LocalCharacter>, Without<Camera3d>)>,
     mut player_inputs: ResMut<InputHistory>,
     character_config: Res<CharacterConfig>,
 ) {
-    if let Ok(mut character) = characters.get_single_mut() {
+    if let Ok((mut character, mut jump, health)) = characters.get_single_mut() {
+        if health.is_dead() {
+            return;
+        }
+
         if let Some(input) = player_inputs.inputs.last_mut() {
             alter_character_velocity(
                 &mut character, 
+                &mut jump,
                 input, 
                 fixed_time.delta_secs(), 
-                character_config.speed, 
-                character_config.acceleration,
-                character_config.friction, 
+                &character_config,
             );
         }
     }
 }
 
+/// Fires our own weapon as soon as the input is captured, so the effects don't wait on the server.
+/// The server decides what was actually hit.
+fn predict_fire_system(
+    fixed_time: Res<Time<Fixed>>,
+    weapon_config: Res<WeaponConfig>,
+    spatial_query: SpatialQuery,
+    player_inputs: Res<InputHistory>,
+    local_client_id: Option<Res<LocalClientId>>,
+    mut characters: Query<(&Transform, &mut WeaponState, &Health), With<LocalCharacter>>,
+    mut weapon_fired_events: EventWriter<WeaponFiredEvent>,
+) {
+    if let (Ok((transform, mut weapon_state, health)), Some(input), Some(local_client_id)) = (characters.get_single_mut(), player_inputs.inputs.last(), local_client_id) {
+        if health.is_dead() {
+            return;
+        }
+
+        if let Some(weapon) = weapon_state.update(input, &weapon_config, fixed_time.delta_secs(), true) {
+            let direction = shot_direction(input.yaw, weapon.spread, input.id);
+            let distance = trace_world(&spatial_query, transform.translation, direction, weapon.range);
+            weapon_fired_events.send(WeaponFiredEvent {
+                client_id: local_client_id.0,
+                weapon: input.active_weapon,
+                origin: transform.translation,
+                end: transform.translation + direction * distance,
+            });
+        }
+    }
+}
+
 fn post_move_system(
     mut player_inputs: ResMut<InputHistory>,
-    player_controller: Query<(&MoveableSimulation, &Transform), With<LocalCharacter>>,
+    player_controller: Query<(&MoveableSimulation, &CharacterJump, &Transform), With<LocalCharacter>>,
 ) {
     // We log these and store them on the input so that when we receive a snapshot,
     // we can compare the post-move values to the values in the snapshot to determine
     // if we should correct the client's movement.
     if let Some(input) = player_inputs.inputs.last_mut() {
-        if let Ok((player_controller, player_transform)) = player_controller.get_single() {
+        if let Ok((player_controller, jump, player_transform)) = player_controller.get_single() {
             input.post_move_velocity = player_controller.velocity;
             input.post_move_position = player_transform.translation;
             input.post_move_grounded = player_controller.grounded;
+            input.post_move_jump = *jump;
         }
     }
 }
@@ -194,15 +287,11 @@ fn camera_follow_system(
 
 /// Listens for new characters and tags them as local if they are ours.
 fn tag_as_local_system(
-    transport: Option<Res<NetcodeClientTransport>>,
+    local_client_id: Option<Res<LocalClientId>>,
     mut commands: Commands,
     characters: Query<(Entity, &Character)>,
 ) {
-    let client_id = if let Some(transport) = transport {
-        transport.client_id()
-    } else {
-        0
-    };
+    let client_id = local_client_id.map_or(0, |local_client_id| local_client_id.0);
 
     for (entity, character) in characters.iter() {
         if character.client_id == client_id {
@@ -213,18 +302,16 @@ fn tag_as_local_system(
 
 /// Listens for new characters and spawns visuals for them.
 fn spawn_visuals_system(
-    transport: Option<Res<NetcodeClientTransport>>,
+    local_client_id: Option<Res<LocalClientId>>,
+    roster: Option<Res<Roster>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut commands: Commands,
     characters: Query<(Entity, &Character), Added<Character>>,
 ) {
     for (entity, character) in characters.iter() {
-        let client_id = if let Some(transport) = &transport {
-            transport.client_id()
-        } else {
-            0
-        };
+        let color = character_color(roster.as_deref(), character.client_id);
+        let client_id = local_client_id.as_ref().map_or(0, |local_client_id| local_client_id.0);
     
         info!("Spawning visuals for character: {}", character.client_id);
 
@@ -238,7 +325,7 @@ fn spawn_visuals_system(
                     simulation_entity: entity,
                 },
                 Mesh3d::from(meshes.add(Cuboid::default())),
-                MeshMaterial3d::from(materials.add(Color::srgb(0.9, 0.1, 0.1))),
+                MeshMaterial3d::from(materials.add(color)),
             ));
         } else {
             commands.spawn((
@@ -247,8 +334,56 @@ fn spawn_visuals_system(
                     simulation_entity: entity,
                 },
                 Mesh3d::from(meshes.add(Cuboid::default())),
-                MeshMaterial3d::from(materials.add(Color::srgb(0.9, 0.1, 0.1))),
+                MeshMaterial3d::from(materials.add(color)),
             ));
         }
     }       
 }
+
+/// The color a player picked, or the default until we know it.
+fn character_color(roster: Option<&Roster>, client_id: u64) -> Color {
+    roster
+        .and_then(|roster| roster.get(client_id))
+        .map_or(DEFAULT_PLAYER_COLOR, |info| info.color)
+}
+
+/// Keeps character colors up to date with the roster, players can change theirs at any time.
+fn character_color_system(
+    roster: Res<Roster>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    characters: Query<&Character>,
+    visuals: Query<(&MoveableVisuals, &MeshMaterial3d<StandardMaterial>), With<CharacterVisuals>>,
+) {
+    for (moveable_visuals, material) in visuals.iter() {
+        let Ok(character) = characters.get(moveable_visuals.simulation_entity) else {
+            continue;
+        };
+
+        let color = character_color(Some(&roster), character.client_id);
+        if let Some(material) = materials.get_mut(&material.0) {
+            if material.base_color != color {
+                material.base_color = color;
+            }
+        }
+    }
+}
+
+/// Hides the visuals of dead characters until they respawn.
+fn character_visibility_system(
+    characters: Query<&Health, With<Character>>,
+    mut visuals: Query<(&MoveableVisuals, &mut Visibility), With<CharacterVisuals>>,
+) {
+    for (moveable_visuals, mut visibility) in visuals.iter_mut() {
+        if let Ok(health) = characters.get(moveable_visuals.simulation_entity) {
+            let target = if health.is_dead() {
+                Visibility::Hidden
+            } else {
+                Visibility::Inherited
+            };
+
+            if *visibility != target {
+                *visibility = target;
+            }
+        }
+    }
+}
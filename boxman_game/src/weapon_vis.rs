@@ -0,0 +1,61 @@
+use bevy::prelude::*;
+use boxman_shared::weapons::WeaponFiredEvent;
+
+const MUZZLE_FLASH_SECONDS: f32 = 0.06;
+
+#[derive(Component)]
+pub struct MuzzleFlash {
+    pub timer: Timer,
+    pub origin: Vec3,
+    pub end: Vec3,
+}
+
+pub struct WeaponVisualsPlugin;
+
+impl Plugin for WeaponVisualsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (
+            spawn_muzzle_flash_system,
+            muzzle_flash_system,
+        ).chain());
+    }
+}
+
+/// Spawns a short lived flash and tracer for every shot, ours (predicted) and everyone else's.
+fn spawn_muzzle_flash_system(
+    mut commands: Commands,
+    mut weapon_fired_events: EventReader<WeaponFiredEvent>,
+) {
+    for event in weapon_fired_events.read() {
+        commands.spawn((
+            MuzzleFlash {
+                timer: Timer::from_seconds(MUZZLE_FLASH_SECONDS, TimerMode::Once),
+                origin: event.origin,
+                end: event.end,
+            },
+            PointLight {
+                color: Color::srgb(1.0, 0.8, 0.4),
+                intensity: 200_000.0,
+                range: 6.0,
+                ..default()
+            },
+            Transform::from_translation(event.origin),
+        ));
+    }
+}
+
+fn muzzle_flash_system(
+    time: Res<Time>,
+    mut commands: Commands,
+    mut gizmos: Gizmos,
+    mut muzzle_flashes: Query<(Entity, &mut MuzzleFlash)>,
+) {
+    for (entity, mut muzzle_flash) in muzzle_flashes.iter_mut() {
+        muzzle_flash.timer.tick(time.delta());
+        if muzzle_flash.timer.finished() {
+            commands.entity(entity).despawn_recursive();
+        } else {
+            gizmos.line(muzzle_flash.origin, muzzle_flash.end, Color::srgb(1.0, 0.9, 0.6));
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Runs a server and headless clients in one process, stepping them together one fixed tick at a time.
+//! Packets are handed between their renet server and clients directly, nothing goes over a socket.
+
+use std::{collections::VecDeque, time::Duration};
+
+use avian3d::PhysicsPlugins;
+use bevy::{asset::AssetPlugin, input::InputPlugin, prelude::*, scene::ScenePlugin, time::TimeUpdateStrategy};
+use bevy_renet::renet::{ConnectionConfig, RenetClient, RenetServer};
+use boxman_game::{
+    client::{handshake::DisconnectReason, snapshot::{Corrections, LastProcessedSnapshotId}, GameClientPlugin, LocalClientId},
+    player::{alter_velocity_system, InputHistory, PredictionPlugin},
+};
+use boxman_server::{handshake::AwaitingHandshake, player::Player, GameServerPlugin};
+use boxman_shared::{
+    character::{Character, CharacterJump, LocalCharacter, PlayerInput},
+    data::{CharacterConfig, MultiplayerConfig, ServerConfig},
+    level::{CurrentLevel, LevelsDirectory},
+    moveable_sim::MoveableVisuals,
+    roster::PlayerProfile,
+    utils::{InMemoryTransport, ServerLevel},
+    weapons::WeaponConfig,
+    SharedPlugin,
+};
+
+/// One tick at the default tick rate of 64 Hz, every update moves time along by exactly this much.
+pub const TICK: Duration = Duration::from_nanos(15_625_000);
+
+pub const LEVEL: &str = "arena";
+
+/// What a headless client does for one tick, in place of reading the controls.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScriptedInput {
+    pub wish_dir: Vec2,
+    pub yaw: f32,
+    pub jump: bool,
+}
+
+impl ScriptedInput {
+    pub fn walk(wish_dir: Vec2) -> Self {
+        Self {
+            wish_dir: wish_dir.normalize_or_zero(),
+            ..default()
+        }
+    }
+
+    pub fn jump() -> Self {
+        Self {
+            jump: true,
+            ..default()
+        }
+    }
+}
+
+/// The inputs a client still has to play, one per tick. It stands still once they run out.
+#[derive(Resource, Default)]
+struct InputScript(VecDeque<ScriptedInput>);
+
+pub struct TestClient {
+    pub client_id: u64,
+    pub app: App,
+}
+
+impl TestClient {
+    fn new(client_id: u64) -> Self {
+        let mut app = base_app();
+        app.add_plugins((InputPlugin, GameClientPlugin, PredictionPlugin));
+        app.insert_resource(InMemoryTransport);
+        app.insert_resource(LocalClientId(client_id));
+        app.insert_resource(MultiplayerConfig {
+            // Ticks stay in lockstep with the server, nothing to catch up on.
+            max_tick_rate_adjustment: 0.0,
+            ..default()
+        });
+        app.insert_resource(PlayerProfile {
+            name: format!("Client {}", client_id),
+            ..default()
+        });
+        app.init_resource::<InputScript>();
+        app.add_systems(FixedPreUpdate, scripted_input_system.before(alter_velocity_system));
+
+        let mut client = RenetClient::new(ConnectionConfig::default());
+        client.set_connected();
+        app.insert_resource(client);
+
+        app.finish();
+        app.cleanup();
+        Self {
+            client_id,
+            app,
+        }
+    }
+
+    /// Queues inputs to play after the ones already queued.
+    pub fn script(&mut self, inputs: impl IntoIterator<Item = ScriptedInput>) {
+        self.app.world_mut().resource_mut::<InputScript>().0.extend(inputs);
+    }
+
+    pub fn script_done(&self) -> bool {
+        self.app.world().resource::<InputScript>().0.is_empty()
+    }
+
+    pub fn local_position(&mut self) -> Option<Vec3> {
+        let world = self.app.world_mut();
+        world.query_filtered::<&Transform, (With<LocalCharacter>, Without<MoveableVisuals>)>()
+            .iter(world)
+            .next()
+            .map(|transform| transform.translation)
+    }
+
+    /// Where this client shows another player's character.
+    pub fn remote_position(&mut self, client_id: u64) -> Option<Vec3> {
+        character_position(self.app.world_mut(), client_id)
+    }
+
+    pub fn input_history(&self) -> &InputHistory {
+        self.app.world().resource::<InputHistory>()
+    }
+
+    pub fn acked_input_id(&self) -> Option<u32> {
+        self.input_history().acked_input_id
+    }
+
+    pub fn corrections(&self) -> &Corrections {
+        self.app.world().resource::<Corrections>()
+    }
+
+    pub fn reset_corrections(&mut self) {
+        *self.app.world_mut().resource_mut::<Corrections>() = Corrections::default();
+    }
+
+    pub fn current_level(&self) -> Option<&str> {
+        self.app.world().get_resource::<CurrentLevel>().map(|current_level| current_level.name.as_str())
+    }
+
+    /// The character config the server sent us, clients don't load their own.
+    pub fn character_config(&self) -> Option<&CharacterConfig> {
+        self.app.world().get_resource::<CharacterConfig>()
+    }
+
+    pub fn disconnect_reason(&self) -> Option<&str> {
+        self.app.world().resource::<DisconnectReason>().0.as_deref()
+    }
+
+    /// Connected with a character of our own, which has landed and acked an input.
+    pub fn is_ready(&mut self) -> bool {
+        self.app.world().resource::<RenetClient>().is_connected()
+            && self.acked_input_id().is_some()
+            && self.local_position().is_some()
+    }
+}
+
+/// A server and the clients connected to it, all updated once per `tick`.
+pub struct TestWorld {
+    pub server: App,
+    pub clients: Vec<TestClient>,
+    next_client_id: u64,
+}
+
+impl TestWorld {
+    pub fn new(client_count: usize) -> Self {
+        let mut server = base_app();
+        server.add_plugins(GameServerPlugin);
+        server.insert_resource(InMemoryTransport);
+        server.insert_resource(ServerConfig::default());
+        server.insert_resource(ServerLevel(LEVEL.to_string()));
+        server.insert_resource(CharacterConfig::default());
+        server.insert_resource(WeaponConfig::default());
+        server.finish();
+        server.cleanup();
+
+        let mut world = Self {
+            server,
+            clients: Vec::new(),
+            next_client_id: 1,
+        };
+        for _ in 0..client_count {
+            world.connect();
+        }
+        world
+    }
+
+    /// Connects another client, returns its index in `clients`.
+    pub fn connect(&mut self) -> usize {
+        let client_id = self.next_client_id;
+        self.next_client_id += 1;
+
+        self.server.world_mut().resource_mut::<RenetServer>().add_connection(client_id);
+        self.clients.push(TestClient::new(client_id));
+        self.clients.len() - 1
+    }
+
+    pub fn client(&mut self, index: usize) -> &mut TestClient {
+        &mut self.clients[index]
+    }
+
+    /// Runs one tick on the server and then on every client, handing over the packets each sent.
+    pub fn tick(&mut self) {
+        self.server.update();
+
+        for client in self.clients.iter_mut() {
+            let packets = self.server.world_mut().resource_mut::<RenetServer>()
+                .get_packets_to_send(client.client_id)
+                .unwrap_or_default();
+            let mut renet_client = client.app.world_mut().resource_mut::<RenetClient>();
+            for packet in packets {
+                renet_client.process_packet(&packet);
+            }
+        }
+
+        for client in self.clients.iter_mut() {
+            client.app.update();
+
+            let packets = client.app.world_mut().resource_mut::<RenetClient>().get_packets_to_send();
+            let mut renet_server = self.server.world_mut().resource_mut::<RenetServer>();
+            for packet in packets {
+                renet_server.process_packet_from(&packet, client.client_id).expect("client is connected to the server");
+            }
+        }
+    }
+
+    pub fn ticks(&mut self, count: usize) {
+        for _ in 0..count {
+            self.tick();
+        }
+    }
+
+    /// Ticks until `condition` holds, panics if it doesn't within `max_ticks`.
+    pub fn tick_until(&mut self, max_ticks: usize, what: &str, mut condition: impl FnMut(&mut Self) -> bool) {
+        for _ in 0..max_ticks {
+            if condition(self) {
+                return;
+            }
+            self.tick();
+        }
+        assert!(condition(self), "{} didn't happen within {} ticks", what, max_ticks);
+    }
+
+    /// Ticks until every client has a character standing still on the ground, then forgets the corrections
+    /// from joining. The first snapshots correct inputs made before our character existed.
+    pub fn settle(&mut self) {
+        self.tick_until(256, "every client getting a character", |world| {
+            world.clients.iter_mut().all(|client| client.is_ready())
+        });
+        self.ticks(128);
+        for client in self.clients.iter_mut() {
+            client.reset_corrections();
+        }
+    }
+
+    /// Ticks until every client has played its script and the server has acked all of it.
+    pub fn play_scripts(&mut self) {
+        self.tick_until(1024, "every script being played", |world| {
+            world.clients.iter().all(|client| client.script_done())
+        });
+        let last_input_ids: Vec<u32> = self.clients.iter()
+            .map(|client| client.input_history().next_input_id - 1)
+            .collect();
+        self.tick_until(64, "every scripted input being acked", |world| {
+            world.clients.iter()
+                .zip(last_input_ids.iter())
+                .all(|(client, last_input_id)| client.acked_input_id().is_some_and(|acked_input_id| acked_input_id >= *last_input_id))
+        });
+    }
+
+    /// Where the server has a player's character.
+    pub fn server_position(&mut self, client_id: u64) -> Option<Vec3> {
+        character_position(self.server.world_mut(), client_id)
+    }
+
+    pub fn server_player(&mut self, client_id: u64) -> Option<&Player> {
+        let world = self.server.world_mut();
+        world.query::<&Player>()
+            .iter(world)
+            .find(|player| player.client_id == client_id)
+    }
+
+    pub fn awaiting_handshake(&mut self, client_id: u64) -> bool {
+        let world = self.server.world_mut();
+        world.query_filtered::<&Player, With<AwaitingHandshake>>()
+            .iter(world)
+            .any(|player| player.client_id == client_id)
+    }
+}
+
+/// What both the server and the clients run on, none of it needs a window.
+fn base_app() -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        TransformPlugin,
+        HierarchyPlugin,
+        // Avian's collider constructors expect these to exist, nothing is loaded through them.
+        AssetPlugin::default(),
+        ScenePlugin,
+        PhysicsPlugins::default(),
+        SharedPlugin,
+    ));
+    app.init_resource::<Assets<Mesh>>();
+    app.insert_resource(TimeUpdateStrategy::ManualDuration(TICK));
+    app.insert_resource(Time::<Fixed>::from_duration(TICK));
+    app.insert_resource(LevelsDirectory(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/levels").into()));
+    app
+}
+
+fn character_position(world: &mut World, client_id: u64) -> Option<Vec3> {
+    world.query_filtered::<(&Character, &Transform), Without<MoveableVisuals>>()
+        .iter(world)
+        .find(|(character, _)| character.client_id == client_id)
+        .map(|(_, transform)| transform.translation)
+}
+
+/// Pushes the next scripted input, the way `input_capture_system` pushes what the player pressed.
+fn scripted_input_system(
+    time: Res<Time<Fixed>>,
+    snapshot_id: Res<LastProcessedSnapshotId>,
+    mut script: ResMut<InputScript>,
+    mut input_history: ResMut<InputHistory>,
+) {
+    let scripted = script.0.pop_front().unwrap_or_default();
+    let input = PlayerInput {
+        id: input_history.next_input_id,
+        snapshot_id: snapshot_id.0,
+        view_snapshot_id: None,
+        yaw: scripted.yaw,
+        wish_dir: scripted.wish_dir,
+        wish_jump: scripted.jump,
+        wish_fire: false,
+        active_weapon: 0,
+        timestamp: time.elapsed_secs(),
+        send_count: 0,
+        post_move_velocity: Vec3::ZERO,
+        post_move_position: Vec3::ZERO,
+        post_move_grounded: false,
+        post_move_jump: CharacterJump::default(),
+    };
+    input_history.push(input);
+}
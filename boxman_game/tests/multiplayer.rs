@@ -0,0 +1,169 @@
+mod common;
+
+use bevy::prelude::*;
+use boxman_game::client::snapshot::ReceivedSnapshots;
+use boxman_server::ServerConfigOverrides;
+use boxman_shared::{data::{CharacterConfig, ServerConfig}, snapshot::POSITION_PRECISION};
+use common::{ScriptedInput, TestWorld, LEVEL};
+use pretty_assertions::assert_eq;
+
+/// Snapshots round positions to this, the rest of the simulation runs the same on both ends.
+const TOLERANCE: f32 = POSITION_PRECISION;
+
+fn assert_near(actual: Vec3, expected: Vec3, what: &str) {
+    assert!(actual.distance(expected) <= TOLERANCE, "{}: {} is not at {}", what, actual, expected);
+}
+
+#[test]
+fn clients_connect_and_get_a_character() {
+    let mut world = TestWorld::new(2);
+    world.settle();
+
+    for index in 0..2 {
+        let client_id = world.client(index).client_id;
+        assert!(!world.awaiting_handshake(client_id));
+        assert_eq!(world.server_player(client_id).map(|player| player.name.clone()), Some(format!("Client {}", client_id)));
+
+        let client = world.client(index);
+        assert_eq!(client.current_level(), Some(LEVEL));
+        assert_eq!(client.disconnect_reason(), None);
+        // Holds on to every snapshot the server could encode against.
+        assert_eq!(client.app.world().resource::<ReceivedSnapshots>().capacity, ServerConfig::default().snapshot_history);
+    }
+
+    // Everyone is relevant in a level this small.
+    let (first_id, second_id) = (world.client(0).client_id, world.client(1).client_id);
+    assert!(world.client(0).remote_position(second_id).is_some());
+    assert!(world.client(1).remote_position(first_id).is_some());
+}
+
+#[test]
+fn predicted_movement_matches_the_server() {
+    let mut world = TestWorld::new(1);
+    world.settle();
+
+    let client_id = world.client(0).client_id;
+    let start = world.server_position(client_id).unwrap();
+
+    world.client(0).script(
+        std::iter::repeat_n(ScriptedInput::walk(Vec2::new(1.0, -1.0)), 32)
+            .chain([ScriptedInput::jump()])
+            .chain(std::iter::repeat_n(ScriptedInput {
+                yaw: 1.0,
+                ..ScriptedInput::walk(Vec2::NEG_Y)
+            }, 32))
+    );
+    world.play_scripts();
+    // Let it come to a stop and land.
+    world.ticks(64);
+
+    let server_position = world.server_position(client_id).unwrap();
+    assert!(server_position.xz().distance(start.xz()) > 1.0, "didn't move from {}", start);
+
+    let client = world.client(0);
+    assert_near(client.local_position().unwrap(), server_position, "predicted position");
+    assert_eq!(client.corrections().count, 0, "corrected by up to {}", client.corrections().last_distance);
+}
+
+#[test]
+fn inputs_are_acked_as_they_are_consumed() {
+    let mut world = TestWorld::new(1);
+    world.settle();
+
+    let mut last_acked_input_id = world.client(0).acked_input_id().unwrap();
+    world.client(0).script(std::iter::repeat_n(ScriptedInput::walk(Vec2::X), 64));
+
+    for _ in 0..64 {
+        world.tick();
+
+        let client = world.client(0);
+        let acked_input_id = client.acked_input_id().unwrap();
+        assert!(acked_input_id >= last_acked_input_id, "ack went back from {} to {}", last_acked_input_id, acked_input_id);
+        last_acked_input_id = acked_input_id;
+
+        // Every packet arrives the next tick, so the server is never more than a few inputs behind.
+        let newest_input_id = client.input_history().next_input_id - 1;
+        assert!(newest_input_id - acked_input_id <= 4, "input {} was sent, {} is acked", newest_input_id, acked_input_id);
+    }
+
+    world.play_scripts();
+    assert_eq!(world.client(0).corrections().count, 0);
+}
+
+#[test]
+fn movement_is_replicated_to_other_clients() {
+    let mut world = TestWorld::new(2);
+    world.settle();
+
+    let mover_id = world.client(0).client_id;
+    world.client(0).script(std::iter::repeat_n(ScriptedInput::walk(Vec2::new(-1.0, 1.0)), 48));
+    world.play_scripts();
+    // Remote characters are shown a little in the past, give that time to catch up.
+    world.ticks(64);
+
+    let server_position = world.server_position(mover_id).unwrap();
+    assert_near(world.client(0).local_position().unwrap(), server_position, "predicted position");
+    assert_near(world.client(1).remote_position(mover_id).unwrap(), server_position, "replicated position");
+}
+
+#[test]
+fn late_joiners_see_everyone() {
+    let mut world = TestWorld::new(1);
+    world.settle();
+
+    let first_id = world.client(0).client_id;
+    world.client(0).script(std::iter::repeat_n(ScriptedInput::walk(Vec2::Y), 24));
+    world.play_scripts();
+
+    let late = world.connect();
+    world.settle();
+    let late_id = world.client(late).client_id;
+
+    let server_position = world.server_position(first_id).unwrap();
+    assert_near(world.client(late).remote_position(first_id).unwrap(), server_position, "replicated position");
+    assert!(world.client(0).remote_position(late_id).is_some());
+    assert_eq!(world.client(late).disconnect_reason(), None);
+}
+
+#[test]
+fn changed_configs_reach_clients() {
+    let mut world = TestWorld::new(2);
+    world.settle();
+
+    // What hot reloading character.ron does on the server.
+    world.server.insert_resource(CharacterConfig {
+        speed: 42.0,
+        jump_impulse: 7.5,
+        ..default()
+    });
+    world.tick_until(16, "every client getting the new config", |world| {
+        world.clients.iter().all(|client| client.character_config().is_some_and(|config| config.speed == 42.0))
+    });
+    assert_eq!(world.client(0).character_config().map(|config| config.jump_impulse), Some(7.5));
+
+    let late = world.connect();
+    world.settle();
+    assert_eq!(world.client(late).character_config().map(|config| config.speed), Some(42.0));
+}
+
+#[test]
+fn overrides_survive_a_config_reload() {
+    let mut world = TestWorld::new(0);
+    world.server.insert_resource(ServerConfigOverrides {
+        snapshot_history: Some(16),
+        ..default()
+    });
+    world.tick();
+    assert_eq!(world.server.world().resource::<ServerConfig>().snapshot_history, 16);
+
+    // What hot reloading server.ron does, the file doesn't know about the command line.
+    world.server.insert_resource(ServerConfig {
+        snapshot_history: 32,
+        relevance_distance: 75.0,
+        ..default()
+    });
+    world.tick();
+    let server_config = world.server.world().resource::<ServerConfig>();
+    assert_eq!(server_config.snapshot_history, 16);
+    assert_eq!(server_config.relevance_distance, 75.0);
+}
@@ -0,0 +1,467 @@
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc::{self, Receiver, Sender}, Mutex},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use bevy::prelude::*;
+use bevy_renet::renet::RenetServer;
+use boxman_shared::{conditioner::{LinkConditioner, NetworkConditions}, data::CharacterConfig, level::{load_level, LevelsDirectory, LoadLevelEvent}};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{chat::ServerAnnouncementEvent, player::{KickPlayerEvent, Player}};
+
+/// How long an RCON client gets to send the password.
+const RCON_LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command an admin can type. Arguments are parsed into the command before it runs,
+/// so `run` only ever sees a well formed command.
+///
+/// Register commands with `app.add_admin_command::<MyCommand>()`.
+pub trait AdminCommand: Sized + Send + Sync + 'static {
+    /// What the command is typed as, e.g. "kick".
+    const NAME: &'static str;
+
+    /// Shown by `help`, the arguments followed by what the command does.
+    const USAGE: &'static str;
+
+    /// `args` are the whitespace separated words after the command name.
+    fn parse(args: &[&str]) -> Result<Self, String>;
+
+    /// Runs the command, the text returned is shown to the admin.
+    fn run(self, world: &mut World) -> Result<String, String>;
+}
+
+struct RegisteredCommand {
+    usage: &'static str,
+    run: fn(&[&str], &mut World) -> Result<String, String>,
+}
+
+fn parse_and_run<C: AdminCommand>(args: &[&str], world: &mut World) -> Result<String, String> {
+    C::parse(args)?.run(world)
+}
+
+/// Every command admins can run, by name.
+#[derive(Resource, Default)]
+pub struct AdminCommands {
+    commands: BTreeMap<&'static str, RegisteredCommand>,
+}
+
+impl AdminCommands {
+    pub fn register<C: AdminCommand>(&mut self) {
+        if self.commands.contains_key(C::NAME) {
+            warn!("Admin command {} registered twice, replacing it", C::NAME);
+        }
+
+        self.commands.insert(C::NAME, RegisteredCommand {
+            usage: C::USAGE,
+            run: parse_and_run::<C>,
+        });
+    }
+
+    /// Runs a line typed by an admin, e.g. "kick Brian spamming".
+    pub fn run(&self, line: &str, world: &mut World) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return Ok(String::new());
+        };
+        let args: Vec<&str> = words.collect();
+
+        if name == "help" {
+            let usages: Vec<String> = self.commands.iter()
+                .map(|(name, command)| format!("{} {}", name, command.usage))
+                .collect();
+            return Ok(usages.join("\n"));
+        }
+
+        let command = self.commands.get(name).ok_or_else(|| format!("Unknown command {}, try help", name))?;
+        (command.run)(&args, world)
+    }
+}
+
+pub trait AdminAppExt {
+    fn add_admin_command<C: AdminCommand>(&mut self) -> &mut Self;
+}
+
+impl AdminAppExt for App {
+    fn add_admin_command<C: AdminCommand>(&mut self) -> &mut Self {
+        self.world_mut().get_resource_or_insert_with(AdminCommands::default).register::<C>();
+        self
+    }
+}
+
+/// A line typed into the console or sent over RCON, the output is sent back on `reply`.
+pub struct AdminRequest {
+    pub line: String,
+    pub reply: Sender<String>,
+}
+
+/// Where the console and RCON threads send commands, they're run on the main thread between frames.
+#[derive(Resource)]
+pub struct AdminConsole {
+    sender: Sender<AdminRequest>,
+    receiver: Mutex<Receiver<AdminRequest>>,
+}
+
+impl Default for AdminConsole {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender,
+            receiver: Mutex::new(receiver),
+        }
+    }
+}
+
+impl AdminConsole {
+    pub fn sender(&self) -> Sender<AdminRequest> {
+        self.sender.clone()
+    }
+}
+
+/// Sends `line` to be run and waits for its output. None if the server has shut down.
+fn request(sender: &Sender<AdminRequest>, line: String) -> Option<String> {
+    let (reply, output) = mpsc::channel();
+    sender.send(AdminRequest { line, reply }).ok()?;
+    output.recv().ok()
+}
+
+/// Reads commands from stdin on its own thread and prints their output.
+pub fn spawn_stdin_console(sender: Sender<AdminRequest>) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            match request(&sender, line) {
+                Some(output) if !output.is_empty() => println!("{}", output),
+                Some(_) => {}
+                None => break,
+            }
+        }
+    })
+}
+
+/// Listens for RCON clients on localhost only. The first line a client sends must be the password,
+/// after that every line is a command and its output is sent back followed by an empty line.
+pub fn spawn_rcon(sender: Sender<AdminRequest>, port: u16, password: String) -> std::io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port))?;
+    info!("RCON listening on port {}", port);
+
+    Ok(std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sender = sender.clone();
+                    let password = password.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle_rcon_client(stream, &sender, &password) {
+                            warn!("RCON connection failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("RCON connection failed: {}", e);
+                }
+            }
+        }
+    }))
+}
+
+fn handle_rcon_client(stream: TcpStream, sender: &Sender<AdminRequest>, password: &str) -> std::io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(RCON_LOGIN_TIMEOUT))?;
+
+    let mut writer = stream.try_clone()?;
+    let mut lines = BufReader::new(stream).lines();
+
+    let attempt = lines.next().transpose()?.unwrap_or_default();
+    if !constant_time_eq(attempt.trim_end().as_bytes(), password.as_bytes()) {
+        warn!("RCON login from {} failed", peer);
+        writer.write_all(b"Wrong password\n")?;
+        return Ok(());
+    }
+
+    info!("RCON login from {}", peer);
+    writer.write_all(b"OK\n\n")?;
+    writer.set_read_timeout(None)?;
+
+    for line in lines {
+        let line = line?;
+        info!("RCON {}: {}", peer, line);
+        let Some(output) = request(sender, line) else {
+            break;
+        };
+        writer.write_all(output.as_bytes())?;
+        writer.write_all(b"\n\n")?;
+    }
+
+    Ok(())
+}
+
+/// Compares every byte so the time taken doesn't give away how much of the password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub struct AdminPlugin;
+
+impl Plugin for AdminPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AdminConsole>();
+        app.init_resource::<AdminCommands>();
+        app.add_admin_command::<KickCommand>();
+        app.add_admin_command::<ListCommand>();
+        app.add_admin_command::<LevelCommand>();
+        app.add_admin_command::<SetCommand>();
+        app.add_admin_command::<SayCommand>();
+        app.add_admin_command::<NetsimCommand>();
+        app.add_systems(Update, admin_command_system);
+    }
+}
+
+fn admin_command_system(world: &mut World) {
+    let requests: Vec<AdminRequest> = match world.resource::<AdminConsole>().receiver.lock() {
+        Ok(receiver) => receiver.try_iter().collect(),
+        Err(_) => return,
+    };
+
+    if requests.is_empty() {
+        return;
+    }
+
+    world.resource_scope(|world, commands: Mut<AdminCommands>| {
+        for request in requests {
+            let output = match commands.run(&request.line, world) {
+                Ok(output) => output,
+                Err(e) => format!("Error: {}", e),
+            };
+            let _ = request.reply.send(output);
+        }
+    });
+}
+
+/// Finds a connected player by client id or name, names ignore case.
+pub fn find_player(world: &mut World, who: &str) -> Result<(u64, String), String> {
+    let client_id = who.parse::<u64>().ok();
+    world.query::<&Player>()
+        .iter(world)
+        .find(|player| Some(player.client_id) == client_id || player.name.eq_ignore_ascii_case(who))
+        .map(|player| (player.client_id, player.name.clone()))
+        .ok_or_else(|| format!("No player {}", who))
+}
+
+/// Players are matched by id or name, names with spaces can only be matched by id.
+pub struct KickCommand {
+    pub player: String,
+    pub reason: String,
+}
+
+impl AdminCommand for KickCommand {
+    const NAME: &'static str = "kick";
+    const USAGE: &'static str = "<player> [reason] - disconnects a player";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let (player, reason) = args.split_first().ok_or("Expected a player")?;
+        Ok(Self {
+            player: player.to_string(),
+            reason: if reason.is_empty() { "Kicked by an admin".to_string() } else { reason.join(" ") },
+        })
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let (client_id, name) = find_player(world, &self.player)?;
+        world.send_event(KickPlayerEvent {
+            client_id,
+            reason: self.reason,
+        });
+        Ok(format!("Kicked {} ({})", name, client_id))
+    }
+}
+
+pub struct ListCommand;
+
+impl AdminCommand for ListCommand {
+    const NAME: &'static str = "list";
+    const USAGE: &'static str = "- shows everyone connected";
+
+    fn parse(_args: &[&str]) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let players: Vec<(u64, String)> = world.query::<&Player>()
+            .iter(world)
+            .map(|player| (player.client_id, player.name.clone()))
+            .collect();
+
+        if players.is_empty() {
+            return Ok("Nobody is connected".to_string());
+        }
+
+        let renet_server = world.resource::<RenetServer>();
+        let lines: Vec<String> = players.into_iter()
+            .map(|(client_id, name)| {
+                let rtt = renet_server.network_info(client_id).map_or(0.0, |info| info.rtt);
+                format!("{} {} ({:.0} ms)", client_id, name, rtt * 1000.0)
+            })
+            .collect();
+
+        Ok(lines.join("\n"))
+    }
+}
+
+pub struct LevelCommand {
+    pub name: String,
+}
+
+impl AdminCommand for LevelCommand {
+    const NAME: &'static str = "level";
+    const USAGE: &'static str = "<name> - changes the level";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            [name] => Ok(Self { name: name.to_string() }),
+            _ => Err("Expected a level name".to_string()),
+        }
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        // Check it loads first, so the admin sees the error rather than only the log.
+        let levels_directory = world.get_resource::<LevelsDirectory>().ok_or("No levels directory")?;
+        load_level(&levels_directory.0, &self.name).map_err(|e| e.to_string())?;
+
+        world.send_event(LoadLevelEvent(self.name.clone()));
+        Ok(format!("Changing level to {}", self.name))
+    }
+}
+
+/// Changes a `CharacterConfig` value until the server restarts, or shows it when no value is given.
+pub struct SetCommand {
+    pub field: String,
+    pub value: Option<String>,
+}
+
+impl AdminCommand for SetCommand {
+    const NAME: &'static str = "set";
+    const USAGE: &'static str = "<field> [value] - shows or changes a character config value, e.g. set speed 8.0";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            [field] => Ok(Self { field: field.to_string(), value: None }),
+            [field, value] => Ok(Self { field: field.to_string(), value: Some(value.to_string()) }),
+            _ => Err("Expected a field and a value".to_string()),
+        }
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let mut character_config = world.get_resource_mut::<CharacterConfig>().ok_or("No character config loaded")?;
+        match self.value {
+            Some(value) => {
+                *character_config = set_config_field(&*character_config, &self.field, &value)?;
+                Ok(format!("{} = {}", self.field, value))
+            }
+            None => {
+                let value = config_field(&*character_config, &self.field)?;
+                Ok(format!("{} = {}", self.field, value))
+            }
+        }
+    }
+}
+
+/// Changes the simulated network conditions, or shows them. Only works when the server was started with them.
+pub struct NetsimCommand {
+    pub field: Option<String>,
+    pub value: Option<String>,
+}
+
+impl AdminCommand for NetsimCommand {
+    const NAME: &'static str = "netsim";
+    const USAGE: &'static str = "[field] [value] - shows or changes the simulated network conditions, e.g. netsim loss 0.1";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            [] => Ok(Self { field: None, value: None }),
+            [field] => Ok(Self { field: Some(field.to_string()), value: None }),
+            [field, value] => Ok(Self { field: Some(field.to_string()), value: Some(value.to_string()) }),
+            _ => Err("Expected a field and a value".to_string()),
+        }
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        // Clients are already connected straight to the server otherwise, there's nothing to put in between.
+        if !world.contains_resource::<LinkConditioner>() {
+            return Err("Network conditions are only simulated when the server is started with a --sim flag".to_string());
+        }
+
+        let mut conditions = world.get_resource_or_insert_with(NetworkConditions::default);
+        match (self.field, self.value) {
+            (Some(field), Some(value)) => {
+                *conditions = set_config_field(&*conditions, &field, &value)?.sanitized();
+                Ok(format!("{} = {}", field, config_field(&*conditions, &field)?))
+            }
+            (Some(field), None) => {
+                let value = config_field(&*conditions, &field)?;
+                Ok(format!("{} = {}", field, value))
+            }
+            _ => Ok(format!("{:?}", *conditions)),
+        }
+    }
+}
+
+/// Every field of the config by name, going through its RON representation.
+fn config_fields<T: Serialize>(config: &T) -> Result<ron::Map, String> {
+    let serialized = ron::to_string(config).map_err(|e| e.to_string())?;
+    match ron::from_str::<ron::Value>(&serialized).map_err(|e| e.to_string())? {
+        ron::Value::Map(fields) => Ok(fields),
+        _ => Err("Config isn't a struct".to_string()),
+    }
+}
+
+fn config_field<T: Serialize>(config: &T, field: &str) -> Result<String, String> {
+    let fields = config_fields(config)?;
+    let value = fields.iter()
+        .find(|(key, _)| **key == ron::Value::String(field.to_string()))
+        .map(|(_, value)| value)
+        .ok_or_else(|| format!("No field {}", field))?;
+    ron::to_string(value).map_err(|e| e.to_string())
+}
+
+/// A copy of the config with `field` set to `value`, which is parsed as RON.
+fn set_config_field<T: Serialize + DeserializeOwned>(config: &T, field: &str, value: &str) -> Result<T, String> {
+    let mut fields = config_fields(config)?;
+    let key = ron::Value::String(field.to_string());
+    if !fields.keys().any(|existing| *existing == key) {
+        return Err(format!("No field {}", field));
+    }
+
+    let value = ron::from_str::<ron::Value>(value).map_err(|e| e.to_string())?;
+    fields.insert(key, value);
+    ron::Value::Map(fields).into_rust::<T>().map_err(|e| e.to_string())
+}
+
+pub struct SayCommand {
+    pub text: String,
+}
+
+impl AdminCommand for SayCommand {
+    const NAME: &'static str = "say";
+    const USAGE: &'static str = "<text> - sends a server announcement to everyone";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        if args.is_empty() {
+            return Err("Expected something to say".to_string());
+        }
+
+        Ok(Self { text: args.join(" ") })
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        world.send_event(ServerAnnouncementEvent(self.text));
+        Ok(String::new())
+    }
+}
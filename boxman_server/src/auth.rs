@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, SystemTime},
+};
+
+use bevy::prelude::*;
+use bevy_renet::netcode::{ConnectToken, NETCODE_KEY_BYTES};
+use boxman_shared::protocol::{PlayerUserData, MAX_PLAYER_NAME_BYTES};
+
+/// How long a token can be used to connect for, in seconds.
+const TOKEN_EXPIRE_SECONDS: u64 = 30;
+
+/// Seconds without packets before a connection made with the token times out.
+const CONNECTION_TIMEOUT_SECONDS: i32 = 15;
+
+/// How long the token service waits on a client that has connected but isn't saying anything.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests the token service works on at once, connections past this are closed straight away.
+const MAX_CONCURRENT_REQUESTS: usize = 32;
+
+/// Player keys are generated by clients as hex, but any ASCII letters and digits of a sensible length will do.
+const MIN_PLAYER_KEY_BYTES: usize = 16;
+const MAX_PLAYER_KEY_BYTES: usize = 64;
+
+/// Mints netcode connect tokens for a server running in secure mode. It has to share the server's private key.
+///
+/// Players prove who they are with a secret player key their client generates once and keeps. Each key
+/// gets a player id the first time it's seen, and the same id every time after, so bans and the allowlist
+/// keep working across reconnects and restarts. Names are made unique separately, when the player joins.
+///
+/// Call `issue` directly when it runs in-process (e.g. in tests), or `spawn` it to serve tokens over TCP.
+/// A request is the player key and then the player's name, each followed by a newline. The response is the connect token.
+pub struct TokenService {
+    protocol_id: u64,
+    private_key: [u8; NETCODE_KEY_BYTES],
+    server_addresses: Vec<SocketAddr>,
+
+    /// The player id of every key we've seen.
+    players: HashMap<String, u64>,
+
+    /// Where `players` is kept, nothing is saved without it.
+    players_path: Option<PathBuf>,
+}
+
+impl TokenService {
+    pub fn new(protocol_id: u64, private_key: [u8; NETCODE_KEY_BYTES], server_addresses: Vec<SocketAddr>) -> Self {
+        Self {
+            protocol_id,
+            private_key,
+            server_addresses,
+            players: HashMap::new(),
+            players_path: None,
+        }
+    }
+
+    /// Keeps the player ids in a RON file, so they're the same after a restart. It's created when the first player
+    /// shows up. The file holds everyone's player keys, so keep it as private as the server's passwords.
+    pub fn with_players_file(mut self, path: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let path = path.into();
+        if path.exists() {
+            self.players = ron::from_str(&std::fs::read_to_string(&path)?)?;
+        }
+        self.players_path = Some(path);
+        Ok(self)
+    }
+
+    /// A token for the player with `player_key` to connect with as `name`, its player id is the netcode client id.
+    pub fn issue(&mut self, player_key: &str, name: &str) -> Result<(PlayerUserData, ConnectToken), Box<dyn Error>> {
+        let player_key = validate_player_key(player_key).ok_or("invalid player key")?;
+        let player_id = self.player_id(player_key);
+
+        let user_data = PlayerUserData {
+            player_id,
+            name: name.to_string(),
+        };
+
+        let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let connect_token = ConnectToken::generate(
+            current_time,
+            self.protocol_id,
+            TOKEN_EXPIRE_SECONDS,
+            player_id,
+            CONNECTION_TIMEOUT_SECONDS,
+            self.server_addresses.clone(),
+            Some(&user_data.to_bytes()),
+            &self.private_key,
+        )?;
+
+        Ok((user_data, connect_token))
+    }
+
+    /// The key's player id, a new random one the first time we see it.
+    fn player_id(&mut self, player_key: &str) -> u64 {
+        if let Some(player_id) = self.players.get(player_key) {
+            return *player_id;
+        }
+
+        // Random rather than worked out from the key, so an id can't be used to find a key that gets it.
+        let player_id = loop {
+            let player_id = rand::random::<u64>();
+            if player_id != 0 && !self.players.values().any(|taken| *taken == player_id) {
+                break player_id;
+            }
+        };
+        self.players.insert(player_key.to_string(), player_id);
+
+        if let Some(players_path) = &self.players_path {
+            if let Err(e) = save_players(&self.players, players_path) {
+                error!("Failed to save player ids to {}: {}", players_path.display(), e);
+            }
+        }
+
+        player_id
+    }
+
+    /// Serves tokens over TCP, every request on its own thread so a slow client doesn't hold up the rest.
+    pub fn spawn(self, port: u16) -> std::io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(SocketAddr::new([0, 0, 0, 0].into(), port))?;
+        info!("Token service started on port {}", port);
+
+        let token_service = Arc::new(Mutex::new(self));
+        let pending_requests = Arc::new(AtomicUsize::new(0));
+        Ok(std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if pending_requests.fetch_add(1, Ordering::SeqCst) >= MAX_CONCURRENT_REQUESTS {
+                            pending_requests.fetch_sub(1, Ordering::SeqCst);
+                            warn!("Token service is busy, turned away {:?}", stream.peer_addr());
+                            continue;
+                        }
+
+                        let token_service = token_service.clone();
+                        let pending_requests = pending_requests.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = handle_request(&token_service, stream) {
+                                warn!("Failed to issue connect token: {}", e);
+                            }
+                            pending_requests.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(e) => {
+                        error!("Token service connection failed: {}", e);
+                    }
+                }
+            }
+        }))
+    }
+}
+
+/// Reads a request and answers it. The service is only locked to issue the token, not while waiting on the client.
+fn handle_request(token_service: &Mutex<TokenService>, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Room for the longest key and name and their line endings, anything after that is ignored.
+    let mut reader = BufReader::new((&stream).take((MAX_PLAYER_KEY_BYTES + MAX_PLAYER_NAME_BYTES) as u64 + 4));
+    let mut player_key = String::new();
+    reader.read_line(&mut player_key)?;
+    let mut name = String::new();
+    reader.read_line(&mut name)?;
+    let name = validate_name(&name).ok_or("invalid player name")?;
+
+    let (user_data, connect_token) = token_service.lock().map_err(|_| "token service poisoned")?.issue(&player_key, name)?;
+    connect_token.write(&mut stream)?;
+    stream.flush()?;
+
+    info!("Issued connect token to {} (player {})", user_data.name, user_data.player_id);
+    Ok(())
+}
+
+fn save_players(players: &HashMap<String, u64>, path: &Path) -> Result<(), Box<dyn Error>> {
+    let serialized = ron::ser::to_string_pretty(players, ron::ser::PrettyConfig::default())?;
+    std::fs::write(path, serialized)?;
+    Ok(())
+}
+
+/// Asks the token service at `address` for a token to connect with.
+pub fn request_connect_token(address: SocketAddr, player_key: &str, name: &str) -> Result<ConnectToken, Box<dyn Error>> {
+    let player_key = validate_player_key(player_key).ok_or("invalid player key")?;
+    let name = validate_name(name).ok_or("invalid player name")?;
+
+    let mut stream = TcpStream::connect_timeout(&address, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(player_key.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.write_all(name.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.flush()?;
+
+    Ok(ConnectToken::read(&mut stream)?)
+}
+
+/// A new secret player key, clients make one and keep it.
+pub fn generate_player_key() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Trims the key, None if it's too short, too long or has anything but ASCII letters and digits in it.
+pub fn validate_player_key(player_key: &str) -> Option<&str> {
+    let player_key = player_key.trim();
+    if !(MIN_PLAYER_KEY_BYTES..=MAX_PLAYER_KEY_BYTES).contains(&player_key.len()) || !player_key.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Some(player_key)
+}
+
+/// Trims the name, None if there's nothing left or it's too long.
+pub fn validate_name(name: &str) -> Option<&str> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_PLAYER_NAME_BYTES || name.chars().any(char::is_control) {
+        return None;
+    }
+
+    Some(name)
+}
@@ -0,0 +1,420 @@
+use std::{
+    error::Error,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use bevy::prelude::*;
+use bevy_renet::{netcode::NetcodeServerTransport, renet::RenetServer};
+use serde::{Deserialize, Serialize};
+
+use crate::{admin::{find_player, AdminAppExt, AdminCommand}, player::KickPlayerEvent};
+
+/// An address, or a whole block of them in CIDR notation, e.g. "10.0.0.0/8" or "2001:db8::/32".
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub struct IpRange {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl IpRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(range), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(range) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(range), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(range) & mask == u128::from(address) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(address)) => {
+                // IPv4 clients show up as mapped addresses on a dual stack socket.
+                address.to_ipv4_mapped().is_some_and(|address| self.contains(IpAddr::V4(address)))
+            }
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(address: IpAddr) -> Self {
+        Self {
+            address,
+            prefix_len: if address.is_ipv4() { 32 } else { 128 },
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let address: IpAddr = address.trim().parse().map_err(|_| format!("Invalid address {}", address))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("Invalid prefix length {}", prefix_len))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self { address, prefix_len })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let max_prefix_len = if self.address.is_ipv4() { 32 } else { 128 };
+        if self.prefix_len == max_prefix_len {
+            write!(f, "{}", self.address)
+        } else {
+            write!(f, "{}/{}", self.address, self.prefix_len)
+        }
+    }
+}
+
+/// Who a ban or allowlist entry applies to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientMatch {
+    /// A player id. These only really identify someone with secure auth, unsecured clients pick their own.
+    Player(u64),
+    Address(IpRange),
+}
+
+impl ClientMatch {
+    pub fn matches(&self, client_id: u64, address: Option<IpAddr>) -> bool {
+        match self {
+            ClientMatch::Player(player_id) => *player_id == client_id,
+            ClientMatch::Address(range) => address.is_some_and(|address| range.contains(address)),
+        }
+    }
+}
+
+impl FromStr for ClientMatch {
+    type Err = String;
+
+    /// A number is a player id, anything else has to be an address or CIDR block.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<u64>() {
+            Ok(player_id) => Ok(ClientMatch::Player(player_id)),
+            Err(_) => s.parse::<IpRange>().map(ClientMatch::Address),
+        }
+    }
+}
+
+impl fmt::Display for ClientMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientMatch::Player(player_id) => write!(f, "player {}", player_id),
+            ClientMatch::Address(range) => write!(f, "{}", range),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub target: ClientMatch,
+
+    /// Told to the player when they're refused.
+    pub reason: String,
+}
+
+/// Who may connect. Changes are saved to `BanListPath` if there is one, so they survive restarts.
+#[derive(Resource, Serialize, Deserialize, Default, Debug, Clone)]
+pub struct BanList {
+    pub bans: Vec<Ban>,
+
+    /// Only clients matching `allowed` can connect, bans still apply to them.
+    #[serde(default)]
+    pub allowlist_only: bool,
+
+    #[serde(default)]
+    pub allowed: Vec<ClientMatch>,
+}
+
+/// Where the ban list is saved to whenever it changes.
+#[derive(Resource)]
+pub struct BanListPath(pub PathBuf);
+
+impl BanList {
+    pub fn ban(&mut self, target: ClientMatch, reason: String) {
+        self.bans.retain(|ban| ban.target != target);
+        self.bans.push(Ban { target, reason });
+    }
+
+    /// Returns how many bans were lifted.
+    pub fn unban(&mut self, target: ClientMatch) -> usize {
+        let len = self.bans.len();
+        self.bans.retain(|ban| ban.target != target);
+        len - self.bans.len()
+    }
+
+    /// Why the client may not connect, if they may not.
+    pub fn check(&self, client_id: u64, address: Option<IpAddr>) -> Result<(), String> {
+        if let Some(ban) = self.bans.iter().find(|ban| ban.target.matches(client_id, address)) {
+            return Err(format!("You are banned: {}", ban.reason));
+        }
+
+        if self.allowlist_only && !self.allowed.iter().any(|allowed| allowed.matches(client_id, address)) {
+            return Err("This server is private".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// An empty ban list if the file doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(ron::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let serialized = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, serialized)?;
+        Ok(())
+    }
+}
+
+pub struct BansPlugin;
+
+impl Plugin for BansPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BanList>();
+        app.add_admin_command::<BanCommand>();
+        app.add_admin_command::<BanAddressCommand>();
+        app.add_admin_command::<UnbanCommand>();
+        app.add_admin_command::<BansCommand>();
+        app.add_admin_command::<AllowlistCommand>();
+        app.add_systems(PostUpdate,
+            save_ban_list_system
+                .run_if(resource_exists_and_changed::<BanList>)
+                .run_if(resource_exists::<BanListPath>)
+        );
+    }
+}
+
+fn save_ban_list_system(
+    ban_list: Res<BanList>,
+    ban_list_path: Res<BanListPath>,
+) {
+    // Nothing has changed yet, and writing it back would clobber a file that failed to parse.
+    if ban_list.is_added() {
+        return;
+    }
+
+    match ban_list.save(&ban_list_path.0) {
+        Ok(_) => {
+            info!("Saved ban list to {}", ban_list_path.0.display());
+        }
+        Err(e) => {
+            error!("Failed to save ban list to {}: {}", ban_list_path.0.display(), e);
+        }
+    }
+}
+
+fn reason_or(reason: &[&str], default: &str) -> String {
+    if reason.is_empty() { default.to_string() } else { reason.join(" ") }
+}
+
+/// Bans a connected player by their id and address.
+pub struct BanCommand {
+    pub player: String,
+    pub reason: String,
+}
+
+impl AdminCommand for BanCommand {
+    const NAME: &'static str = "ban";
+    const USAGE: &'static str = "<player> [reason] - disconnects a player and keeps them out";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let (player, reason) = args.split_first().ok_or("Expected a player")?;
+        Ok(Self {
+            player: player.to_string(),
+            reason: reason_or(reason, "Banned by an admin"),
+        })
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let (client_id, name) = find_player(world, &self.player)?;
+        let address = world.get_resource::<NetcodeServerTransport>()
+            .and_then(|transport| transport.client_addr(client_id))
+            .map(|address| address.ip());
+
+        let mut ban_list = world.resource_mut::<BanList>();
+        ban_list.ban(ClientMatch::Player(client_id), self.reason.clone());
+        if let Some(address) = address {
+            ban_list.ban(ClientMatch::Address(address.into()), self.reason.clone());
+        }
+
+        world.send_event(KickPlayerEvent {
+            client_id,
+            reason: self.reason,
+        });
+        Ok(format!("Banned {} ({})", name, client_id))
+    }
+}
+
+/// Bans a player id, address or CIDR block whether or not anyone is connected from it.
+pub struct BanAddressCommand {
+    pub target: ClientMatch,
+    pub reason: String,
+}
+
+impl AdminCommand for BanAddressCommand {
+    const NAME: &'static str = "banip";
+    const USAGE: &'static str = "<player id|address|cidr> [reason] - bans anyone matching, e.g. banip 10.0.0.0/8";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        let (target, reason) = args.split_first().ok_or("Expected a player id, address or CIDR block")?;
+        Ok(Self {
+            target: target.parse()?,
+            reason: reason_or(reason, "Banned by an admin"),
+        })
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        world.resource_mut::<BanList>().ban(self.target, self.reason.clone());
+
+        // Kick whoever is already connected from there.
+        let transport = world.get_resource::<NetcodeServerTransport>();
+        let kicked: Vec<u64> = world.get_resource::<RenetServer>()
+            .map(|renet_server| renet_server.clients_id())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|client_id| {
+                let address = transport.and_then(|transport| transport.client_addr(*client_id)).map(|address| address.ip());
+                self.target.matches(*client_id, address)
+            })
+            .collect();
+
+        for client_id in kicked.iter() {
+            world.send_event(KickPlayerEvent {
+                client_id: *client_id,
+                reason: self.reason.clone(),
+            });
+        }
+
+        Ok(format!("Banned {}, kicked {} player(s)", self.target, kicked.len()))
+    }
+}
+
+pub struct UnbanCommand {
+    pub target: ClientMatch,
+}
+
+impl AdminCommand for UnbanCommand {
+    const NAME: &'static str = "unban";
+    const USAGE: &'static str = "<player id|address|cidr> - lifts a ban, exactly as it's listed by bans";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            [target] => Ok(Self { target: target.parse()? }),
+            _ => Err("Expected a player id, address or CIDR block".to_string()),
+        }
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        match world.resource_mut::<BanList>().unban(self.target) {
+            0 => Err(format!("{} isn't banned", self.target)),
+            _ => Ok(format!("Unbanned {}", self.target)),
+        }
+    }
+}
+
+pub struct BansCommand;
+
+impl AdminCommand for BansCommand {
+    const NAME: &'static str = "bans";
+    const USAGE: &'static str = "- shows every ban and the allowlist";
+
+    fn parse(_args: &[&str]) -> Result<Self, String> {
+        Ok(Self)
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let ban_list = world.resource::<BanList>();
+        let mut lines: Vec<String> = ban_list.bans.iter()
+            .map(|ban| format!("{}: {}", ban.target, ban.reason))
+            .collect();
+
+        if lines.is_empty() {
+            lines.push("Nobody is banned".to_string());
+        }
+
+        lines.push(format!("Allowlist {}", if ban_list.allowlist_only { "on" } else { "off" }));
+        lines.extend(ban_list.allowed.iter().map(|allowed| format!("  {}", allowed)));
+        Ok(lines.join("\n"))
+    }
+}
+
+pub enum AllowlistCommand {
+    Enable(bool),
+    Add(ClientMatch),
+    Remove(ClientMatch),
+}
+
+impl AdminCommand for AllowlistCommand {
+    const NAME: &'static str = "allowlist";
+    const USAGE: &'static str = "<on|off|add|remove> [player id|address|cidr] - only lets listed players connect while on";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            ["on"] => Ok(AllowlistCommand::Enable(true)),
+            ["off"] => Ok(AllowlistCommand::Enable(false)),
+            ["add", target] => Ok(AllowlistCommand::Add(target.parse()?)),
+            ["remove", target] => Ok(AllowlistCommand::Remove(target.parse()?)),
+            _ => Err("Expected on, off, add or remove".to_string()),
+        }
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let mut ban_list = world.resource_mut::<BanList>();
+        match self {
+            AllowlistCommand::Enable(enabled) => {
+                ban_list.allowlist_only = enabled;
+                Ok(format!("Allowlist {}, players already connected stay", if enabled { "on" } else { "off" }))
+            }
+            AllowlistCommand::Add(target) => {
+                if !ban_list.allowed.contains(&target) {
+                    ban_list.allowed.push(target);
+                }
+                Ok(format!("Allowed {}", target))
+            }
+            AllowlistCommand::Remove(target) => {
+                let len = ban_list.allowed.len();
+                ban_list.allowed.retain(|allowed| *allowed != target);
+                if ban_list.allowed.len() == len {
+                    return Err(format!("{} isn't on the allowlist", target));
+                }
+                Ok(format!("Removed {} from the allowlist", target))
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetServer};
+use boxman_shared::{chat::{sanitize_chat_message, ChatChannel, ChatLine, ChatLineKind}, protocol::ServerToClientMessage};
+
+use crate::{admin::{find_player, AdminAppExt, AdminCommand}, handshake::JoinedClients, player::Player};
+
+/// Chat messages a player may send per second on average.
+const CHAT_MESSAGES_PER_SECOND: f32 = 1.0;
+
+/// How many chat messages a player may send at once.
+const CHAT_MESSAGE_BURST: f32 = 5.0;
+
+/// A client's `Chat` message, before it's been checked.
+#[derive(Event)]
+pub struct ChatReceivedEvent {
+    pub client_id: u64,
+    pub channel: ChatChannel,
+    pub text: String,
+}
+
+/// Sends a message from the server to everyone.
+#[derive(Event, Debug, Clone)]
+pub struct ServerAnnouncementEvent(pub String);
+
+/// What a filter decided to do with a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatVerdict {
+    Allow,
+
+    /// Send this text instead, e.g. with the swear words starred out.
+    Replace(String),
+
+    /// Don't send it, the sender is told the reason.
+    Block(String),
+}
+
+/// Checks chat messages before they're passed on. Filters run in the order they were added,
+/// each one sees the text as the previous one left it.
+pub trait ChatFilter: Send + Sync + 'static {
+    fn filter(&self, sender: &Player, channel: ChatChannel, text: &str) -> ChatVerdict;
+}
+
+/// Every filter chat messages go through.
+#[derive(Resource, Default)]
+pub struct ChatFilters {
+    filters: Vec<Box<dyn ChatFilter>>,
+}
+
+impl ChatFilters {
+    pub fn push(&mut self, filter: impl ChatFilter) {
+        self.filters.push(Box::new(filter));
+    }
+
+    /// Runs every filter over the message, the text to send or why it was blocked.
+    pub fn apply(&self, sender: &Player, channel: ChatChannel, text: &str) -> Result<String, String> {
+        let mut text = text.to_string();
+        for filter in self.filters.iter() {
+            match filter.filter(sender, channel, &text) {
+                ChatVerdict::Allow => {}
+                ChatVerdict::Replace(replacement) => text = replacement,
+                ChatVerdict::Block(reason) => return Err(reason),
+            }
+        }
+
+        Ok(text)
+    }
+}
+
+pub trait ChatAppExt {
+    /// Adds a filter every chat message goes through, after the ones already added.
+    fn add_chat_filter(&mut self, filter: impl ChatFilter) -> &mut Self;
+}
+
+impl ChatAppExt for App {
+    fn add_chat_filter(&mut self, filter: impl ChatFilter) -> &mut Self {
+        self.world_mut().get_resource_or_insert_with(ChatFilters::default).push(filter);
+        self
+    }
+}
+
+/// Blocks everything from muted players.
+pub struct MutedPlayersFilter;
+
+impl ChatFilter for MutedPlayersFilter {
+    fn filter(&self, sender: &Player, _channel: ChatChannel, _text: &str) -> ChatVerdict {
+        if sender.muted {
+            return ChatVerdict::Block("You are muted".to_string());
+        }
+
+        ChatVerdict::Allow
+    }
+}
+
+/// Stars out words from a list, ignoring case. Only whole words are matched, so "class" is fine when "ass" isn't.
+pub struct WordFilter {
+    words: Vec<String>,
+}
+
+impl WordFilter {
+    pub fn new(words: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self {
+            words: words.into_iter()
+                .map(|word| word.as_ref().trim().to_lowercase())
+                .filter(|word| !word.is_empty())
+                .collect(),
+        }
+    }
+}
+
+impl ChatFilter for WordFilter {
+    fn filter(&self, _sender: &Player, _channel: ChatChannel, text: &str) -> ChatVerdict {
+        let mut filtered = String::with_capacity(text.len());
+        let mut changed = false;
+
+        for (is_word, part) in split_words(text) {
+            if is_word && self.words.contains(&part.to_lowercase()) {
+                filtered.extend(part.chars().map(|_| '*'));
+                changed = true;
+            } else {
+                filtered.push_str(part);
+            }
+        }
+
+        if changed {
+            ChatVerdict::Replace(filtered)
+        } else {
+            ChatVerdict::Allow
+        }
+    }
+}
+
+/// Splits text into runs of word and non-word characters, with whether each one is a word.
+fn split_words(text: &str) -> impl Iterator<Item = (bool, &str)> {
+    let mut rest = text;
+    std::iter::from_fn(move || {
+        let first = rest.chars().next()?;
+        let is_word = first.is_alphanumeric();
+        let end = rest.find(|c: char| c.is_alphanumeric() != is_word).unwrap_or(rest.len());
+        let (part, remaining) = rest.split_at(end);
+        rest = remaining;
+        Some((is_word, part))
+    })
+}
+
+/// How many chat messages a player may still send right now, refilled over time.
+#[derive(Component)]
+pub struct ChatRateLimit {
+    pub allowance: f32,
+}
+
+impl Default for ChatRateLimit {
+    fn default() -> Self {
+        Self {
+            allowance: CHAT_MESSAGE_BURST,
+        }
+    }
+}
+
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ChatReceivedEvent>();
+        app.add_event::<ServerAnnouncementEvent>();
+        app.init_resource::<ChatFilters>();
+        app.add_chat_filter(MutedPlayersFilter);
+        app.add_admin_command::<MuteCommand>();
+        app.add_systems(PostUpdate, (
+            chat_rate_limit_system,
+            chat_system,
+            server_announcement_system,
+        ).chain());
+    }
+}
+
+fn chat_rate_limit_system(
+    time: Res<Time>,
+    mut rate_limits: Query<&mut ChatRateLimit>,
+) {
+    for mut rate_limit in rate_limits.iter_mut() {
+        rate_limit.allowance = (rate_limit.allowance + time.delta_secs() * CHAT_MESSAGES_PER_SECOND).min(CHAT_MESSAGE_BURST);
+    }
+}
+
+/// Checks chat messages from players and passes them on to whoever they're for.
+fn chat_system(
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+    mut chat_received_events: EventReader<ChatReceivedEvent>,
+    chat_filters: Res<ChatFilters>,
+    players: Query<&Player>,
+    mut rate_limits: Query<(&Player, &mut ChatRateLimit)>,
+) {
+    for event in chat_received_events.read() {
+        let Some(sender) = players.iter().find(|player| player.client_id == event.client_id) else {
+            continue;
+        };
+
+        let Some(text) = sanitize_chat_message(&event.text) else {
+            continue;
+        };
+
+        let Some((_, mut rate_limit)) = rate_limits.iter_mut().find(|(player, _)| player.client_id == event.client_id) else {
+            continue;
+        };
+
+        if rate_limit.allowance < 1.0 {
+            send_notice(&mut renet_server, event.client_id, "You are sending messages too fast");
+            continue;
+        }
+        rate_limit.allowance -= 1.0;
+
+        let text = match chat_filters.apply(sender, event.channel, &text) {
+            Ok(text) => text,
+            Err(reason) => {
+                info!("Blocked chat from {} ({}): {}", sender.client_id, sender.name, reason);
+                send_notice(&mut renet_server, event.client_id, &reason);
+                continue;
+            }
+        };
+
+        let recipients: Vec<u64> = match (event.channel, sender.team) {
+            (ChatChannel::All, _) => joined_clients.iter().collect(),
+            (ChatChannel::Team, Some(team)) => players.iter()
+                .filter(|player| player.team == Some(team) && joined_clients.contains(player.client_id))
+                .map(|player| player.client_id)
+                .collect(),
+            (ChatChannel::Team, None) => {
+                send_notice(&mut renet_server, event.client_id, "You are not on a team");
+                continue;
+            }
+        };
+
+        info!("[{:?}] {}: {}", event.channel, sender.name, text);
+
+        let line = ChatLine {
+            kind: ChatLineKind::Player {
+                sender_id: sender.client_id,
+                sender_name: sender.name.clone(),
+                channel: event.channel,
+            },
+            text,
+        };
+
+        match ServerToClientMessage::Chat(line).to_bytes() {
+            Ok(serialized) => {
+                for client_id in recipients {
+                    renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized.clone());
+                }
+            }
+            Err(e) => {
+                error!("Error serializing message: {}", e);
+            }
+        }
+    }
+}
+
+fn server_announcement_system(
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+    mut server_announcement_events: EventReader<ServerAnnouncementEvent>,
+) {
+    for ServerAnnouncementEvent(text) in server_announcement_events.read() {
+        info!("[Announcement] {}", text);
+
+        let line = ChatLine {
+            kind: ChatLineKind::Announcement,
+            text: text.clone(),
+        };
+
+        match ServerToClientMessage::Chat(line).to_bytes() {
+            Ok(serialized) => {
+                joined_clients.broadcast(&mut renet_server, DefaultChannel::ReliableOrdered, serialized);
+            }
+            Err(e) => {
+                error!("Error serializing message: {}", e);
+            }
+        }
+    }
+}
+
+/// Tells just this player something, e.g. why their message was blocked.
+fn send_notice(renet_server: &mut RenetServer, client_id: u64, text: &str) {
+    let line = ChatLine {
+        kind: ChatLineKind::Notice,
+        text: text.to_string(),
+    };
+
+    match ServerToClientMessage::Chat(line).to_bytes() {
+        Ok(serialized) => {
+            renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Error serializing message: {}", e);
+        }
+    }
+}
+
+/// Toggles whether a player can chat.
+pub struct MuteCommand {
+    pub player: String,
+}
+
+impl AdminCommand for MuteCommand {
+    const NAME: &'static str = "mute";
+    const USAGE: &'static str = "<player> - mutes or unmutes a player's chat";
+
+    fn parse(args: &[&str]) -> Result<Self, String> {
+        match args {
+            [player] => Ok(Self { player: player.to_string() }),
+            _ => Err("Expected a player".to_string()),
+        }
+    }
+
+    fn run(self, world: &mut World) -> Result<String, String> {
+        let (client_id, name) = find_player(world, &self.player)?;
+        let mut players = world.query::<&mut Player>();
+        let mut player = players.iter_mut(world)
+            .find(|player| player.client_id == client_id)
+            .ok_or_else(|| format!("No player {}", self.player))?;
+
+        player.muted = !player.muted;
+        Ok(format!("{} {} ({})", if player.muted { "Muted" } else { "Unmuted" }, name, client_id))
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetServer};
+use boxman_shared::{level::CurrentLevel, protocol::{Handshake, ServerToClientMessage}};
+
+use crate::player::{KickPlayerEvent, Player};
+
+/// Seconds a client has to send its handshake before it's kicked.
+const HANDSHAKE_TIMEOUT: f32 = 5.0;
+
+/// A client's `Handshake` message.
+#[derive(Event)]
+pub struct ClientHandshakeEvent(pub u64, pub Handshake);
+
+/// A client passed the handshake, its character is spawned and it's sent the game from here on.
+#[derive(Event)]
+pub struct HandshakeCompletedEvent(pub u64);
+
+/// The player hasn't sent a handshake yet, they're kicked when the timer runs out.
+/// Until then they only get the handshake (and a kick), they may not be able to read anything else.
+#[derive(Component)]
+pub struct AwaitingHandshake {
+    pub timer: Timer,
+}
+
+impl Default for AwaitingHandshake {
+    fn default() -> Self {
+        Self {
+            timer: Timer::from_seconds(HANDSHAKE_TIMEOUT, TimerMode::Once),
+        }
+    }
+}
+
+/// Clients that passed the handshake. Anything sent to everyone should go through `broadcast`,
+/// so clients that are still connecting (or on another version) don't get messages they can't read.
+#[derive(Resource, Default)]
+pub struct JoinedClients(HashSet<u64>);
+
+impl JoinedClients {
+    pub fn contains(&self, client_id: u64) -> bool {
+        self.0.contains(&client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.0.iter().copied()
+    }
+
+    pub fn remove(&mut self, client_id: u64) -> bool {
+        self.0.remove(&client_id)
+    }
+
+    pub fn broadcast(&self, renet_server: &mut RenetServer, channel: DefaultChannel, serialized: Vec<u8>) {
+        for client_id in self.iter() {
+            renet_server.send_message(client_id, channel, serialized.clone());
+        }
+    }
+}
+
+pub struct HandshakePlugin;
+
+impl Plugin for HandshakePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ClientHandshakeEvent>();
+        app.add_event::<HandshakeCompletedEvent>();
+        app.init_resource::<JoinedClients>();
+        app.add_systems(PostUpdate, (
+            handshake_system,
+            handshake_timeout_system,
+        ));
+    }
+}
+
+/// Sends the server's handshake, this should be the first message a client gets.
+pub fn send_handshake(renet_server: &mut RenetServer, client_id: u64, current_level: Option<&CurrentLevel>) {
+    match ServerToClientMessage::Handshake(Handshake::new(current_level)).to_bytes() {
+        Ok(serialized) => {
+            renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Error serializing message: {}", e);
+        }
+    }
+}
+
+/// Lets compatible clients join, and kicks clients that can't play on this server with a reason they'll see.
+fn handshake_system(
+    mut commands: Commands,
+    mut joined_clients: ResMut<JoinedClients>,
+    mut client_handshake_events: EventReader<ClientHandshakeEvent>,
+    mut handshake_completed_events: EventWriter<HandshakeCompletedEvent>,
+    mut kick_events: EventWriter<KickPlayerEvent>,
+    players: Query<(Entity, &Player), With<AwaitingHandshake>>,
+) {
+    let local = Handshake::new(None);
+
+    for ClientHandshakeEvent(client_id, handshake) in client_handshake_events.read() {
+        let Some((entity, player)) = players.iter().find(|(_, player)| player.client_id == *client_id) else {
+            continue;
+        };
+
+        match handshake.check(&local) {
+            Ok(()) => {
+                info!("Player {} ({}) completed the handshake", client_id, player.name);
+                commands.entity(entity).remove::<AwaitingHandshake>();
+                joined_clients.0.insert(*client_id);
+                handshake_completed_events.send(HandshakeCompletedEvent(*client_id));
+            }
+            Err(reason) => {
+                kick_events.send(KickPlayerEvent {
+                    client_id: *client_id,
+                    reason: format!("Incompatible client: {}", reason),
+                });
+            }
+        }
+    }
+}
+
+/// Clients from before the handshake existed never send one.
+fn handshake_timeout_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut kick_events: EventWriter<KickPlayerEvent>,
+    mut players: Query<(Entity, &Player, &mut AwaitingHandshake)>,
+) {
+    for (entity, player, mut awaiting_handshake) in players.iter_mut() {
+        if awaiting_handshake.timer.tick(time.delta()).just_finished() {
+            kick_events.send(KickPlayerEvent {
+                client_id: player.client_id,
+                reason: "No handshake received, your client is probably out of date".to_string(),
+            });
+            commands.entity(entity).remove::<AwaitingHandshake>();
+        }
+    }
+}
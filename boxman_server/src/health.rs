@@ -0,0 +1,113 @@
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetServer};
+use boxman_shared::{
+    character::{Character, CharacterJump},
+    data::CharacterConfig,
+    health::{apply_damage, Armor, CharacterDeathEvent, DamageEvent, Health},
+    level::CurrentLevel,
+    moveable_sim::MoveableSimulation,
+    protocol::ServerToClientMessage,
+    weapons::WeaponHitEvent,
+};
+
+use crate::{handshake::JoinedClients, player::pick_spawn_point};
+
+/// Counts down until a dead character respawns.
+#[derive(Component)]
+pub struct RespawnTimer(pub Timer);
+
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedPostUpdate,
+            (
+                weapon_damage_system,
+                damage_system,
+                respawn_system,
+            )
+            .chain()
+            .run_if(resource_exists::<CharacterConfig>)
+        );
+    }
+}
+
+fn weapon_damage_system(
+    mut weapon_hit_events: EventReader<WeaponHitEvent>,
+    mut damage_events: EventWriter<DamageEvent>,
+) {
+    for event in weapon_hit_events.read() {
+        damage_events.send(DamageEvent {
+            target_id: event.target_id,
+            attacker_id: Some(event.shooter_id),
+            amount: event.damage,
+        });
+    }
+}
+
+fn damage_system(
+    mut commands: Commands,
+    character_config: Res<CharacterConfig>,
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+    mut damage_events: EventReader<DamageEvent>,
+    mut character_death_events: EventWriter<CharacterDeathEvent>,
+    mut characters: Query<(Entity, &Character, &mut Health, &mut Armor)>,
+) {
+    for event in damage_events.read() {
+        let Some((entity, character, mut health, mut armor)) = characters.iter_mut()
+            .find(|(_, character, _, _)| character.client_id == event.target_id) else {
+            continue;
+        };
+
+        if !apply_damage(&mut health, &mut armor, event.amount, character_config.armor_absorption) {
+            continue;
+        }
+
+        info!("Player {} was killed by {:?}", character.client_id, event.attacker_id);
+        commands.entity(entity).insert(RespawnTimer(Timer::from_seconds(character_config.respawn_time, TimerMode::Once)));
+
+        let character_death_event = CharacterDeathEvent {
+            client_id: character.client_id,
+            killer_id: event.attacker_id,
+        };
+        character_death_events.send(character_death_event.clone());
+
+        match ServerToClientMessage::CharacterDied(character_death_event).to_bytes() {
+            Ok(serialized) => {
+                joined_clients.broadcast(&mut renet_server, DefaultChannel::ReliableOrdered, serialized);
+            }
+            Err(e) => {
+                error!("Error serializing message: {}", e);
+            }
+        }
+    }
+}
+
+fn respawn_system(
+    mut commands: Commands,
+    fixed_time: Res<Time<Fixed>>,
+    character_config: Res<CharacterConfig>,
+    current_level: Option<Res<CurrentLevel>>,
+    mut characters: Query<(Entity, &mut RespawnTimer, &mut Health, &mut Armor, &mut Transform, &mut MoveableSimulation, &mut CharacterJump)>,
+) {
+    for (entity, mut respawn_timer, mut health, mut armor, mut transform, mut simulation, mut jump) in characters.iter_mut() {
+        respawn_timer.0.tick(fixed_time.delta());
+        if !respawn_timer.0.finished() {
+            continue;
+        }
+
+        let spawn_point = pick_spawn_point(current_level.as_deref());
+        transform.translation = spawn_point.position;
+        transform.rotation = Quat::from_rotation_y(spawn_point.yaw);
+        simulation.velocity = Vec3::ZERO;
+        simulation.last_translation = spawn_point.position;
+        simulation.last_rotation = transform.rotation;
+        *jump = CharacterJump::default();
+
+        health.current = health.max;
+        armor.current = character_config.spawn_armor.min(armor.max);
+
+        commands.entity(entity).remove::<RespawnTimer>();
+    }
+}
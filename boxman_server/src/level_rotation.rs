@@ -0,0 +1,74 @@
+use bevy::prelude::*;
+use boxman_shared::{character::CharacterJump, data::ServerConfig, level::{CurrentLevel, LoadLevelEvent}, moveable_sim::MoveableSimulation, prelude::Character};
+
+use crate::{chat::ServerAnnouncementEvent, player::pick_spawn_point};
+
+/// How long the current level has been played for.
+#[derive(Resource, Default)]
+pub struct LevelRotation {
+    pub elapsed: f32,
+}
+
+pub struct LevelRotationPlugin;
+
+impl Plugin for LevelRotationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LevelRotation>();
+        app.add_systems(Update, (
+            level_rotation_system
+                .run_if(resource_exists::<ServerConfig>)
+                .run_if(resource_exists::<CurrentLevel>),
+            level_changed_system.run_if(resource_exists_and_changed::<CurrentLevel>),
+        ));
+    }
+}
+
+/// Moves on to the next level in the config once the current one has run its time.
+fn level_rotation_system(
+    time: Res<Time>,
+    server_config: Res<ServerConfig>,
+    current_level: Res<CurrentLevel>,
+    mut level_rotation: ResMut<LevelRotation>,
+    mut load_level_events: EventWriter<LoadLevelEvent>,
+    mut server_announcement_events: EventWriter<ServerAnnouncementEvent>,
+) {
+    if server_config.level_duration <= 0.0 || server_config.levels.is_empty() {
+        return;
+    }
+
+    level_rotation.elapsed += time.delta_secs();
+    if level_rotation.elapsed < server_config.level_duration {
+        return;
+    }
+    level_rotation.elapsed = 0.0;
+
+    // Levels loaded by hand that aren't in the rotation are followed by the first one.
+    let next_index = server_config.levels.iter()
+        .position(|name| *name == current_level.name)
+        .map_or(0, |index| (index + 1) % server_config.levels.len());
+    let next_level = &server_config.levels[next_index];
+
+    if *next_level != current_level.name {
+        server_announcement_events.send(ServerAnnouncementEvent(format!("Changing level to {}", next_level)));
+        load_level_events.send(LoadLevelEvent(next_level.clone()));
+    }
+}
+
+/// Restarts the clock and moves every character to a spawn point in the new level.
+fn level_changed_system(
+    current_level: Res<CurrentLevel>,
+    mut level_rotation: ResMut<LevelRotation>,
+    mut characters: Query<(&mut Transform, &mut MoveableSimulation, &mut CharacterJump), With<Character>>,
+) {
+    level_rotation.elapsed = 0.0;
+
+    for (mut transform, mut simulation, mut jump) in characters.iter_mut() {
+        let spawn_point = pick_spawn_point(Some(&current_level));
+        transform.translation = spawn_point.position;
+        transform.rotation = Quat::from_rotation_y(spawn_point.yaw);
+        simulation.velocity = Vec3::ZERO;
+        simulation.last_translation = spawn_point.position;
+        simulation.last_rotation = transform.rotation;
+        *jump = CharacterJump::default();
+    }
+}
@@ -1,16 +1,192 @@
+pub mod admin;
+pub mod auth;
+pub mod bans;
+pub mod chat;
+pub mod handshake;
+mod health;
+mod level_rotation;
 pub mod player;
 mod snapshot;
-use std::{error::Error, net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket}, time::SystemTime};
+mod weapons;
+use std::{error::Error, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, time::SystemTime};
 
 use bevy::prelude::*;
 use bevy_renet::{
-    netcode::{NetcodeServerPlugin, NetcodeServerTransport, ServerAuthentication, ServerConfig}, 
+    netcode::{NetcodeServerPlugin, NetcodeServerTransport, ServerAuthentication, ServerConfig as NetcodeServerConfig, NETCODE_KEY_BYTES}, 
     renet::{ConnectionConfig, DefaultChannel, RenetServer}, 
     RenetServerPlugin
 };
-use boxman_shared::{ protocol::ClientToServerMessage, utils::{GameServer, ServerPort}};
-use player::{PlayerInputEvent, PlayerPlugin};
-use snapshot::SnapshotPlugin;
+use boxman_shared::{conditioner::{LinkConditioner, NetworkConditions}, data::{CharacterConfig, ServerConfig}, level::{CurrentLevel, LoadLevelEvent}, protocol::{content_hash, ClientToServerMessage, ServerToClientMessage}, utils::{GameServer, InMemoryTransport, ServerLevel, ServerPort}, weapons::WeaponConfig};
+use admin::AdminPlugin;
+use bans::BansPlugin;
+use chat::{ChatPlugin, ChatReceivedEvent};
+use handshake::{ClientHandshakeEvent, HandshakePlugin, JoinedClients};
+use health::HealthPlugin;
+use level_rotation::LevelRotationPlugin;
+use player::{Player, PlayerInputEvent, PlayerInputQueue, PlayerPlugin, PlayerProfileEvent};
+use snapshot::{SnapshotContainer, SnapshotPlugin};
+use weapons::WeaponsPlugin;
+
+/// How clients prove who they are when connecting.
+#[derive(Resource, Clone, Default)]
+pub enum ServerSecurity {
+    /// Anyone can connect with whatever client id and name they like.
+    #[default]
+    Unsecure,
+
+    /// Clients need a connect token made with `private_key`, see `auth::TokenService`.
+    /// `public_addresses` are the addresses clients connect to, tokens are only valid for those.
+    Secure {
+        private_key: [u8; NETCODE_KEY_BYTES],
+        public_addresses: Vec<SocketAddr>,
+    },
+}
+
+/// Command line flags for simulating a bad network, shared by the server and the game.
+/// Giving any of them puts a `LinkConditioner` in the way, the rest default to perfect.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct NetworkSimArgs {
+    /// Simulated latency in seconds, each way.
+    #[arg(long)]
+    pub sim_latency: Option<f32>,
+
+    /// Up to this many seconds of extra latency per packet.
+    #[arg(long)]
+    pub sim_jitter: Option<f32>,
+
+    /// Chance a packet is dropped, 0 to 1.
+    #[arg(long)]
+    pub sim_loss: Option<f32>,
+
+    /// Chance a packet arrives twice, 0 to 1.
+    #[arg(long)]
+    pub sim_duplication: Option<f32>,
+
+    /// Chance a packet arrives after the ones sent after it, 0 to 1.
+    #[arg(long)]
+    pub sim_reorder: Option<f32>,
+
+    /// Makes the simulated conditions repeatable.
+    #[arg(long)]
+    pub sim_seed: Option<u64>,
+}
+
+impl NetworkSimArgs {
+    /// None when no flag was given, so nothing is simulated.
+    pub fn conditions(&self) -> Option<NetworkConditions> {
+        let given = self.sim_latency.is_some()
+            || self.sim_jitter.is_some()
+            || self.sim_loss.is_some()
+            || self.sim_duplication.is_some()
+            || self.sim_reorder.is_some()
+            || self.sim_seed.is_some();
+
+        given.then(|| NetworkConditions {
+            latency: self.sim_latency.unwrap_or_default(),
+            jitter: self.sim_jitter.unwrap_or_default(),
+            loss: self.sim_loss.unwrap_or_default(),
+            duplication: self.sim_duplication.unwrap_or_default(),
+            reorder: self.sim_reorder.unwrap_or_default(),
+            seed: self.sim_seed,
+        }.sanitized())
+    }
+}
+
+/// Server config settings given on the command line. They're applied over the config file every time it's loaded,
+/// so they stick when it's hot reloaded.
+#[derive(clap::Args, Resource, Debug, Clone, Default)]
+pub struct ServerConfigOverrides {
+    #[arg(long)]
+    pub port: Option<u16>,
+
+    /// e.g. "0.0.0.0", or "::" for IPv6.
+    #[arg(long)]
+    pub bind: Option<IpAddr>,
+
+    #[arg(long)]
+    pub tick_rate: Option<f64>,
+
+    #[arg(long)]
+    pub send_rate: Option<f64>,
+
+    #[arg(long)]
+    pub max_players: Option<usize>,
+
+    #[arg(long)]
+    pub protocol_id: Option<u64>,
+
+    /// Snapshots kept to encode deltas against.
+    #[arg(long)]
+    pub snapshot_history: Option<usize>,
+
+    /// Characters further than this from a client aren't sent to it.
+    #[arg(long)]
+    pub relevance_distance: Option<f32>,
+
+    /// Bytes of character state per snapshot packet.
+    #[arg(long)]
+    pub snapshot_byte_budget: Option<usize>,
+
+    /// The level rotation, e.g. "arena,canyon". Starts with the first one.
+    #[arg(long, value_delimiter = ',')]
+    pub levels: Option<Vec<String>>,
+
+    /// Seconds per level before rotating, 0 never rotates.
+    #[arg(long)]
+    pub level_duration: Option<f32>,
+}
+
+impl ServerConfigOverrides {
+    pub fn apply(&self, server_config: &mut ServerConfig) {
+        if let Some(port) = self.port {
+            server_config.port = port;
+        }
+        if let Some(bind) = self.bind {
+            server_config.bind_address = bind;
+        }
+        if let Some(tick_rate) = self.tick_rate {
+            server_config.tick_rate = tick_rate;
+        }
+        if let Some(send_rate) = self.send_rate {
+            server_config.snapshot_send_rate = send_rate;
+        }
+        if let Some(max_players) = self.max_players {
+            server_config.max_players = max_players;
+        }
+        if let Some(protocol_id) = self.protocol_id {
+            server_config.protocol_id = protocol_id;
+        }
+        if let Some(snapshot_history) = self.snapshot_history {
+            server_config.snapshot_history = snapshot_history;
+        }
+        if let Some(relevance_distance) = self.relevance_distance {
+            server_config.relevance_distance = relevance_distance;
+        }
+        if let Some(snapshot_byte_budget) = self.snapshot_byte_budget {
+            server_config.snapshot_byte_budget = snapshot_byte_budget;
+        }
+        if let Some(levels) = &self.levels {
+            server_config.levels = levels.clone();
+        }
+        if let Some(level_duration) = self.level_duration {
+            server_config.level_duration = level_duration;
+        }
+
+        if !server_config.tick_rate.is_finite() || server_config.tick_rate <= 0.0 {
+            error!("Invalid tick rate {}, using 64", server_config.tick_rate);
+            server_config.tick_rate = 64.0;
+        }
+    }
+}
+
+/// How the server gets going once its config is loaded, in order.
+#[derive(SystemSet, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ServerStartSet {
+    /// The command line overrides are applied, the config is final after this.
+    Config,
+    /// The server starts listening, e.g. `ServerSecurity` has to be in place before this.
+    Listen,
+}
 
 pub struct GameServerPlugin;
 
@@ -21,28 +197,155 @@ impl Plugin for GameServerPlugin {
             NetcodeServerPlugin,
             SnapshotPlugin,
             PlayerPlugin,
+            HandshakePlugin,
+            WeaponsPlugin,
+            HealthPlugin,
+            ChatPlugin,
+            AdminPlugin,
+            BansPlugin,
+            LevelRotationPlugin,
         ));
 
         let server = RenetServer::new(ConnectionConfig::default());
         app.insert_resource(server);
         app.insert_resource(GameServer);
-        app.add_systems(Startup, start_server_system);
+        app.add_systems(Startup, 
+            load_server_level_system.run_if(resource_exists::<ServerLevel>),
+        );
+        app.configure_sets(Update, (ServerStartSet::Config, ServerStartSet::Listen).chain());
         app.add_systems(Update, (
+            server_config_overrides_system
+                .run_if(resource_exists::<ServerConfigOverrides>)
+                .run_if(resource_exists_and_changed::<ServerConfig>)
+                .in_set(ServerStartSet::Config),
+            start_server_system
+                .run_if(resource_exists::<ServerConfig>)
+                .run_if(not(resource_exists::<InMemoryTransport>))
+                .in_set(ServerStartSet::Listen),
+            tick_rate_system
+                .run_if(resource_exists_and_changed::<ServerConfig>)
+                .after(ServerStartSet::Config),
             message_receiver_system,
+            broadcast_level_system.run_if(resource_exists_and_changed::<CurrentLevel>),
+            broadcast_gameplay_config_system
+                .run_if(resource_exists::<CharacterConfig>)
+                .run_if(resource_exists::<WeaponConfig>)
+                .after(tick_rate_system),
         ));
     }
 }
 
+/// Puts the command line overrides back over a freshly loaded (or reloaded) server config.
+fn server_config_overrides_system(
+    server_config_overrides: Res<ServerConfigOverrides>,
+    mut server_config: ResMut<ServerConfig>,
+) {
+    // It's already marked as changed by the load, and marking it again would have this run every frame.
+    server_config_overrides.apply(server_config.bypass_change_detection());
+}
+
+/// Starts listening once the server config is available, which takes a moment when it's loaded as an asset.
 fn start_server_system(
     mut commands: Commands,
-    server_port: Res<ServerPort>,
+    mut started: Local<bool>,
+    server_config: Res<ServerConfig>,
+    server_port: Option<Res<ServerPort>>,
+    server_security: Option<Res<ServerSecurity>>,
+    network_conditions: Option<Res<NetworkConditions>>,
 ) {
-    match listen(&mut commands, server_port.0) {
+    if *started {
+        return;
+    }
+    *started = true;
+
+    // A port given on the command line wins over the config.
+    let port = server_port.map_or(server_config.port, |server_port| server_port.0);
+    let server_security = server_security.map(|security| security.clone()).unwrap_or_default();
+    match listen(&mut commands, &server_config, port, &server_security, network_conditions.as_deref()) {
         Ok(_) => {
-            info!("Server started on port {}", server_port.0);
+            info!("Server started on {}", SocketAddr::new(server_config.bind_address, port));
         }
         Err(e) => {
-            error!("Failed to start server on port {}: {}", server_port.0, e);
+            error!("Failed to start server on port {}: {}", port, e);
+        }
+    }
+}
+
+fn tick_rate_system(
+    server_config: Res<ServerConfig>,
+    mut fixed_time: ResMut<Time<Fixed>>,
+) {
+    if server_config.tick_rate > 0.0 && fixed_time.timestep().as_secs_f64() != 1.0 / server_config.tick_rate {
+        info!("Tick rate set to {} Hz", server_config.tick_rate);
+        fixed_time.set_timestep_hz(server_config.tick_rate);
+    }
+}
+
+fn load_server_level_system(
+    server_level: Res<ServerLevel>,
+    mut load_level_events: EventWriter<LoadLevelEvent>,
+) {
+    load_level_events.send(LoadLevelEvent(server_level.0.clone()));
+}
+
+/// Tells every joined client to load the level whenever it changes.
+/// Clients that join later are told when they join instead.
+fn broadcast_level_system(
+    current_level: Res<CurrentLevel>,
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+) {
+    let message = ServerToClientMessage::LoadLevel {
+        name: current_level.name.clone(),
+        hash: content_hash(&current_level.level),
+    };
+
+    match message.to_bytes() {
+        Ok(serialized) => {
+            joined_clients.broadcast(&mut renet_server, DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Error serializing message: {}", e);
+        }
+    }
+}
+
+/// The configs clients predict with, from the server's point of view.
+pub fn gameplay_config_message(
+    character_config: &CharacterConfig,
+    weapon_config: &WeaponConfig,
+    server_config: Option<&ServerConfig>,
+    fixed_time: &Time<Fixed>,
+) -> ServerToClientMessage {
+    ServerToClientMessage::GameplayConfig {
+        tick_rate: 1.0 / fixed_time.timestep().as_secs_f64(),
+        snapshot_history: server_config.map_or(64, |server_config| server_config.snapshot_history.max(1)) as u32,
+        character: character_config.clone(),
+        weapons: weapon_config.clone(),
+    }
+}
+
+/// Sends every joined client the gameplay configs whenever they change, e.g. when they're hot reloaded or set by an admin.
+/// Clients that join later are sent them when they join instead.
+fn broadcast_gameplay_config_system(
+    character_config: Res<CharacterConfig>,
+    weapon_config: Res<WeaponConfig>,
+    server_config: Option<Res<ServerConfig>>,
+    fixed_time: Res<Time<Fixed>>,
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+) {
+    let server_config_changed = server_config.as_ref().is_some_and(|server_config| server_config.is_changed());
+    if !character_config.is_changed() && !weapon_config.is_changed() && !server_config_changed {
+        return;
+    }
+
+    match gameplay_config_message(&character_config, &weapon_config, server_config.as_deref(), &fixed_time).to_bytes() {
+        Ok(serialized) => {
+            joined_clients.broadcast(&mut renet_server, DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Error serializing message: {}", e);
         }
     }
 }
@@ -50,12 +353,90 @@ fn start_server_system(
 fn message_receiver_system(
     mut renet_server: ResMut<RenetServer>,
     mut player_input_events: EventWriter<PlayerInputEvent>,
+    mut player_profile_events: EventWriter<PlayerProfileEvent>,
+    mut chat_received_events: EventWriter<ChatReceivedEvent>,
+    mut client_handshake_events: EventWriter<ClientHandshakeEvent>,
+    fixed_time: Res<Time<Fixed>>,
+    snapshot_container: Res<SnapshotContainer>,
+    joined_clients: Res<JoinedClients>,
+    players: Query<(&Player, &PlayerInputQueue)>,
 ) {
     for client_id in renet_server.clients_id() {
+        // Until their handshake passes, all we want from them is the handshake. Their profile is kept too,
+        // it's what they join with. Anything else may be in a format we can't read, so it's dropped quietly.
+        if !joined_clients.contains(client_id) {
+            while renet_server.receive_message(client_id, DefaultChannel::Unreliable).is_some() {}
+
+            while let Some(message) = renet_server.receive_message(client_id, DefaultChannel::ReliableOrdered) {
+                match ClientToServerMessage::from_bytes(&message) {
+                    Ok(ClientToServerMessage::Handshake(handshake)) => {
+                        client_handshake_events.send(ClientHandshakeEvent(client_id, handshake));
+                    }
+                    Ok(ClientToServerMessage::SetProfile(profile)) => {
+                        player_profile_events.send(PlayerProfileEvent(client_id, profile));
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        debug!("Ignoring message from client {} before its handshake: {}", client_id, e);
+                    }
+                }
+            }
+            continue;
+        }
+
         while let Some(message) = renet_server.receive_message(client_id, DefaultChannel::Unreliable) {
-            match bincode::deserialize::<ClientToServerMessage>(&message) {
-                Ok(ClientToServerMessage::PlayerInput(player_input)) => {
-                    player_input_events.send(PlayerInputEvent(client_id, player_input));
+            match ClientToServerMessage::from_bytes(&message) {
+                Ok(ClientToServerMessage::PlayerInputs(player_inputs)) => {
+                    player_input_events.send(PlayerInputEvent(client_id, player_inputs));
+                }
+                Ok(ClientToServerMessage::Ping { client_time }) => {
+                    let server_tick = snapshot_container.next_id.saturating_sub(1);
+                    let buffered_inputs = players.iter()
+                        .find(|(player, _)| player.client_id == client_id)
+                        .map_or(0, |(_, input_queue)| input_queue.inputs.len() as u32);
+
+                    let message = ServerToClientMessage::Pong {
+                        client_time,
+                        server_tick,
+                        server_time: server_tick as f64 * fixed_time.timestep().as_secs_f64() + fixed_time.overstep().as_secs_f64(),
+                        buffered_inputs,
+                    };
+
+                    match message.to_bytes() {
+                        Ok(serialized) => {
+                            renet_server.send_message(client_id, DefaultChannel::Unreliable, serialized);
+                        }
+                        Err(e) => {
+                            error!("Error serializing message: {}", e);
+                        }
+                    }
+                }
+                Ok(_) => {
+                    error!("Received unexpected message from client {} on unreliable channel", client_id);
+                }
+                Err(e) => {
+                    error!("Error deserializing message from client {}: {}", client_id, e);
+                }
+            }
+        }
+
+        while let Some(message) = renet_server.receive_message(client_id, DefaultChannel::ReliableOrdered) {
+            match ClientToServerMessage::from_bytes(&message) {
+                Ok(ClientToServerMessage::Handshake(handshake)) => {
+                    client_handshake_events.send(ClientHandshakeEvent(client_id, handshake));
+                }
+                Ok(ClientToServerMessage::SetProfile(profile)) => {
+                    player_profile_events.send(PlayerProfileEvent(client_id, profile));
+                }
+                Ok(ClientToServerMessage::Chat { channel, text }) => {
+                    chat_received_events.send(ChatReceivedEvent {
+                        client_id,
+                        channel,
+                        text,
+                    });
+                }
+                Ok(_) => {
+                    error!("Received unexpected message from client {} on reliable channel", client_id);
                 }
                 Err(e) => {
                     error!("Error deserializing message from client {}: {}", client_id, e);
@@ -65,18 +446,40 @@ fn message_receiver_system(
     }
 }
 
+/// Starts the server on `port`. With `conditions`, clients connect through a `LinkConditioner` on that port instead,
+/// which relays to the server on loopback. They then all seem to connect from this machine, so don't ban anyone by address.
 pub fn listen(
     commands: &mut Commands,
+    config: &ServerConfig,
     port: u16,
+    security: &ServerSecurity,
+    conditions: Option<&NetworkConditions>,
 ) -> Result<(), Box<dyn Error>> {
-    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-    let socket = UdpSocket::bind(socket_addr)?;
-    let server_config = ServerConfig {
+    let socket_addr = SocketAddr::new(config.bind_address, port);
+    let socket = match conditions {
+        Some(conditions) => {
+            let loopback: IpAddr = if socket_addr.is_ipv6() { Ipv6Addr::LOCALHOST.into() } else { Ipv4Addr::LOCALHOST.into() };
+            let socket = UdpSocket::bind(SocketAddr::new(loopback, 0))?;
+            let conditioner = LinkConditioner::spawn(UdpSocket::bind(socket_addr)?, socket.local_addr()?, conditions.clone())?;
+            info!("Simulating network conditions for every client: {:?}", conditioner.conditions());
+            commands.insert_resource(conditioner);
+            socket
+        }
+        None => UdpSocket::bind(socket_addr)?,
+    };
+    let (public_addresses, authentication) = match security {
+        ServerSecurity::Unsecure => (vec![socket_addr], ServerAuthentication::Unsecure),
+        ServerSecurity::Secure { private_key, public_addresses } => (
+            public_addresses.clone(),
+            ServerAuthentication::Secure { private_key: *private_key },
+        ),
+    };
+    let server_config = NetcodeServerConfig {
         current_time: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?,
-        max_clients: 64,
-        protocol_id: 0,
-        public_addresses: vec![socket_addr],
-        authentication: ServerAuthentication::Unsecure
+        max_clients: config.max_players,
+        protocol_id: config.protocol_id,
+        public_addresses,
+        authentication,
     };
     let transport = NetcodeServerTransport::new(server_config, socket)?;
     commands.insert_resource(transport);
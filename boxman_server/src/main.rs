@@ -0,0 +1,218 @@
+use std::{net::{IpAddr, SocketAddr}, path::Path, time::Duration};
+
+use avian3d::PhysicsPlugins;
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    asset::AssetPlugin,
+    log::LogPlugin,
+    prelude::*,
+    scene::ScenePlugin,
+};
+use bevy_renet::netcode::generate_random_bytes;
+use bevy_config_stack::prelude::*;
+use boxman_server::{admin::{spawn_rcon, spawn_stdin_console, AdminConsole}, auth::TokenService, bans::{BanList, BanListPath}, chat::{ChatAppExt, WordFilter}, GameServerPlugin, NetworkSimArgs, ServerConfigOverrides, ServerSecurity, ServerStartSet};
+use boxman_shared::{data::{CharacterConfig, ServerConfig}, level::{LevelsDirectory, LoadLevelEvent}, weapons::WeaponConfig, SharedPlugin};
+use clap::Parser;
+
+/// How often the main loop runs, well above any sensible tick rate.
+const LOOP_INTERVAL: Duration = Duration::from_millis(2);
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None, name = "Boxman Server", author = "Riverside Games")]
+pub struct CommandLineArgs {
+    /// Directory containing the `data` and `levels` folders, same layout as the game's assets.
+    #[arg(long, default_value = "boxman_game/assets")]
+    pub assets: String,
+
+    /// The server config, relative to the assets directory. Every setting in it can be overridden below.
+    #[arg(long, default_value = "data/server.ron")]
+    pub config: String,
+
+    #[command(flatten)]
+    pub overrides: ServerConfigOverrides,
+
+    /// Only let clients in with a connect token from our token service.
+    #[arg(long)]
+    pub secure: bool,
+
+    /// The address clients connect to, connect tokens are only valid for it. Only used with --secure.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub public_ip: IpAddr,
+
+    /// The port the token service listens on. Only used with --secure.
+    #[arg(long, default_value_t = 5001)]
+    pub auth_port: u16,
+
+    /// A text file of words to star out in chat, one per line.
+    #[arg(long)]
+    pub banned_words: Option<String>,
+
+    /// Don't read admin commands from stdin, e.g. when running without a terminal.
+    #[arg(long)]
+    pub no_console: bool,
+
+    /// Port for the RCON listener, which only accepts connections from localhost.
+    #[arg(long, default_value_t = 5002)]
+    pub rcon_port: u16,
+
+    /// RCON is only enabled when this is set, clients send it before any commands.
+    #[arg(long)]
+    pub rcon_password: Option<String>,
+
+    /// Where the token service keeps every player's id, so they're the same after a restart. Only used with --secure.
+    #[arg(long, default_value = "players.ron")]
+    pub players: String,
+
+    /// Where bans and the allowlist are kept, it's created when someone is first banned.
+    #[arg(long, default_value = "bans.ron")]
+    pub bans: String,
+
+    /// Only let in players on the allowlist, see the `allowlist` admin command.
+    #[arg(long)]
+    pub allowlist: bool,
+
+    /// Every client connects through the simulated network, change it at runtime with the `netsim` admin command.
+    /// Clients all appear to connect from this machine while it's on.
+    #[command(flatten)]
+    pub network_sim: NetworkSimArgs,
+}
+
+fn main() {
+    let args = CommandLineArgs::parse();
+
+    // Asset paths are relative to the executable, the command line ones to where we're run from.
+    let assets = std::env::current_dir()
+        .map(|current_dir| current_dir.join(&args.assets))
+        .unwrap_or_else(|_| args.assets.clone().into());
+
+    let mut app = App::new();
+    app.add_plugins((
+        // Fixed ticks follow the tick rate in the config, which can change, the loop only has to come round often enough.
+        MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(LOOP_INTERVAL)),
+        LogPlugin::default(),
+        TransformPlugin,
+        HierarchyPlugin,
+        // Configs are hot reloaded from here, and Avian's collider constructors expect it to exist.
+        AssetPlugin {
+            file_path: assets.to_string_lossy().into_owned(),
+            watch_for_changes_override: Some(true),
+            ..default()
+        },
+        ScenePlugin,
+        PhysicsPlugins::default(),
+        SharedPlugin,
+        // Loaded the same way as with `boxman_game --server`. Clients get the character and weapon configs from us.
+        // The plugin keeps the path for as long as the app runs.
+        ConfigAssetLoaderPlugin::<ServerConfig>::new(args.config.clone().leak()),
+        ConfigAssetLoaderPlugin::<CharacterConfig>::new("data/character.ron"),
+        ConfigAssetLoaderPlugin::<WeaponConfig>::new("data/weapons.ron"),
+        GameServerPlugin,
+    ));
+    app.init_resource::<Assets<Mesh>>();
+    app.insert_resource(LevelsDirectory(assets.join("levels")));
+    app.insert_resource(args.overrides.clone());
+    if args.secure {
+        app.insert_resource(SecureArgs {
+            public_ip: args.public_ip,
+            auth_port: args.auth_port,
+            players: args.players.clone(),
+        });
+    }
+    app.add_systems(Update, config_loaded_system
+        .run_if(resource_exists::<ServerConfig>)
+        .after(ServerStartSet::Config)
+        .before(ServerStartSet::Listen));
+
+    match BanList::load(Path::new(&args.bans)) {
+        Ok(mut ban_list) => {
+            ban_list.allowlist_only |= args.allowlist;
+            app.insert_resource(ban_list);
+            app.insert_resource(BanListPath(args.bans.clone().into()));
+        }
+        Err(e) => {
+            // Don't save over a file we couldn't read, the bans in it would be lost.
+            error!("Failed to load ban list {}: {}, bans won't be saved", args.bans, e);
+            app.insert_resource(BanList {
+                allowlist_only: args.allowlist,
+                ..default()
+            });
+        }
+    }
+
+    if let Some(network_conditions) = args.network_sim.conditions() {
+        app.insert_resource(network_conditions);
+    }
+
+    if let Some(banned_words) = &args.banned_words {
+        match std::fs::read_to_string(banned_words) {
+            Ok(contents) => {
+                app.add_chat_filter(WordFilter::new(contents.lines()));
+            }
+            Err(e) => {
+                error!("Failed to read {}: {}, chat won't be filtered", banned_words, e);
+            }
+        }
+    }
+
+    let admin_sender = app.world().resource::<AdminConsole>().sender();
+    if !args.no_console {
+        spawn_stdin_console(admin_sender.clone());
+    }
+    if let Some(rcon_password) = args.rcon_password.clone() {
+        if let Err(e) = spawn_rcon(admin_sender, args.rcon_port, rcon_password) {
+            error!("Failed to start RCON on port {}: {}", args.rcon_port, e);
+        }
+    }
+
+    app.run();
+}
+
+/// What the token service needs besides the server config, only there with --secure.
+#[derive(Resource)]
+struct SecureArgs {
+    public_ip: IpAddr,
+    auth_port: u16,
+    players: String,
+}
+
+/// Gets going whatever needs the loaded config, before the server starts listening.
+fn config_loaded_system(
+    mut commands: Commands,
+    mut started: Local<bool>,
+    server_config: Res<ServerConfig>,
+    secure_args: Option<Res<SecureArgs>>,
+    mut load_level_events: EventWriter<LoadLevelEvent>,
+    mut app_exit_events: EventWriter<AppExit>,
+) {
+    if *started {
+        return;
+    }
+    *started = true;
+
+    load_level_events.send(LoadLevelEvent(server_config.levels.first().cloned().unwrap_or_else(|| "arena".to_string())));
+
+    if let Some(secure_args) = secure_args {
+        // The key only ever lives in this process, shared between the server and the token service.
+        let private_key = generate_random_bytes();
+        let public_addresses = vec![SocketAddr::new(secure_args.public_ip, server_config.port)];
+        // Secure even if the token service fails, the server shuts down either way.
+        commands.insert_resource(ServerSecurity::Secure {
+            private_key,
+            public_addresses: public_addresses.clone(),
+        });
+
+        // Handing out new ids to everyone would let them past their bans, and saving would lose the old ones.
+        let token_service = match TokenService::new(server_config.protocol_id, private_key, public_addresses).with_players_file(&secure_args.players) {
+            Ok(token_service) => token_service,
+            Err(e) => {
+                error!("Failed to load player ids {}: {}", secure_args.players, e);
+                app_exit_events.send(AppExit::error());
+                return;
+            }
+        };
+        if let Err(e) = token_service.spawn(secure_args.auth_port) {
+            error!("Failed to start token service on port {}: {}", secure_args.auth_port, e);
+            app_exit_events.send(AppExit::error());
+        }
+    }
+}
@@ -1,8 +1,38 @@
+use std::{collections::VecDeque, f32::consts::{PI, TAU}};
+
 use bevy::prelude::*;
-use bevy_renet::renet::{DefaultChannel, RenetServer, ServerEvent};
+use bevy_renet::{netcode::NetcodeServerTransport, renet::{DefaultChannel, RenetServer, ServerEvent}};
 use boxman_shared::{
-    character::{alter_character_velocity, PlayerInput}, data::CharacterConfig, moveable_sim::MoveableSimulation, prelude::{Character, CharacterDespawnEvent, CharacterSpawnEvent, ServerToClientMessage}
+    character::{alter_character_velocity, CharacterJump, PlayerInput}, protocol::{content_hash, packed::MAX_INPUTS_PER_PACKET, PlayerUserData, MAX_TICK_RATE_ADJUSTMENT}, roster::{PlayerInfo, PlayerProfile, DEFAULT_PLAYER_COLOR}, data::{CharacterConfig, ServerConfig}, health::Health, level::{CurrentLevel, SpawnPoint}, moveable_sim::MoveableSimulation, weapons::{WeaponConfig, WeaponState}, prelude::{Character, CharacterDespawnEvent, CharacterSpawnEvent, ServerToClientMessage}
 };
+use rand::seq::IndexedRandom;
+
+use crate::{auth::validate_name, bans::BanList, chat::ChatRateLimit, gameplay_config_message, handshake::{send_handshake, AwaitingHandshake, HandshakeCompletedEvent, JoinedClients}, snapshot::Relevancy, ServerSecurity};
+
+/// How many consumed input ids we remember per player, one second worth.
+const CONSUMED_INPUT_HISTORY: usize = 64;
+
+/// Input packets a client may send per fixed tick on average, on top of speeding up its ticks.
+/// Clients send about one per tick, a little more while they're catching up.
+const INPUT_PACKETS_PER_TICK: f32 = 1.5;
+
+/// How many input packets a client may send at once, e.g. after a hitch.
+const INPUT_PACKET_BURST: f32 = 32.0;
+
+/// How far past the newest consumed input an input id may be.
+const MAX_INPUT_ID_LEAD: u32 = 256;
+
+/// How much longer than a unit vector `wish_dir` may be before it counts as a violation, rather than rounding.
+const WISH_DIR_TOLERANCE: f32 = 0.01;
+
+/// A client gets kicked once its violations go above this.
+const MAX_VIOLATIONS: f32 = 10.0;
+
+/// How many violations are forgiven per second.
+const VIOLATION_DECAY_PER_SECOND: f32 = 0.5;
+
+/// Seconds between telling a player they're kicked and disconnecting them, so the message has a chance to arrive.
+const KICK_DISCONNECT_DELAY: f32 = 0.5;
 
 #[derive(Component)]
 pub struct Player {
@@ -11,6 +41,28 @@ pub struct Player {
     pub last_acked_snapshot_id: Option<u64>,
     pub newest_processed_input_id: Option<u32>,
     pub last_input: Option<PlayerInput>,
+
+    /// Ids of the inputs we've consumed recently, oldest first.
+    /// Clients send every input several times, this is how we only act on it once.
+    pub consumed_input_ids: VecDeque<u32>,
+
+    pub color: Color,
+
+    /// Players on the same team see each other's team chat. None until teams are picked.
+    pub team: Option<u32>,
+
+    /// Muted players can't chat, see `chat::MutedPlayersFilter`.
+    pub muted: bool,
+}
+
+impl Player {
+    pub fn info(&self) -> PlayerInfo {
+        PlayerInfo {
+            id: self.client_id,
+            name: self.name.clone(),
+            color: self.color,
+        }
+    }
 }
 
 #[derive(Component)]
@@ -18,17 +70,128 @@ pub struct PlayerInputQueue {
     pub inputs: Vec<PlayerInput>,
 }
 
+/// Why an input from a client was rejected or had to be fixed up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputViolation {
+    /// A NaN or infinite number.
+    NonFinite,
+
+    /// `wish_dir` longer than a unit vector.
+    WishDirTooLong,
+
+    /// An input id way past anything the client could have made by now.
+    InputIdTooFar,
+
+    /// The timestamp went backwards compared to an older input.
+    TimestampWentBackwards,
+
+    /// More inputs in a single packet than a client ever sends.
+    TooManyInputs,
+
+    /// More input packets than a client ever sends.
+    Flooding,
+}
+
+/// How well a player's inputs have behaved.
+#[derive(Component)]
+pub struct InputValidation {
+    /// Input packets the player may still send right now, refilled over time.
+    pub packet_allowance: f32,
+
+    /// Goes up with every violation and decays over time, the player is kicked when it gets too high.
+    pub violations: f32,
+
+    pub last_violation: Option<InputViolation>,
+}
+
+impl Default for InputValidation {
+    fn default() -> Self {
+        Self {
+            packet_allowance: INPUT_PACKET_BURST,
+            violations: 0.0,
+            last_violation: None,
+        }
+    }
+}
+
+impl InputValidation {
+    fn refill(&mut self, delta_secs: f32, max_packets_per_second: f32) {
+        self.packet_allowance = (self.packet_allowance + delta_secs * max_packets_per_second).min(INPUT_PACKET_BURST);
+        self.violations = (self.violations - delta_secs * VIOLATION_DECAY_PER_SECOND).max(0.0);
+    }
+
+    /// Returns false if the player is sending too many packets.
+    fn take_packet(&mut self) -> bool {
+        if self.packet_allowance < 1.0 {
+            return false;
+        }
+
+        self.packet_allowance -= 1.0;
+        true
+    }
+
+    fn record(&mut self, client_id: u64, violation: InputViolation) {
+        warn!("Player {} sent an invalid input: {:?}", client_id, violation);
+        self.violations += 1.0;
+        self.last_violation = Some(violation);
+    }
+}
+
+/// A client's `SetProfile` message.
+#[derive(Event)]
+pub struct PlayerProfileEvent(pub u64, pub PlayerProfile);
+
+/// A packet of inputs from a client, oldest first.
 #[derive(Event)]
-pub struct PlayerInputEvent(pub u64, pub PlayerInput);
+pub struct PlayerInputEvent(pub u64, pub Vec<PlayerInput>);
+
+/// Kicks a player, they're told the reason before being disconnected.
+#[derive(Event, Debug, Clone)]
+pub struct KickPlayerEvent {
+    pub client_id: u64,
+    pub reason: String,
+}
+
+/// The client was told why they're being disconnected, and is when the timer runs out.
+/// Kicked players get this on their own entity, refused connections get an entity of their own.
+#[derive(Component)]
+pub struct PendingDisconnect {
+    pub client_id: u64,
+    pub timer: Timer,
+}
+
+impl PendingDisconnect {
+    pub fn new(client_id: u64) -> Self {
+        Self {
+            client_id,
+            timer: Timer::from_seconds(KICK_DISCONNECT_DELAY, TimerMode::Once),
+        }
+    }
+}
+
+/// Sent when a player's input fired their weapon, the hit is resolved in the weapons module.
+#[derive(Event)]
+pub struct PlayerFireEvent {
+    pub client_id: u64,
+    pub origin: Vec3,
+    pub input: PlayerInput,
+}
 
 pub struct PlayerPlugin;
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<PlayerInputEvent>();
+        app.add_event::<PlayerFireEvent>();
+        app.add_event::<KickPlayerEvent>();
+        app.add_event::<PlayerProfileEvent>();
         app.add_systems(PostUpdate, (
             connection_event_receiver_system, 
+            join_system,
             player_input_receiver_system,
+            player_profile_system,
+            kick_system,
+            pending_disconnect_system,
         ));
         app.add_systems(FixedPreUpdate, (
             player_input_consumer_system.run_if(resource_exists::<CharacterConfig>), 
@@ -40,145 +203,472 @@ fn connection_event_receiver_system(
     players: Query<(Entity, &Player)>,
     mut commands: Commands,
     mut renet_server: ResMut<RenetServer>,
+    mut joined_clients: ResMut<JoinedClients>,
     mut server_events: EventReader<ServerEvent>,
-    mut character_spawn_events: EventWriter<CharacterSpawnEvent>,
     mut character_despawn_events: EventWriter<CharacterDespawnEvent>,
-    characters: Query<(Entity, &Transform, &Character)>,
+    current_level: Option<Res<CurrentLevel>>,
+    transport: Option<Res<NetcodeServerTransport>>,
+    ban_list: Res<BanList>,
 ) {
+    // Players that connect this frame aren't in the query yet.
+    let mut connected_this_frame: Vec<String> = Vec::new();
+
     for event in server_events.read() {
         match event {
             ServerEvent::ClientConnected { client_id } => {
-                info!("Player {} connected", client_id);
+                // First thing, so even a client that can't read anything else finds out why it's turned away.
+                send_handshake(&mut renet_server, *client_id, current_level.as_deref());
+
+                let address = transport.as_ref()
+                    .and_then(|transport| transport.client_addr(*client_id))
+                    .map(|address| address.ip());
+                if let Err(reason) = ban_list.check(*client_id, address) {
+                    info!("Refused player {} ({:?}): {}", client_id, address, reason);
+                    send_kicked(&mut renet_server, *client_id, &reason);
+                    commands.spawn(PendingDisconnect::new(*client_id));
+                    continue;
+                }
+
+                // With secure auth the name comes from their connect token, otherwise it's whatever they sent.
+                let name = transport.as_ref()
+                    .and_then(|transport| transport.user_data(*client_id))
+                    .and_then(|user_data| PlayerUserData::from_bytes(&user_data))
+                    .and_then(|user_data| validate_name(&user_data.name).map(str::to_string))
+                    .unwrap_or_else(|| format!("Player {}", client_id));
+
+                let taken_names: Vec<String> = players.iter()
+                    .map(|(_, player)| player.name.clone())
+                    .chain(connected_this_frame.iter().cloned())
+                    .collect();
+                let name = unique_name(&name, &taken_names);
+
+                info!("Player {} ({}) connected", client_id, name);
+
+                // Nothing else is sent and nothing is spawned until their handshake checks out, see `join_system`.
                 commands.spawn((
                     Player {
                         client_id: *client_id,
-                        name: format!("Player {}", client_id),
+                        name: name.clone(),
                         last_acked_snapshot_id: None,
                         newest_processed_input_id: None,
                         last_input: None,
+                        consumed_input_ids: VecDeque::new(),
+                        color: DEFAULT_PLAYER_COLOR,
+                        team: None,
+                        muted: false,
                     },
                     PlayerInputQueue {
                         inputs: Vec::new(),
-                    }
+                    },
+                    InputValidation::default(),
+                    ChatRateLimit::default(),
+                    Relevancy::default(),
+                    AwaitingHandshake::default(),
                 ));
+                connected_this_frame.push(name);
+            }
+            ServerEvent::ClientDisconnected { client_id, reason } => {
+                let joined = joined_clients.remove(*client_id);
+
+                for (entity, player) in players.iter() {
+                    if player.client_id == *client_id {
+                        info!("Player {} ({}) disconnected: {:?}", client_id, player.name, reason);
+                        commands.entity(entity).despawn_recursive();
 
-                // get every character and tell the new client to spawn it
-                for (_, transform, character_simulation) in characters.iter() {
-                    let message = ServerToClientMessage::SpawnCharacter(CharacterSpawnEvent {
-                        client_id: character_simulation.client_id,
-                        position: transform.translation,
-                        yaw: 0.0,
-                    });
-
-                    match bincode::serialize(&message) {
-                        Ok(serialized) => {
-                            renet_server.send_message(*client_id, DefaultChannel::ReliableOrdered, serialized);
+                        // Nobody heard about them if they never joined.
+                        if !joined {
+                            continue;
                         }
-                        Err(e) => {
-                            error!("Error serializing message: {}", e);
+
+                        match (ServerToClientMessage::PlayerLeft { id: *client_id }).to_bytes() {
+                            Ok(serialized) => {
+                                joined_clients.broadcast(&mut renet_server, DefaultChannel::ReliableOrdered, serialized);
+                            }
+                            Err(e) => {
+                                error!("Error serializing message: {}", e);
+                            }
                         }
+        
+                        // despawn the character, it drops out of everyone's snapshots with it
+                        character_despawn_events.send(CharacterDespawnEvent {
+                            client_id: *client_id,
+                        });
                     }
                 }
 
-                // spawn their character
-                let character_spawn_event = CharacterSpawnEvent {
-                    client_id: *client_id,
-                    position: Vec3::new(0.0, 2.0, 0.0),
-                    yaw: 0.0,
-                };
-                character_spawn_events.send(character_spawn_event.clone());
+            }
+        }
+    }
+}
 
-                // tell every client about the new character
-                for client_id in renet_server.clients_id() {
-                    let message = ServerToClientMessage::SpawnCharacter(character_spawn_event.clone());
+/// Brings players into the game once their handshake checks out: they're sent the configs, the level
+/// and the roster, everyone else is told about them, and their character is spawned.
+fn join_system(
+    players: Query<&Player>,
+    mut renet_server: ResMut<RenetServer>,
+    joined_clients: Res<JoinedClients>,
+    mut handshake_completed_events: EventReader<HandshakeCompletedEvent>,
+    mut character_spawn_events: EventWriter<CharacterSpawnEvent>,
+    current_level: Option<Res<CurrentLevel>>,
+    character_config: Option<Res<CharacterConfig>>,
+    weapon_config: Option<Res<WeaponConfig>>,
+    server_config: Option<Res<ServerConfig>>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    // Joined in the handshake already, but not brought in yet.
+    let mut joining: Vec<u64> = handshake_completed_events.read().map(|event| event.0).collect();
 
-                    match bincode::serialize(&message) {
-                        Ok(serialized) => {
-                            renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
-                        }
-                        Err(e) => {
-                            error!("Error serializing message: {}", e);
-                        }
-                    }
+    while !joining.is_empty() {
+        let client_id = joining.remove(0);
+        let Some(player) = players.iter().find(|player| player.client_id == client_id) else {
+            continue;
+        };
+
+        // they predict with our configs, so those go before their character
+        if let (Some(character_config), Some(weapon_config)) = (&character_config, &weapon_config) {
+            match gameplay_config_message(character_config, weapon_config, server_config.as_deref(), &fixed_time).to_bytes() {
+                Ok(serialized) => {
+                    renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+                }
+                Err(e) => {
+                    error!("Error serializing message: {}", e);
                 }
             }
-            ServerEvent::ClientDisconnected { client_id, reason } => {
-                for (entity, player) in players.iter() {
-                    if player.client_id == *client_id {
-                        info!("Player {} ({}) disconnected: {:?}", client_id, player.name, reason);
-                        commands.entity(entity).despawn_recursive();
-        
-                        // despawn the character
-                        let character_despawn_event = CharacterDespawnEvent {
-                            client_id: *client_id,
-                        };
-                        character_despawn_events.send(character_despawn_event.clone());
-        
-                        // tell everyone to despawn the character
-                        for client_id in renet_server.clients_id() {
-                            let message = ServerToClientMessage::DespawnCharacter(character_despawn_event.clone());
-        
-                            match bincode::serialize(&message) {
-                                Ok(serialized) => {
-                                    renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
-                                }
-                                Err(e) => {
-                                    error!("Error serializing message: {}", e);
-                                }
-                            }
-                        }
-                    }
+        }
+
+        // tell them which level to load before anything is spawned in it
+        if let Some(current_level) = &current_level {
+            let message = ServerToClientMessage::LoadLevel {
+                name: current_level.name.clone(),
+                hash: content_hash(&current_level.level),
+            };
+
+            match message.to_bytes() {
+                Ok(serialized) => {
+                    renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+                }
+                Err(e) => {
+                    error!("Error serializing message: {}", e);
                 }
+            }
+        }
 
+        // tell the new client who's already here
+        let roster = players.iter()
+            .filter(|other| other.client_id != client_id && joined_clients.contains(other.client_id) && !joining.contains(&other.client_id))
+            .map(|other| other.info());
+        for info in roster {
+            match ServerToClientMessage::PlayerJoined(info).to_bytes() {
+                Ok(serialized) => {
+                    renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+                }
+                Err(e) => {
+                    error!("Error serializing message: {}", e);
+                }
+            }
+        }
+
+        // and tell everyone, including them, about the new player. The ones still to go get them in their roster.
+        match ServerToClientMessage::PlayerJoined(player.info()).to_bytes() {
+            Ok(serialized) => {
+                for other_id in joined_clients.iter().filter(|other_id| !joining.contains(other_id)) {
+                    renet_server.send_message(other_id, DefaultChannel::ReliableOrdered, serialized.clone());
+                }
+            }
+            Err(e) => {
+                error!("Error serializing message: {}", e);
+            }
+        }
+
+        // spawn their character
+        let spawn_point = pick_spawn_point(current_level.as_deref());
+        let character_spawn_event = CharacterSpawnEvent {
+            client_id,
+            position: spawn_point.position,
+            yaw: spawn_point.yaw,
+        };
+        character_spawn_events.send(character_spawn_event.clone());
+
+        // tell them about their character, everyone else finds out from their snapshots
+        match ServerToClientMessage::SpawnCharacter(character_spawn_event).to_bytes() {
+            Ok(serialized) => {
+                renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+            }
+            Err(e) => {
+                error!("Error serializing message: {}", e);
             }
         }
     }
 }
 
+/// Makes `name` unique by numbering it, e.g. "Brian (2)". Names differing only in case count as the same.
+pub fn unique_name(name: &str, taken: &[String]) -> String {
+    let is_taken = |candidate: &str| taken.iter().any(|taken| taken.eq_ignore_ascii_case(candidate));
+    if !is_taken(name) {
+        return name.to_string();
+    }
+
+    (2..)
+        .map(|number| format!("{} ({})", name, number))
+        .find(|candidate| !is_taken(candidate))
+        .unwrap_or_else(|| name.to_string())
+}
+
+/// Cosmetics can be anything, as long as it's a valid opaque color.
+fn sanitize_color(color: Color) -> Color {
+    let color = color.to_srgba();
+    let channel = |value: f32| if value.is_finite() { value.clamp(0.0, 1.0) } else { 0.0 };
+    Color::srgb(channel(color.red), channel(color.green), channel(color.blue))
+}
+
+/// Applies profile changes from clients, and tells everyone when a player has changed.
+fn player_profile_system(
+    server_security: Option<Res<ServerSecurity>>,
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+    mut player_profile_events: EventReader<PlayerProfileEvent>,
+    mut players: Query<&mut Player>,
+) {
+    // Secure connect tokens decide the name, only cosmetics can change.
+    let name_locked = matches!(server_security.as_deref(), Some(ServerSecurity::Secure { .. }));
+
+    for PlayerProfileEvent(client_id, profile) in player_profile_events.read() {
+        let taken_names: Vec<String> = players.iter()
+            .filter(|player| player.client_id != *client_id)
+            .map(|player| player.name.clone())
+            .collect();
+
+        let Some(mut player) = players.iter_mut().find(|player| player.client_id == *client_id) else {
+            continue;
+        };
+
+        let mut changed = false;
+
+        if let (false, Some(name)) = (name_locked, validate_name(&profile.name)) {
+            let name = unique_name(name, &taken_names);
+            if name != player.name {
+                info!("Player {} renamed from {} to {}", client_id, player.name, name);
+                player.name = name;
+                changed = true;
+            }
+        }
+
+        let color = sanitize_color(profile.color);
+        if color != player.color {
+            player.color = color;
+            changed = true;
+        }
+
+        // Players who haven't joined yet are announced with their profile when they do.
+        if changed && joined_clients.contains(*client_id) {
+            match ServerToClientMessage::PlayerUpdated(player.info()).to_bytes() {
+                Ok(serialized) => {
+                    joined_clients.broadcast(&mut renet_server, DefaultChannel::ReliableOrdered, serialized);
+                }
+                Err(e) => {
+                    error!("Error serializing message: {}", e);
+                }
+            }
+        }
+    }
+}
+
+/// Picks a random spawn point from the level, or the origin if the level doesn't define any.
+pub fn pick_spawn_point(current_level: Option<&CurrentLevel>) -> SpawnPoint {
+    current_level
+        .and_then(|current_level| current_level.level.spawn_points.choose(&mut rand::rng()))
+        .cloned()
+        .unwrap_or(SpawnPoint {
+            position: Vec3::new(0.0, 2.0, 0.0),
+            yaw: 0.0,
+        })
+}
+
+/// Checks an input before it's queued. Problems that can be fixed are fixed in place and still reported,
+/// inputs that can't be trusted at all come back as an error and should be dropped.
+/// Jumping isn't checked here, `alter_character_velocity` only jumps when grounded (or in coyote time) anyway.
+pub fn validate_input(input: &mut PlayerInput, player: &Player) -> Result<Option<InputViolation>, InputViolation> {
+    if !input.yaw.is_finite() || !input.wish_dir.is_finite() || !input.timestamp.is_finite() {
+        return Err(InputViolation::NonFinite);
+    }
+
+    if player.newest_processed_input_id.is_some_and(|newest_id| input.id > newest_id.saturating_add(MAX_INPUT_ID_LEAD)) {
+        return Err(InputViolation::InputIdTooFar);
+    }
+
+    if let Some(last_input) = &player.last_input {
+        if input.id > last_input.id && input.timestamp < last_input.timestamp {
+            return Err(InputViolation::TimestampWentBackwards);
+        }
+    }
+
+    input.yaw = (input.yaw + PI).rem_euclid(TAU) - PI;
+
+    let length = input.wish_dir.length();
+    if length > 1.0 {
+        input.wish_dir /= length;
+        if length > 1.0 + WISH_DIR_TOLERANCE {
+            return Ok(Some(InputViolation::WishDirTooLong));
+        }
+    }
+
+    Ok(None)
+}
+
 fn player_input_receiver_system(
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
     mut player_input_events: EventReader<PlayerInputEvent>,
-    mut players: Query<(&mut PlayerInputQueue, &mut Player)>,
+    mut kick_events: EventWriter<KickPlayerEvent>,
+    mut players: Query<(&mut PlayerInputQueue, &mut Player, &mut InputValidation, Has<PendingDisconnect>)>,
 ) {
+    // Clients send an input packet per tick, so the limit follows the tick rate.
+    let tick_rate = 1.0 / fixed_time.timestep().as_secs_f32();
+    let max_packets_per_second = tick_rate * (INPUT_PACKETS_PER_TICK + MAX_TICK_RATE_ADJUSTMENT);
+    for (_, _, mut validation, _) in players.iter_mut() {
+        validation.refill(time.delta_secs(), max_packets_per_second);
+    }
+
     for event in player_input_events.read() {        
         let matching_player = players.iter_mut()
-            .find(|(_, player)| player.client_id == event.0);
-
-        if let Some((mut input_queue, mut player)) = matching_player {
-            // Don't process inputs older than what we've already processed
-            // @todo-brian: What we really should be doing here is 
-            // comparing to a small history of already consumed inputs.
-            // if let Some(last_processed_id) = player.last_processed_input_id {
-            //     if event.1.id <= last_processed_id {
-            //         continue;
-            //     }
-            // }
-
-            input_queue.inputs.push(event.1.clone());
-            input_queue.inputs.sort_by_key(|input| input.id);
-            input_queue.inputs.reverse();
-
-            if let (Some(last_acked_snapshot_id), Some(pending_ack_snapshot_id)) = (player.last_acked_snapshot_id, event.1.snapshot_id) {
-                if pending_ack_snapshot_id > last_acked_snapshot_id {
-                    player.last_acked_snapshot_id = Some(pending_ack_snapshot_id);
+            .find(|(_, player, _, _)| player.client_id == event.0);
+
+        let Some((mut input_queue, mut player, mut validation, pending_disconnect)) = matching_player else {
+            warn!("No player found for client {}", event.0);
+            continue;
+        };
+
+        // They're on their way out, nothing they send matters anymore.
+        if pending_disconnect {
+            continue;
+        }
+
+        if !validation.take_packet() {
+            validation.record(event.0, InputViolation::Flooding);
+        } else if event.1.len() > MAX_INPUTS_PER_PACKET {
+            validation.record(event.0, InputViolation::TooManyInputs);
+        } else {
+            for input in event.1.iter() {
+                let mut input = input.clone();
+                match validate_input(&mut input, &player) {
+                    Ok(None) => {}
+                    Ok(Some(violation)) => {
+                        validation.record(event.0, violation);
+                    }
+                    Err(violation) => {
+                        validation.record(event.0, violation);
+                        continue;
+                    }
+                }
+
+                // Inputs arrive several times over, only queue the ones we haven't consumed or queued already.
+                // Anything at or before the newest consumed one is too late, playing it now would run it out of order.
+                // It still acks the snapshot below.
+                let too_old = player.newest_processed_input_id.is_some_and(|newest_id| input.id <= newest_id)
+                    || player.consumed_input_ids.front().is_some_and(|oldest_id| input.id < *oldest_id);
+                let already_seen = player.consumed_input_ids.contains(&input.id)
+                    || input_queue.inputs.iter().any(|queued| queued.id == input.id);
+
+                if !too_old && !already_seen {
+                    input_queue.inputs.push(input.clone());
+                    input_queue.inputs.sort_by_key(|input| input.id);
+                }
+
+                if let (Some(last_acked_snapshot_id), Some(pending_ack_snapshot_id)) = (player.last_acked_snapshot_id, input.snapshot_id) {
+                    if pending_ack_snapshot_id > last_acked_snapshot_id {
+                        player.last_acked_snapshot_id = Some(pending_ack_snapshot_id);
+                    }
+                } else {
+                    player.last_acked_snapshot_id = input.snapshot_id;
                 }
-            } else {
-                player.last_acked_snapshot_id = event.1.snapshot_id;
             }
-        } else {
-            warn!("No player found for client {}", event.0);
+        }
+
+        if validation.violations > MAX_VIOLATIONS {
+            kick_events.send(KickPlayerEvent {
+                client_id: player.client_id,
+                reason: format!("Too many invalid inputs (last: {:?})", validation.last_violation),
+            });
+        }
+    }
+}
+
+/// Tells kicked players why, then gives the message a moment to arrive before disconnecting them.
+fn kick_system(
+    mut commands: Commands,
+    mut renet_server: ResMut<RenetServer>,
+    mut kick_events: EventReader<KickPlayerEvent>,
+    players: Query<(Entity, &Player), Without<PendingDisconnect>>,
+) {
+    let mut kicked = Vec::new();
+    for event in kick_events.read() {
+        let Some((entity, player)) = players.iter().find(|(_, player)| player.client_id == event.client_id) else {
+            continue;
+        };
+
+        // PendingDisconnect isn't inserted until later, so catch players kicked twice this frame.
+        if kicked.contains(&entity) {
+            continue;
+        }
+        kicked.push(entity);
+
+        info!("Kicking player {} ({}): {}", player.client_id, player.name, event.reason);
+        send_kicked(&mut renet_server, player.client_id, &event.reason);
+        commands.entity(entity).insert(PendingDisconnect::new(player.client_id));
+    }
+}
+
+fn send_kicked(renet_server: &mut RenetServer, client_id: u64, reason: &str) {
+    let message = ServerToClientMessage::Kicked {
+        reason: reason.to_string(),
+    };
+
+    match message.to_bytes() {
+        Ok(serialized) => {
+            renet_server.send_message(client_id, DefaultChannel::ReliableOrdered, serialized);
+        }
+        Err(e) => {
+            error!("Error serializing message: {}", e);
+        }
+    }
+}
+
+fn pending_disconnect_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut renet_server: ResMut<RenetServer>,
+    mut pending_disconnects: Query<(Entity, &mut PendingDisconnect, Has<Player>)>,
+) {
+    for (entity, mut pending_disconnect, is_player) in pending_disconnects.iter_mut() {
+        if pending_disconnect.timer.tick(time.delta()).just_finished() {
+            renet_server.disconnect(pending_disconnect.client_id);
+
+            // Players are despawned once they've disconnected, refused connections have nothing else to clean up.
+            if !is_player {
+                commands.entity(entity).despawn();
+            }
         }
     }
 }
 
 fn player_input_consumer_system(
     character_config: Res<CharacterConfig>,
+    weapon_config: Option<Res<WeaponConfig>>,
     mut players: Query<(&mut PlayerInputQueue, &mut Player)>,
-    mut characters: Query<(&mut MoveableSimulation, &mut Transform, &Character)>,
+    mut characters: Query<(&mut MoveableSimulation, &mut CharacterJump, &mut WeaponState, &mut Transform, &Character, &Health)>,
+    mut player_fire_events: EventWriter<PlayerFireEvent>,
     fixed_time: Res<Time<Fixed>>,
 ) {
     for (mut input_queue, mut player) in players.iter_mut() {
         let input = if !input_queue.inputs.is_empty() {
+            // oldest first, the queue is sorted by id
             let input = input_queue.inputs.remove(0);
+
+            player.consumed_input_ids.push_back(input.id);
+            if player.consumed_input_ids.len() > CONSUMED_INPUT_HISTORY {
+                player.consumed_input_ids.pop_front();
+            }
+
             // Only update last_input if this is a newer input
             if let Some(last_input) = &player.last_input {
                 if input.id > last_input.id {
@@ -194,32 +684,41 @@ fn player_input_consumer_system(
             continue;
         };
 
-        for (mut simulation, mut transform, controller) in characters.iter_mut() {
+        for (mut simulation, mut jump, mut weapon_state, mut transform, controller, health) in characters.iter_mut() {
             if controller.client_id == player.client_id {
-                alter_character_velocity(
-                    &mut simulation,
-                    &input,
-                    fixed_time.delta_secs(),
-                    character_config.speed,
-                    character_config.acceleration,
-                    character_config.friction,
-                );
-
-                if let Some(last_id) = player.newest_processed_input_id {
-                    if input.id > last_id {
-                        player.newest_processed_input_id = Some(input.id);
-
-                        // use the rotation only from the newest input
-                        let (_, pitch, roll) = transform.rotation.to_euler(EulerRot::YXZ);
-                        transform.rotation = Quat::from_euler(EulerRot::YXZ, input.yaw, pitch, roll);
-                    }
-                } else {
+                // Dead characters still ack inputs, they just don't act on them.
+                let is_dead = health.is_dead();
+
+                if !is_dead {
+                    alter_character_velocity(
+                        &mut simulation,
+                        &mut jump,
+                        &input,
+                        fixed_time.delta_secs(),
+                        &character_config,
+                    );
+                }
+
+                let is_newest = player.newest_processed_input_id
+                    .is_none_or(|last_id| input.id > last_id);
+
+                if is_newest {
                     player.newest_processed_input_id = Some(input.id);
 
                     // use the rotation only from the newest input
                     let (_, pitch, roll) = transform.rotation.to_euler(EulerRot::YXZ);
                     transform.rotation = Quat::from_euler(EulerRot::YXZ, input.yaw, pitch, roll);
                 }
+
+                if let (Some(weapon_config), false) = (&weapon_config, is_dead) {
+                    if weapon_state.update(&input, weapon_config, fixed_time.delta_secs(), is_newest).is_some() {
+                        player_fire_events.send(PlayerFireEvent {
+                            client_id: player.client_id,
+                            origin: transform.translation,
+                            input: input.clone(),
+                        });
+                    }
+                }
             }
         }
     }
@@ -1,9 +1,28 @@
+use std::collections::HashMap;
+
+use avian3d::prelude::SpatialQuery;
 use bevy::prelude::*;
 use bevy_renet::renet::{DefaultChannel, RenetServer};
-use boxman_shared::{moveable_sim::MoveableSimulation, character::Character, snapshot::{CharacterSnapshot, Snapshot, SnapshotDiff}};
+use boxman_shared::{data::ServerConfig, moveable_sim::MoveableSimulation, character::Character, health::{Armor, Health}, replication::{EntitySnapshot, NetworkId, NetworkIdAllocator, Replicated, ReplicationRegistry}, protocol::packed::packed_character_diff_bits, snapshot::{CharacterSnapshot, Snapshot, SnapshotDiff}, weapons::trace_world};
 use boxman_shared::protocol::ServerToClientMessage;
 
-use crate::player::Player;
+use crate::{handshake::JoinedClients, player::Player};
+
+/// How much a client's snapshot rate goes up per adjustment while its connection keeps up.
+const SEND_RATE_INCREASE: f64 = 4.0;
+
+/// What a client's snapshot rate is multiplied by per adjustment when its connection is struggling.
+const SEND_RATE_DECREASE: f64 = 0.5;
+
+/// Seconds between snapshot rate adjustments, long enough for the connection stats to react to the last one.
+const SEND_RATE_ADJUST_INTERVAL: f64 = 1.0;
+
+/// Distance at which a character's priority grows half as fast as one right next to the client.
+const PRIORITY_FALLOFF_DISTANCE: f32 = 20.0;
+
+/// How far short of a character a line of sight trace may stop and still count, so geometry
+/// they're touching doesn't hide them.
+const LINE_OF_SIGHT_TOLERANCE: f32 = 0.5;
 
 #[derive(Resource)]
 pub struct SnapshotContainer {
@@ -11,6 +30,135 @@ pub struct SnapshotContainer {
     pub snapshots: Vec<Snapshot>,
 }
 
+/// How often a client is sent snapshots. It starts at the configured rate, backs off quickly
+/// when the client loses packets or goes over its bandwidth, and recovers slowly once it's fine again.
+#[derive(Component)]
+pub struct SnapshotSendRate {
+    /// Snapshots per second.
+    pub rate: f64,
+    pub since_last_send: f64,
+    pub since_adjust: f64,
+}
+
+/// How much a character matters to one client right now.
+#[derive(Debug, Clone, Default)]
+pub struct RelevancyEntry {
+    /// Grows every time the character is left out of a snapshot, the highest go first.
+    pub priority: f32,
+
+    /// When the client could last see the character, in seconds since startup.
+    pub last_seen: f64,
+}
+
+/// Which characters a client is sent. Characters out of range, or out of sight for a while,
+/// aren't in its snapshots at all, so the client despawns them. The rest share a byte budget
+/// per snapshot, and characters that don't fit keep their last sent state until they do.
+/// Other replicated entities are sent to everyone.
+#[derive(Component, Default)]
+pub struct Relevancy {
+    pub entries: HashMap<u64, RelevancyEntry>,
+
+    /// What this client was sent, oldest first. Its snapshots are encoded against the one it acked.
+    pub sent_snapshots: Vec<Snapshot>,
+}
+
+impl Relevancy {
+    /// Picks what goes in this client's cut of `snapshot` and encodes it against the newest one it acked.
+    pub fn encode(
+        &mut self,
+        client_id: u64,
+        snapshot: &Snapshot,
+        last_acked_snapshot_id: Option<u64>,
+        now: f64,
+        server_config: &ServerConfig,
+        spatial_query: &SpatialQuery,
+    ) -> SnapshotDiff {
+        let viewer = snapshot.character_snapshots.iter()
+            .find(|character| character.client_id == client_id)
+            .map(|character| character.translation());
+
+        // Work out who's relevant and how urgently they need an update.
+        let mut candidates: Vec<(&CharacterSnapshot, f32)> = Vec::new();
+        for character in snapshot.character_snapshots.iter() {
+            if character.client_id == client_id {
+                candidates.push((character, f32::INFINITY));
+                continue;
+            }
+
+            // Without a character of our own there's nowhere to look from, so everyone's relevant.
+            let distance = viewer.map_or(0.0, |viewer| viewer.distance(character.translation()));
+            if distance > server_config.relevance_distance {
+                continue;
+            }
+
+            let entry = self.entries.entry(character.client_id).or_insert_with(|| RelevancyEntry {
+                priority: 0.0,
+                last_seen: f64::NEG_INFINITY,
+            });
+
+            let visible = viewer.is_none_or(|viewer| {
+                distance <= server_config.always_relevant_distance
+                    || trace_world(spatial_query, viewer, character.translation() - viewer, distance) >= distance - LINE_OF_SIGHT_TOLERANCE
+            });
+            if visible {
+                entry.last_seen = now;
+            }
+
+            // Characters linger for a bit after going out of sight, so they don't pop in and out around corners.
+            if now - entry.last_seen > server_config.relevance_linger as f64 {
+                continue;
+            }
+
+            entry.priority += 1.0 / (1.0 + distance / PRIORITY_FALLOFF_DISTANCE);
+            candidates.push((character, entry.priority));
+        }
+
+        self.entries.retain(|id, _| candidates.iter().any(|(character, _)| character.client_id == *id));
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        let baseline = last_acked_snapshot_id
+            .and_then(|last_acked_snapshot_id| self.sent_snapshots.iter().find(|s| s.id == last_acked_snapshot_id));
+
+        // Fill the budget in priority order. Our own character always goes in.
+        let mut client_snapshot = Snapshot {
+            id: snapshot.id,
+            character_snapshots: Vec::new(),
+            entities: snapshot.entities.clone(),
+        };
+        let mut stale_client_ids = Vec::new();
+        let mut bits = 0;
+        for (character, priority) in candidates {
+            let baseline_character = baseline
+                .and_then(|baseline| baseline.character_snapshots.iter().find(|c| c.client_id == character.client_id));
+
+            let size = character.diff(baseline_character)
+                .map_or(0, |diff| packed_character_diff_bits(&diff));
+
+            if priority.is_infinite() || bits + size <= server_config.snapshot_byte_budget * 8 {
+                bits += size;
+                client_snapshot.character_snapshots.push(character.clone());
+                if let Some(entry) = self.entries.get_mut(&character.client_id) {
+                    entry.priority = 0.0;
+                }
+            } else if let Some(baseline_character) = baseline_character {
+                // Repeating what the client already has costs nothing, and keeps it from being despawned.
+                client_snapshot.character_snapshots.push(baseline_character.clone());
+                stale_client_ids.push(character.client_id);
+            }
+        }
+
+        let mut snapshot_diff = client_snapshot.diff(baseline);
+        snapshot_diff.stale_client_ids = stale_client_ids;
+
+        self.sent_snapshots.push(client_snapshot);
+        while self.sent_snapshots.len() > server_config.snapshot_history.max(1) {
+            self.sent_snapshots.remove(0);
+        }
+
+        snapshot_diff
+    }
+}
+
 pub struct SnapshotPlugin;
 
 impl Plugin for SnapshotPlugin {
@@ -21,7 +169,10 @@ impl Plugin for SnapshotPlugin {
         });
         app.add_systems(FixedPostUpdate, 
             (
+                assign_network_ids_system,
                 snapshot_system,
+                replicated_entities_snapshot_system,
+                send_rate_system,
                 send_snapshot_diff_system,
             )
             .chain()
@@ -30,74 +181,169 @@ impl Plugin for SnapshotPlugin {
 }
 
 fn snapshot_system(
+    server_config: Option<Res<ServerConfig>>,
     mut snapshot_container: ResMut<SnapshotContainer>,
-    characters: Query<(&Character, &Transform, &MoveableSimulation)>,
+    characters: Query<(&Character, &Transform, &MoveableSimulation, &Health, &Armor)>,
 ) {
     let id = snapshot_container.next_id;
     snapshot_container.snapshots.push(Snapshot {
         id,
         character_snapshots: {
             let mut c = Vec::new();
-            for (character, transform, moveable_simulation) in characters.iter() {
-                c.push(CharacterSnapshot {
-                    client_id: character.client_id,
-                    translation: transform.translation,
-                    velocity: moveable_simulation.velocity,
-                    yaw: transform.rotation.to_euler(EulerRot::YXZ).0,
-                    pitch: 0.0,
-                    grounded: moveable_simulation.grounded,
-                });
+            for (character, transform, moveable_simulation, health, armor) in characters.iter() {
+                c.push(CharacterSnapshot::new(
+                    character.client_id,
+                    transform.translation,
+                    moveable_simulation.velocity,
+                    transform.rotation.to_euler(EulerRot::YXZ).0,
+                    0.0,
+                    moveable_simulation.grounded,
+                    health.current,
+                    armor.current,
+                ));
             }
             c
         },
+        // Filled in by `replicated_entities_snapshot_system`.
+        entities: Vec::new(),
     });
     snapshot_container.next_id += 1;
 
-    // keep the last 64 snapshots (1 second of snapshots)
-    if snapshot_container.snapshots.len() > 64 {
+    let history = server_config.map_or(64, |server_config| server_config.snapshot_history.max(1));
+    while snapshot_container.snapshots.len() > history {
         snapshot_container.snapshots.remove(0);
     }
 }
 
+/// Gives newly replicated entities their network id.
+fn assign_network_ids_system(
+    mut commands: Commands,
+    mut network_id_allocator: ResMut<NetworkIdAllocator>,
+    new_entities: Query<Entity, (With<Replicated>, Without<NetworkId>)>,
+) {
+    for entity in new_entities.iter() {
+        commands.entity(entity).insert(network_id_allocator.allocate());
+    }
+}
+
+/// Adds every replicated entity's components to the snapshot that was just taken.
+fn replicated_entities_snapshot_system(world: &mut World) {
+    let mut replicated = world.query_filtered::<(Entity, &NetworkId), With<Replicated>>();
+    let mut entities: Vec<(Entity, NetworkId)> = replicated.iter(world).map(|(entity, id)| (entity, *id)).collect();
+    entities.sort_by_key(|(_, id)| *id);
+
+    let registry = world.resource::<ReplicationRegistry>();
+    let entity_snapshots: Vec<EntitySnapshot> = entities.into_iter()
+        .map(|(entity, id)| EntitySnapshot {
+            id,
+            components: registry.capture(world, entity),
+        })
+        .collect();
+
+    if let Some(snapshot) = world.resource_mut::<SnapshotContainer>().snapshots.last_mut() {
+        snapshot.entities = entity_snapshots;
+    }
+}
+
+/// Adapts every client's snapshot rate to how well its connection is keeping up.
+fn send_rate_system(
+    mut commands: Commands,
+    fixed_time: Res<Time<Fixed>>,
+    server_config: Option<Res<ServerConfig>>,
+    server: Res<RenetServer>,
+    new_players: Query<Entity, (With<Player>, Without<SnapshotSendRate>)>,
+    mut players: Query<(&Player, &mut SnapshotSendRate)>,
+) {
+    let (max_rate, min_rate) = server_config.as_ref()
+        .map_or((f64::INFINITY, 0.0), |server_config| (server_config.snapshot_send_rate, server_config.min_snapshot_send_rate.min(server_config.snapshot_send_rate)));
+
+    for entity in new_players.iter() {
+        commands.entity(entity).insert(SnapshotSendRate {
+            rate: max_rate,
+            since_last_send: 0.0,
+            since_adjust: 0.0,
+        });
+    }
+
+    let Some(server_config) = server_config else {
+        return;
+    };
+
+    for (player, mut send_rate) in players.iter_mut() {
+        send_rate.since_adjust += fixed_time.delta_secs_f64();
+        if send_rate.since_adjust < SEND_RATE_ADJUST_INTERVAL {
+            continue;
+        }
+        send_rate.since_adjust = 0.0;
+
+        let Ok(network_info) = server.network_info(player.client_id) else {
+            continue;
+        };
+
+        let struggling = network_info.packet_loss > server_config.snapshot_loss_threshold
+            || network_info.bytes_sent_per_second > server_config.max_client_bandwidth;
+        let rate = if struggling {
+            (send_rate.rate * SEND_RATE_DECREASE).max(min_rate)
+        } else {
+            (send_rate.rate + SEND_RATE_INCREASE).min(max_rate)
+        };
+
+        if rate != send_rate.rate {
+            debug!(
+                "Snapshot rate for {} is now {:.1} Hz (loss {:.1}%, {:.0} B/s)",
+                player.client_id, rate, network_info.packet_loss * 100.0, network_info.bytes_sent_per_second
+            );
+            send_rate.rate = rate;
+        }
+    }
+}
+
+/// Sends every client whose send rate says it's due one the part of the newest snapshot that's relevant to it.
 fn send_snapshot_diff_system(
+    fixed_time: Res<Time<Fixed>>,
+    time: Res<Time>,
+    server_config: Option<Res<ServerConfig>>,
+    spatial_query: SpatialQuery,
     snapshot_container: Res<SnapshotContainer>,
+    joined_clients: Res<JoinedClients>,
     mut server: ResMut<RenetServer>,
-    players: Query<&Player>,
+    mut players: Query<(&Player, &mut SnapshotSendRate, &mut Relevancy)>,
 ) {
     if snapshot_container.snapshots.is_empty() {
         return;
     }
 
+    let default_server_config = ServerConfig::default();
+    let server_config = server_config.as_deref().unwrap_or(&default_server_config);
+
+    let delta = fixed_time.delta_secs_f64();
     let latest_snapshot = snapshot_container.snapshots.last().unwrap();
-    for client_id in server.clients_id() {
-        if let Some(player) = players.iter().find(|p| p.client_id == client_id) {
-            let last_acked_snapshot = if let Some(last_acked_snapshot_id) = player.last_acked_snapshot_id {
-                snapshot_container.snapshots.iter().find(|s| s.id == last_acked_snapshot_id)
-            } else {
-                None
-            };
-
-            if let Some(last_acked_snapshot) = last_acked_snapshot {
-                let mut snapshot_diff = latest_snapshot.diff(last_acked_snapshot);
-                snapshot_diff.acked_input_id = player.newest_processed_input_id;
-                match bincode::serialize(&ServerToClientMessage::SnapshotDiff(snapshot_diff)) {
-                    Ok(serialized) => {
-                        server.send_message(client_id, DefaultChannel::Unreliable, serialized);
-                    }
-                    Err(e) => {
-                        error!("Error serializing snapshot diff: {}", e);
-                    }
+    for client_id in joined_clients.iter() {
+        if let Some((player, mut send_rate, mut relevancy)) = players.iter_mut().find(|(p, _, _)| p.client_id == client_id) {
+            // Snapshots only go out on ticks, so rates at or above the tick rate send one every tick.
+            // Carrying the remainder over keeps the average rate right when it doesn't divide the tick rate.
+            let send_interval = 1.0 / send_rate.rate.max(f64::EPSILON);
+            send_rate.since_last_send += delta;
+            if send_rate.since_last_send + delta * 0.5 < send_interval {
+                continue;
+            }
+            send_rate.since_last_send = (send_rate.since_last_send - send_interval).clamp(0.0, send_interval);
+
+            let mut snapshot_diff = relevancy.encode(
+                client_id,
+                latest_snapshot,
+                player.last_acked_snapshot_id,
+                time.elapsed_secs_f64(),
+                server_config,
+                &spatial_query,
+            );
+            snapshot_diff.acked_input_id = player.newest_processed_input_id;
+            match ServerToClientMessage::SnapshotDiff(snapshot_diff).to_bytes() {
+                Ok(serialized) => {
+                    server.send_message(client_id, DefaultChannel::Unreliable, serialized);
                 }
-            } else {
-                let mut snapshot_diff = SnapshotDiff::from(latest_snapshot);
-                snapshot_diff.acked_input_id = player.newest_processed_input_id;
-                match bincode::serialize(&ServerToClientMessage::SnapshotDiff(snapshot_diff)) {
-                    Ok(serialized) => {
-                        server.send_message(client_id, DefaultChannel::Unreliable, serialized);
-                    }
-                    Err(e) => {
-                        error!("Error serializing snapshot: {}", e);
-                    }
+                Err(e) => {
+                    error!("Error serializing snapshot diff: {}", e);
                 }
             }
         }
@@ -0,0 +1,108 @@
+use avian3d::prelude::SpatialQuery;
+use bevy::prelude::*;
+use bevy_renet::renet::{DefaultChannel, RenetServer};
+use boxman_shared::{
+    character::Character,
+    health::Health,
+    moveable_sim::MoveableSimulation,
+    protocol::ServerToClientMessage,
+    weapons::{ray_cylinder_distance, shot_direction, trace_world, WeaponConfig, WeaponFiredEvent, WeaponHitEvent},
+};
+
+use crate::{handshake::JoinedClients, player::PlayerFireEvent, snapshot::SnapshotContainer};
+
+pub struct WeaponsPlugin;
+
+impl Plugin for WeaponsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate,
+            resolve_fire_system.run_if(resource_exists::<WeaponConfig>)
+        );
+    }
+}
+
+/// Resolves shots against where characters were on the shooter's screen when they fired.
+/// The client renders other characters interpolated behind the server, and tells us which
+/// snapshot that was in `PlayerInput::view_snapshot_id`, so we rewind everyone else to it before tracing.
+fn resolve_fire_system(
+    weapon_config: Res<WeaponConfig>,
+    snapshot_container: Res<SnapshotContainer>,
+    spatial_query: SpatialQuery,
+    joined_clients: Res<JoinedClients>,
+    mut renet_server: ResMut<RenetServer>,
+    mut player_fire_events: EventReader<PlayerFireEvent>,
+    mut weapon_fired_events: EventWriter<WeaponFiredEvent>,
+    mut weapon_hit_events: EventWriter<WeaponHitEvent>,
+    characters: Query<(&Character, &Transform, &MoveableSimulation, &Health)>,
+) {
+    for event in player_fire_events.read() {
+        let Some(weapon) = weapon_config.weapon(event.input.active_weapon) else {
+            continue;
+        };
+
+        let direction = shot_direction(event.input.yaw, weapon.spread, event.input.id);
+        let world_distance = trace_world(&spatial_query, event.origin, direction, weapon.range);
+
+        // Fall back to the present if the snapshot they saw is too old to still be around.
+        let rewound_snapshot = event.input.view_snapshot_id
+            .and_then(|snapshot_id| snapshot_container.snapshots.iter().find(|s| s.id == snapshot_id));
+
+        let mut closest_hit: Option<(u64, f32)> = None;
+        for (character, transform, simulation, health) in characters.iter() {
+            if character.client_id == event.client_id || health.is_dead() {
+                continue;
+            }
+
+            let position = rewound_snapshot
+                .and_then(|snapshot| snapshot.character_snapshots.iter().find(|c| c.client_id == character.client_id))
+                .map(|character_snapshot| character_snapshot.translation())
+                .unwrap_or(transform.translation);
+
+            if let Some(distance) = ray_cylinder_distance(
+                event.origin,
+                direction,
+                position,
+                simulation.params.collision_radius,
+                simulation.params.collision_height,
+            ) {
+                if distance < world_distance && closest_hit.is_none_or(|(_, closest)| distance < closest) {
+                    closest_hit = Some((character.client_id, distance));
+                }
+            }
+        }
+
+        let end_distance = closest_hit.map_or(world_distance, |(_, distance)| distance);
+        let end = event.origin + direction * end_distance;
+
+        if let Some((target_id, _)) = closest_hit {
+            weapon_hit_events.send(WeaponHitEvent {
+                shooter_id: event.client_id,
+                target_id,
+                damage: weapon.damage,
+                point: end,
+            });
+        }
+
+        let weapon_fired_event = WeaponFiredEvent {
+            client_id: event.client_id,
+            weapon: event.input.active_weapon,
+            origin: event.origin,
+            end,
+        };
+        weapon_fired_events.send(weapon_fired_event.clone());
+
+        // The shooter already predicted their own effects
+        match ServerToClientMessage::WeaponFired(weapon_fired_event).to_bytes() {
+            Ok(serialized) => {
+                for client_id in joined_clients.iter() {
+                    if client_id != event.client_id {
+                        renet_server.send_message(client_id, DefaultChannel::Unreliable, serialized.clone());
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Error serializing message: {}", e);
+            }
+        }
+    }
+}
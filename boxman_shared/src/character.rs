@@ -1,7 +1,7 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 
-use crate::moveable_sim::{MoveableParams, MoveableSimulation};
+use crate::{data::CharacterConfig, health::{Armor, Health}, moveable_sim::{MoveableParams, MoveableSimulation}, weapons::WeaponState};
 
 pub struct CharacterPlugin;
 
@@ -18,14 +18,19 @@ impl Plugin for CharacterPlugin {
 
 fn spawn_character_system(
     mut commands: Commands,
+    character_config: Option<Res<CharacterConfig>>,
     mut character_spawn_events: EventReader<CharacterSpawnEvent>,
 ) {
+    let default_character_config = CharacterConfig::default();
+    let character_config = character_config.as_deref().unwrap_or(&default_character_config);
+
     for event in character_spawn_events.read() {
+        let rotation = Quat::from_rotation_y(event.yaw);
         commands.spawn((
             MoveableSimulation {
                 velocity: Vec3::ZERO,
                 last_translation: event.position,
-                last_rotation: Quat::IDENTITY,
+                last_rotation: rotation,
                 is_visually_correcting: false,
                 grounded: false,
                 params: MoveableParams {
@@ -38,7 +43,18 @@ fn spawn_character_system(
             Character {
                 client_id: event.client_id,
             },
-            Transform::from_translation(event.position),
+            CharacterJump::default(),
+            WeaponState::default(),
+            Health {
+                current: character_config.max_health,
+                max: character_config.max_health,
+            },
+            Armor {
+                current: character_config.spawn_armor,
+                max: character_config.max_armor,
+            },
+            Transform::from_translation(event.position)
+                .with_rotation(rotation),
         ));
     }
 }
@@ -97,6 +113,10 @@ pub struct CharacterVisuals;
 pub struct PlayerInput {
     pub id: u32,
     pub snapshot_id: Option<u64>,
+
+    /// The server snapshot remote characters were rendered at, used for lag compensation.
+    pub view_snapshot_id: Option<u64>,
+
     pub yaw: f32,
     pub wish_dir: Vec2,
     pub wish_jump: bool,
@@ -117,6 +137,23 @@ pub struct PlayerInput {
 
     #[serde(skip)]
     pub post_move_grounded: bool,
+
+    #[serde(skip)]
+    pub post_move_jump: CharacterJump,
+}
+
+/// Jump bookkeeping for a character. This is simulation state, so it is rolled back
+/// and replayed together with the [`MoveableSimulation`] during reconciliation.
+#[derive(Component, Debug, Clone, Copy, Default, PartialEq)]
+pub struct CharacterJump {
+    /// Time left in which a jump is still allowed after leaving the ground.
+    pub coyote_timer: f32,
+
+    /// Time left in which a jump pressed in the air will trigger once grounded.
+    pub buffer_timer: f32,
+
+    /// Whether jump was held on the previous input, jumps only start on a fresh press.
+    pub was_held: bool,
 }
 
 impl MoveableSimulation {
@@ -162,27 +199,65 @@ impl MoveableSimulation {
 
 pub fn alter_character_velocity(
     simulation: &mut MoveableSimulation,
+    jump: &mut CharacterJump,
     input: &PlayerInput,
     delta_secs: f32,
-    speed: f32,
-    acceleration: f32,
-    friction: f32,
+    config: &CharacterConfig,
 ) {
     let rotation = Quat::from_rotation_y(input.yaw);
     let wish_dir = (rotation * Vec3::new(input.wish_dir.x, 0.0, input.wish_dir.y)).normalize_or_zero();
-    
-    simulation.velocity = MoveableSimulation::apply_friction(
-        simulation.velocity,
-        simulation.velocity.length(),
-        friction * delta_secs,
-        delta_secs
-    );
+    let grounded = simulation.grounded;
+
+    // Friction only applies on the ground, and never to vertical velocity so it doesn't eat jumps and falls.
+    if grounded {
+        let horizontal_velocity = Vec3::new(simulation.velocity.x, 0.0, simulation.velocity.z);
+        let horizontal_velocity = MoveableSimulation::apply_friction(
+            horizontal_velocity,
+            horizontal_velocity.length(),
+            config.friction * delta_secs,
+            delta_secs
+        );
+        simulation.velocity.x = horizontal_velocity.x;
+        simulation.velocity.z = horizontal_velocity.z;
+    }
+
+    let acceleration = if grounded {
+        config.acceleration
+    } else {
+        config.acceleration * config.air_control
+    };
 
     simulation.velocity += MoveableSimulation::accelerate(
         wish_dir,
-        speed,
+        config.speed,
         simulation.velocity.dot(wish_dir),
         acceleration * delta_secs,
         delta_secs
     );
+
+    // Only a fresh press starts a jump, so holding jump (or the server repeating the last input) doesn't bunny hop.
+    let jump_pressed = input.wish_jump && !jump.was_held;
+    let wants_jump = jump_pressed || jump.buffer_timer > 0.0;
+    let can_jump = grounded || jump.coyote_timer > 0.0;
+    jump.was_held = input.wish_jump;
+
+    if wants_jump && can_jump {
+        simulation.velocity.y = config.jump_impulse;
+        simulation.grounded = false;
+        jump.buffer_timer = 0.0;
+        jump.coyote_timer = 0.0;
+        return;
+    }
+
+    jump.buffer_timer = if jump_pressed {
+        config.jump_buffer_time
+    } else {
+        (jump.buffer_timer - delta_secs).max(0.0)
+    };
+
+    jump.coyote_timer = if grounded {
+        config.coyote_time
+    } else {
+        (jump.coyote_timer - delta_secs).max(0.0)
+    };
 }
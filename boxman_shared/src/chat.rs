@@ -0,0 +1,54 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Longest chat message (in characters) the server passes on, longer ones are cut short.
+pub const MAX_CHAT_MESSAGE_LEN: usize = 200;
+
+/// Who a player's message goes to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChatChannel {
+    /// Everyone on the server.
+    #[default]
+    All,
+
+    /// Only players on the sender's team.
+    Team,
+}
+
+/// A line of chat as the server sends it out.
+#[derive(Event, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChatLine {
+    pub kind: ChatLineKind,
+    pub text: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ChatLineKind {
+    /// Something a player said. The name is included so the line still makes sense after they leave.
+    Player {
+        sender_id: u64,
+        sender_name: String,
+        channel: ChatChannel,
+    },
+
+    /// Sent by the server to everyone.
+    Announcement,
+
+    /// Sent by the server to just this player, e.g. why their message didn't go through.
+    Notice,
+}
+
+/// Trims the message and strips anything that isn't printable, None if there's nothing left.
+pub fn sanitize_chat_message(text: &str) -> Option<String> {
+    let text: String = text.trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_MESSAGE_LEN)
+        .collect();
+
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    Some(text.trim_end().to_string())
+}
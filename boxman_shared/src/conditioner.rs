@@ -0,0 +1,299 @@
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+/// Extra seconds a packet picked for reordering is held, enough for a few later packets to overtake it.
+const REORDER_DELAY: f32 = 0.05;
+
+/// Most seconds of latency or jitter we simulate, anything longer is a typo rather than a network.
+pub const MAX_SIMULATED_DELAY: f32 = 10.0;
+
+/// How long the relay sleeps when no packets came in.
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+
+/// Netcode packets are well under this.
+const MAX_PACKET_BYTES: usize = 2048;
+
+/// Bad network conditions to simulate, applied to each direction on its own.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct NetworkConditions {
+    /// Seconds every packet is held back. It's added both ways, so the round trip goes up by twice this.
+    pub latency: f32,
+
+    /// Up to this many seconds more, picked per packet. Packets can overtake each other because of it.
+    pub jitter: f32,
+
+    /// Chance a packet is dropped [0.0 to 1.0].
+    pub loss: f32,
+
+    /// Chance a packet arrives twice [0.0 to 1.0].
+    pub duplication: f32,
+
+    /// Chance a packet is held back a little longer, so the ones after it arrive first [0.0 to 1.0].
+    pub reorder: f32,
+
+    /// Makes the random choices repeatable, e.g. in tests. Only used when the conditioner starts.
+    pub seed: Option<u64>,
+}
+
+impl NetworkConditions {
+    /// The same conditions with every value in its valid range.
+    pub fn sanitized(&self) -> Self {
+        let seconds = |value: f32| if value.is_finite() { value.clamp(0.0, MAX_SIMULATED_DELAY) } else { 0.0 };
+        let chance = |value: f32| if value.is_finite() { value.clamp(0.0, 1.0) } else { 0.0 };
+        Self {
+            latency: seconds(self.latency),
+            jitter: seconds(self.jitter),
+            loss: chance(self.loss),
+            duplication: chance(self.duplication),
+            reorder: chance(self.reorder),
+            seed: self.seed,
+        }
+    }
+}
+
+/// A UDP relay between peers and a target socket, passing packets along under `NetworkConditions`.
+/// Each peer gets a socket of its own towards the target, so the target can still tell them apart,
+/// though they all seem to come from this machine.
+///
+/// The relay runs on its own thread until this is dropped.
+#[derive(Resource)]
+pub struct LinkConditioner {
+    conditions: Arc<Mutex<NetworkConditions>>,
+    local_addr: SocketAddr,
+}
+
+impl LinkConditioner {
+    /// Relays whatever arrives on `socket` to `target`, and the replies back.
+    pub fn spawn(socket: UdpSocket, target: SocketAddr, conditions: NetworkConditions) -> io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+        let conditions = conditions.sanitized();
+        let rng = match conditions.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_os_rng(),
+        };
+        let conditions = Arc::new(Mutex::new(conditions));
+
+        let mut relay = Relay {
+            socket,
+            target,
+            upstreams: HashMap::new(),
+            queue: BinaryHeap::new(),
+            next_order: 0,
+            rng,
+            conditions: conditions.clone(),
+        };
+        thread::Builder::new()
+            .name("link conditioner".to_string())
+            .spawn(move || relay.run())?;
+
+        Ok(Self {
+            conditions,
+            local_addr,
+        })
+    }
+
+    /// Where peers send their packets.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        self.conditions.lock().map(|conditions| conditions.clone()).unwrap_or_default()
+    }
+
+    /// Takes effect for the next packet, packets already held back keep their delay.
+    pub fn set_conditions(&self, conditions: &NetworkConditions) {
+        if let Ok(mut current) = self.conditions.lock() {
+            *current = conditions.sanitized();
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Route {
+    /// From a peer, goes out their upstream socket.
+    ToTarget(SocketAddr),
+
+    /// From the target, goes back to the peer.
+    ToPeer(SocketAddr),
+}
+
+struct HeldPacket {
+    send_at: Instant,
+
+    /// Keeps packets held for the same time in the order they came in.
+    order: u64,
+    route: Route,
+    bytes: Vec<u8>,
+}
+
+impl PartialEq for HeldPacket {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeldPacket {}
+
+impl PartialOrd for HeldPacket {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeldPacket {
+    /// Reversed, so the heap gives the packet due first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.send_at, other.order).cmp(&(self.send_at, self.order))
+    }
+}
+
+struct Relay {
+    socket: UdpSocket,
+    target: SocketAddr,
+
+    /// Each peer's socket towards the target.
+    upstreams: HashMap<SocketAddr, UdpSocket>,
+    queue: BinaryHeap<HeldPacket>,
+    next_order: u64,
+    rng: StdRng,
+    conditions: Arc<Mutex<NetworkConditions>>,
+}
+
+impl Relay {
+    fn run(&mut self) {
+        let mut buffer = [0; MAX_PACKET_BYTES];
+        let mut recv_error_logged = false;
+
+        // The conditioner holds the other reference, once it's dropped there's nobody left to relay for.
+        while Arc::strong_count(&self.conditions) > 1 {
+            let conditions = self.conditions.lock().map(|conditions| conditions.clone()).unwrap_or_default();
+            let now = Instant::now();
+            let mut received = false;
+
+            loop {
+                match self.socket.recv_from(&mut buffer) {
+                    Ok((len, peer)) => {
+                        received = true;
+                        if !self.upstreams.contains_key(&peer) {
+                            match self.connect_upstream() {
+                                Ok(upstream) => {
+                                    self.upstreams.insert(peer, upstream);
+                                }
+                                Err(e) => {
+                                    error!("Link conditioner failed to relay for {}: {}", peer, e);
+                                    continue;
+                                }
+                            }
+                        }
+                        self.hold(&conditions, now, Route::ToTarget(peer), &buffer[..len]);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    // e.g. an ICMP error from an earlier send. Anything behind it is read next time round,
+                    // and an error that keeps coming back doesn't keep us here or flood the log.
+                    Err(e) => {
+                        if !recv_error_logged {
+                            warn!("Link conditioner failed to receive: {}", e);
+                            recv_error_logged = true;
+                        }
+                        break;
+                    }
+                }
+            }
+
+            let mut replies = Vec::new();
+            for (peer, upstream) in self.upstreams.iter() {
+                loop {
+                    match upstream.recv(&mut buffer) {
+                        Ok(len) => replies.push((*peer, buffer[..len].to_vec())),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(_) => break,
+                    }
+                }
+            }
+            received |= !replies.is_empty();
+            for (peer, bytes) in replies {
+                self.hold(&conditions, now, Route::ToPeer(peer), &bytes);
+            }
+
+            while self.queue.peek().is_some_and(|packet| packet.send_at <= now) {
+                let Some(packet) = self.queue.pop() else {
+                    break;
+                };
+
+                // The network loses packets anyway, nobody is waiting on these to arrive.
+                let _ = match packet.route {
+                    Route::ToTarget(peer) => self.upstreams.get(&peer).map(|upstream| upstream.send(&packet.bytes)),
+                    Route::ToPeer(peer) => Some(self.socket.send_to(&packet.bytes, peer)),
+                };
+            }
+
+            if !received {
+                thread::sleep(IDLE_SLEEP);
+            }
+        }
+    }
+
+    fn connect_upstream(&self) -> io::Result<UdpSocket> {
+        let local_ip = if self.target.is_ipv6() { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() };
+        let upstream = UdpSocket::bind(SocketAddr::new(local_ip, 0))?;
+        upstream.connect(self.target)?;
+        upstream.set_nonblocking(true)?;
+        Ok(upstream)
+    }
+
+    /// Drops, duplicates and delays a packet according to `conditions`.
+    fn hold(&mut self, conditions: &NetworkConditions, now: Instant, route: Route, bytes: &[u8]) {
+        if self.rng.random::<f32>() < conditions.loss {
+            return;
+        }
+
+        let copies = if self.rng.random::<f32>() < conditions.duplication { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut delay = conditions.latency + self.rng.random::<f32>() * conditions.jitter;
+            if self.rng.random::<f32>() < conditions.reorder {
+                delay += REORDER_DELAY;
+            }
+
+            self.queue.push(HeldPacket {
+                send_at: now + Duration::from_secs_f32(delay),
+                order: self.next_order,
+                route,
+                bytes: bytes.to_vec(),
+            });
+            self.next_order += 1;
+        }
+    }
+}
+
+pub struct ConditionerPlugin;
+
+impl Plugin for ConditionerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update,
+            update_conditions_system
+                .run_if(resource_exists::<LinkConditioner>)
+                .run_if(resource_exists_and_changed::<NetworkConditions>),
+        );
+    }
+}
+
+/// Passes changes to `NetworkConditions` on to the running conditioner.
+fn update_conditions_system(
+    conditions: Res<NetworkConditions>,
+    conditioner: Res<LinkConditioner>,
+) {
+    conditioner.set_conditions(&conditions);
+}
@@ -1,9 +1,17 @@
+use std::net::{IpAddr, Ipv4Addr};
+
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::protocol::PROTOCOL_ID;
+
 #[derive(Asset, TypePath, Debug, Resource, Serialize, Deserialize)]
 pub struct ControlsConfig {
     pub mouse_sensitivity: f32,
+
+    /// How far a gamepad axis has to be pushed before it counts as input [0.0 to 1.0].
+    pub gamepad_deadzone: f32,
+
     pub controls: Controls,
 }
 
@@ -14,24 +22,98 @@ pub struct Controls {
     pub move_left: ControlsInput,
     pub move_right: ControlsInput,
     pub jump: ControlsInput,
+    pub fire: ControlsInput,
+
+    /// Opens chat to everyone. Defaults for these are filled in so older saved controls still load.
+    #[serde(default = "default_chat_binding")]
+    pub chat: ControlsInput,
+
+    /// Opens chat to your team.
+    #[serde(default = "default_team_chat_binding")]
+    pub team_chat: ControlsInput,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+fn default_chat_binding() -> ControlsInput {
+    ControlsInput::Keyboard(KeyCode::Enter)
+}
+
+fn default_team_chat_binding() -> ControlsInput {
+    ControlsInput::Keyboard(KeyCode::KeyY)
+}
+
+impl Controls {
+    pub fn binding(&self, action: ControlsAction) -> &ControlsInput {
+        match action {
+            ControlsAction::MoveForward => &self.move_forward,
+            ControlsAction::MoveBackward => &self.move_backward,
+            ControlsAction::MoveLeft => &self.move_left,
+            ControlsAction::MoveRight => &self.move_right,
+            ControlsAction::Jump => &self.jump,
+            ControlsAction::Fire => &self.fire,
+            ControlsAction::Chat => &self.chat,
+            ControlsAction::TeamChat => &self.team_chat,
+        }
+    }
+
+    pub fn binding_mut(&mut self, action: ControlsAction) -> &mut ControlsInput {
+        match action {
+            ControlsAction::MoveForward => &mut self.move_forward,
+            ControlsAction::MoveBackward => &mut self.move_backward,
+            ControlsAction::MoveLeft => &mut self.move_left,
+            ControlsAction::MoveRight => &mut self.move_right,
+            ControlsAction::Jump => &mut self.jump,
+            ControlsAction::Fire => &mut self.fire,
+            ControlsAction::Chat => &mut self.chat,
+            ControlsAction::TeamChat => &mut self.team_chat,
+        }
+    }
+}
+
+/// Every action that can be bound in [`Controls`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControlsAction {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    Jump,
+    Fire,
+    Chat,
+    TeamChat,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum ControlsInput {
     Keyboard(KeyCode),
     Mouse(MouseButton),
+    Gamepad(GamepadControl),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum GamepadControl {
+    Button(GamepadButton),
+
+    /// One direction of an axis, `positive` picks which half of the axis is used.
+    Axis {
+        axis: GamepadAxis,
+        positive: bool,
+    },
 }
 
 impl Default for ControlsConfig {
     fn default() -> Self {
         Self { 
             mouse_sensitivity: 0.001,
+            gamepad_deadzone: 0.2,
             controls: Controls {
                 move_forward: ControlsInput::Keyboard(KeyCode::KeyW),
                 move_backward: ControlsInput::Keyboard(KeyCode::KeyS),
                 move_left: ControlsInput::Keyboard(KeyCode::KeyA),
                 move_right: ControlsInput::Keyboard(KeyCode::KeyD),
                 jump: ControlsInput::Keyboard(KeyCode::Space),
+                fire: ControlsInput::Mouse(MouseButton::Left),
+                chat: default_chat_binding(),
+                team_chat: default_team_chat_binding(),
             },
         }
     }
@@ -76,6 +158,42 @@ pub struct MultiplayerConfig {
     /// - Correction state is cleared
     /// Should be small enough to be visually unnoticeable.
     pub visual_snap_threshold: f32,         
+
+    /// How far behind the estimated server time remote characters are rendered (in seconds).
+    /// Remote characters are interpolated between the two buffered server states around that time,
+    /// so this should cover a couple of snapshot intervals plus jitter to survive lost packets.
+    pub interpolation_delay: f32,
+
+    /// How long (in seconds) a remote character keeps moving along its last known velocity
+    /// once the render time passes the newest buffered state.
+    /// After this it freezes in place until a new state arrives.
+    pub max_extrapolation: f32,
+
+    /// Maximum number of server states buffered per remote character.
+    /// Older states are dropped first.
+    pub interpolation_buffer_size: usize,
+
+    /// How quickly the estimated server clock, round trip time and jitter follow new pings [0.0 to 1.0].
+    /// Lower values give a steadier render time at the cost of reacting slower to latency changes.
+    pub server_clock_smoothing: f32,
+
+    /// Seconds between pings to the server.
+    /// Each pong updates the clock estimate and the tick rate adjustment.
+    pub ping_interval: f32,
+
+    /// How many of our inputs (in ticks) we want waiting on the server, on top of the jitter.
+    /// Higher values survive more network hiccups but add latency to every input.
+    pub input_buffer_target: f32,
+
+    /// Maximum change to our tick rate (as a fraction, 0.05 = 5%) used to keep the server's input buffer on target.
+    /// Higher values catch up faster but make the game speed noticeably drift. Capped at `MAX_TICK_RATE_ADJUSTMENT`.
+    pub max_tick_rate_adjustment: f32,
+
+    /// Maximum number of unacknowledged inputs sent in each input packet, the newest ones are kept.
+    /// Every input goes out in this many packets (until it's acked), so that many packets in a row
+    /// can be lost before the server misses an input. Higher values cost more upload bandwidth.
+    /// Capped at `packed::MAX_INPUTS_PER_PACKET`, the most the server accepts.
+    pub max_inputs_per_packet: usize,
 }
 
 impl Default for MultiplayerConfig {
@@ -87,15 +205,47 @@ impl Default for MultiplayerConfig {
             visual_smooth_factor_max: 0.90,
             visual_smooth_speed_multiplier: 15.0,
             visual_snap_threshold: 0.005,
+            interpolation_delay: 0.1,
+            max_extrapolation: 0.25,
+            interpolation_buffer_size: 32,
+            server_clock_smoothing: 0.1,
+            ping_interval: 0.25,
+            input_buffer_target: 1.0,
+            max_tick_rate_adjustment: 0.05,
+            max_inputs_per_packet: 5,
         }
     }
 }
 
-#[derive(Asset, TypePath, Debug, Resource, Serialize, Deserialize)]
+#[derive(Asset, TypePath, Debug, Clone, Resource, Serialize, Deserialize)]
 pub struct CharacterConfig {
     pub speed: f32,
     pub acceleration: f32,
     pub friction: f32,
+
+    /// Upwards velocity applied when jumping.
+    pub jump_impulse: f32,
+
+    /// Multiplier on acceleration while airborne [0.0 to 1.0].
+    pub air_control: f32,
+
+    /// Seconds after leaving the ground during which a jump is still allowed.
+    pub coyote_time: f32,
+
+    /// Seconds a jump pressed in the air is remembered, so it triggers on landing.
+    pub jump_buffer_time: f32,
+
+    pub max_health: f32,
+    pub max_armor: f32,
+
+    /// Armor a character starts with when (re)spawning.
+    pub spawn_armor: f32,
+
+    /// Fraction of incoming damage absorbed by armor [0.0 to 1.0].
+    pub armor_absorption: f32,
+
+    /// Seconds a character stays dead before respawning.
+    pub respawn_time: f32,
 }
 
 impl Default for CharacterConfig {
@@ -104,6 +254,92 @@ impl Default for CharacterConfig {
             speed: 100.0,
             acceleration: 10.0,
             friction: 4.0,
+            jump_impulse: 5.0,
+            air_control: 0.3,
+            coyote_time: 0.1,
+            jump_buffer_time: 0.1,
+            max_health: 100.0,
+            max_armor: 100.0,
+            spawn_armor: 50.0,
+            armor_absorption: 0.66,
+            respawn_time: 3.0,
+        }
+    }
+}
+
+#[derive(Asset, TypePath, Debug, Resource, Serialize, Deserialize, Clone)]
+pub struct ServerConfig {
+    /// Simulation ticks per second.
+    pub tick_rate: f64,
+
+    /// Snapshots sent to each client per second, at most one per tick.
+    /// Lower rates save bandwidth, clients interpolate across the gaps.
+    /// This is the most a client gets, the rate drops for clients that lose packets or use too much bandwidth.
+    pub snapshot_send_rate: f64,
+
+    /// The least a struggling client's snapshot rate drops to.
+    pub min_snapshot_send_rate: f64,
+
+    /// Bytes per second we're willing to send a single client, past this its snapshot rate drops.
+    pub max_client_bandwidth: f64,
+
+    /// Packet loss [0.0 to 1.0] past which a client's snapshot rate drops.
+    pub snapshot_loss_threshold: f64,
+
+    /// Address the server's socket binds to. Use "::" to accept IPv4 and IPv6 clients on a dual stack system.
+    pub bind_address: IpAddr,
+
+    pub port: u16,
+
+    /// Players connected at once, anyone past this is refused by netcode.
+    pub max_players: usize,
+
+    /// Identifies our netcode traffic. Clients connect with `PROTOCOL_ID`, so only change this for builds that do too.
+    pub protocol_id: u64,
+
+    /// How many snapshots are kept to encode deltas against.
+    /// Clients that haven't acked any of these get full snapshots, so it should cover a round trip or more.
+    pub snapshot_history: usize,
+
+    /// Characters further than this from a client aren't sent to it.
+    pub relevance_distance: f32,
+
+    /// Characters closer than this are sent even without a line of sight.
+    pub always_relevant_distance: f32,
+
+    /// Seconds a character is still sent after going out of sight.
+    pub relevance_linger: f32,
+
+    /// Bytes of character state per snapshot packet. Characters that don't fit are updated in a later one,
+    /// closer ones get their turn more often.
+    pub snapshot_byte_budget: usize,
+
+    /// Levels the server cycles through, starting with the first one.
+    pub levels: Vec<String>,
+
+    /// Seconds each level is played before moving to the next one. 0 stays on the first level.
+    pub level_duration: f32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tick_rate: 64.0,
+            snapshot_send_rate: 64.0,
+            min_snapshot_send_rate: 10.0,
+            max_client_bandwidth: 64_000.0,
+            snapshot_loss_threshold: 0.05,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 5000,
+            max_players: 64,
+            protocol_id: PROTOCOL_ID,
+            snapshot_history: 64,
+            relevance_distance: 150.0,
+            always_relevant_distance: 20.0,
+            relevance_linger: 1.0,
+            snapshot_byte_budget: 1000,
+            levels: vec!["arena".to_string()],
+            level_duration: 0.0,
         }
     }
 }
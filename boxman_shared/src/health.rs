@@ -0,0 +1,61 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageEvent>();
+        app.add_event::<CharacterDeathEvent>();
+    }
+}
+
+/// A character is dead while `current` is at or below zero.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0.0
+    }
+}
+
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Armor {
+    pub current: f32,
+    pub max: f32,
+}
+
+/// Damage to a character, only acted on by the server.
+#[derive(Event, Debug, Clone)]
+pub struct DamageEvent {
+    pub target_id: u64,
+
+    /// Who dealt the damage, if anyone.
+    pub attacker_id: Option<u64>,
+
+    pub amount: f32,
+}
+
+#[derive(Event, Serialize, Deserialize, Debug, Clone)]
+pub struct CharacterDeathEvent {
+    pub client_id: u64,
+    pub killer_id: Option<u64>,
+}
+
+/// Applies damage, armor soaks up `armor_absorption` [0.0 to 1.0] of it for as long as it lasts.
+/// Returns true if this damage killed the character.
+pub fn apply_damage(health: &mut Health, armor: &mut Armor, amount: f32, armor_absorption: f32) -> bool {
+    if health.is_dead() {
+        return false;
+    }
+
+    let absorbed = (amount * armor_absorption).min(armor.current);
+    armor.current -= absorbed;
+    health.current -= amount - absorbed;
+
+    health.is_dead()
+}
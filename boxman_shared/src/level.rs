@@ -0,0 +1,181 @@
+use std::{error::Error, path::{Path, PathBuf}};
+
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::types::MeshConfig;
+
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<LoadLevelEvent>();
+        app.add_systems(Update,
+            load_level_system.run_if(resource_exists::<LevelsDirectory>)
+        );
+    }
+}
+
+/// Directory the level files are read from, levels are looked up as `<name>.ron` inside of it.
+/// Levels are read straight from disk so the dedicated server doesn't need the asset server.
+#[derive(Resource)]
+pub struct LevelsDirectory(pub PathBuf);
+
+/// Unloads the current level (if any) and loads the level with the given name.
+#[derive(Event)]
+pub struct LoadLevelEvent(pub String);
+
+/// The level that is currently loaded.
+#[derive(Resource)]
+pub struct CurrentLevel {
+    pub name: String,
+    pub level: Level,
+}
+
+/// Marks every entity that belongs to the loaded level, so it can be despawned when the level changes.
+#[derive(Component)]
+pub struct LevelEntity;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Level {
+    pub geometry: Vec<LevelGeometry>,
+    pub spawn_points: Vec<SpawnPoint>,
+    pub lights: Vec<LevelLight>,
+}
+
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+pub struct LevelGeometry {
+    pub position: Vec3,
+    pub rotation: Quat,
+
+    /// What the client renders, leave it out for invisible walls.
+    pub mesh: Option<MeshConfig>,
+
+    /// What the simulation collides with, leave it out for purely visual geometry.
+    pub collider: Option<ColliderKind>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ColliderKind {
+    Box {
+        width: f32,
+        height: f32,
+        depth: f32,
+    },
+    Sphere {
+        radius: f32,
+    },
+    Capsule {
+        radius: f32,
+        height: f32,
+    },
+}
+
+impl ColliderKind {
+    pub fn collider(&self) -> Collider {
+        match self {
+            ColliderKind::Box { width, height, depth } => Collider::cuboid(*width, *height, *depth),
+            ColliderKind::Sphere { radius } => Collider::sphere(*radius),
+            ColliderKind::Capsule { radius, height } => Collider::capsule(*radius, *height),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpawnPoint {
+    pub position: Vec3,
+    pub yaw: f32,
+}
+
+/// Lights are only rendered by the client, the server keeps them around so it can tell
+/// what the level looks like but never does anything with them.
+#[derive(Component, Debug, Clone, Serialize, Deserialize)]
+pub struct LevelLight {
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub light_kind: LightKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum LightKind {
+    Point {
+        color: Color,
+        intensity: f32,
+        range: f32,
+        shadows: bool,
+    },
+    Directional {
+        color: Color,
+        illuminance: f32,
+        shadows: bool,
+    },
+}
+
+pub fn load_level(levels_directory: &Path, name: &str) -> Result<Level, Box<dyn Error>> {
+    let path = levels_directory.join(format!("{}.ron", name));
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let level = ron::from_str::<Level>(&contents)
+        .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    Ok(level)
+}
+
+fn load_level_system(
+    mut commands: Commands,
+    levels_directory: Res<LevelsDirectory>,
+    current_level: Option<Res<CurrentLevel>>,
+    mut load_level_events: EventReader<LoadLevelEvent>,
+    level_entities: Query<Entity, With<LevelEntity>>,
+) {
+    let Some(event) = load_level_events.read().last() else {
+        return;
+    };
+
+    if current_level.is_some_and(|current_level| current_level.name == event.0) {
+        return;
+    }
+
+    match load_level(&levels_directory.0, &event.0) {
+        Ok(level) => {
+            for entity in level_entities.iter() {
+                commands.entity(entity).despawn_recursive();
+            }
+
+            spawn_level(&mut commands, &level);
+
+            info!("Loaded level {}", event.0);
+            commands.insert_resource(CurrentLevel {
+                name: event.0.clone(),
+                level,
+            });
+        }
+        Err(e) => {
+            error!("Failed to load level {}: {}", event.0, e);
+        }
+    }
+}
+
+fn spawn_level(commands: &mut Commands, level: &Level) {
+    for geometry in level.geometry.iter() {
+        let mut entity = commands.spawn((
+            LevelEntity,
+            geometry.clone(),
+            Transform::from_translation(geometry.position)
+                .with_rotation(geometry.rotation),
+        ));
+
+        if let Some(collider_kind) = &geometry.collider {
+            entity.insert(collider_kind.collider());
+        }
+    }
+
+    for light in level.lights.iter() {
+        commands.spawn((
+            LevelEntity,
+            light.clone(),
+            Transform::from_translation(light.position)
+                .with_rotation(light.rotation),
+        ));
+    }
+}
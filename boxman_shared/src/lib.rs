@@ -5,6 +5,13 @@ pub mod snapshot;
 pub mod types;
 pub mod utils;
 pub mod data;
+pub mod health;
+pub mod level;
+pub mod weapons;
+pub mod roster;
+pub mod chat;
+pub mod replication;
+pub mod conditioner;
 
 pub mod prelude {
     pub use super::*;
@@ -15,11 +22,23 @@ pub mod prelude {
     pub use types::*;
     pub use utils::*;
     pub use data::*;
+    pub use health::*;
+    pub use level::*;
+    pub use weapons::*;
+    pub use roster::*;
+    pub use chat::*;
+    pub use replication::*;
+    pub use conditioner::*;
 }
 
 use bevy::prelude::*;
 use character::*;
+use conditioner::ConditionerPlugin;
+use health::HealthPlugin;
+use level::LevelPlugin;
 use moveable_sim::MoveableSimulationPlugin;
+use replication::ReplicationPlugin;
+use weapons::WeaponsPlugin;
 
 pub struct SharedPlugin;
 
@@ -27,5 +46,10 @@ impl Plugin for SharedPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(CharacterPlugin);
         app.add_plugins(MoveableSimulationPlugin);
+        app.add_plugins(LevelPlugin);
+        app.add_plugins(WeaponsPlugin);
+        app.add_plugins(HealthPlugin);
+        app.add_plugins(ReplicationPlugin);
+        app.add_plugins(ConditionerPlugin);
     }
 }
@@ -30,6 +30,11 @@ pub struct MoveableParams {
     pub max_slope_angle: Option<MaxSlopeAngleDegrees>,
 }
 
+/// Moveables with this are positioned from the outside, for example from buffered server
+/// states on the client, and are skipped by the simulation.
+#[derive(Component)]
+pub struct SimulationDisabled;
+
 #[derive(Component)]
 pub struct MoveableVisuals {
     pub simulation_entity: Entity,
@@ -53,7 +58,7 @@ pub struct MoveableSimulation {
 fn simulation_move_system(
     fixed_time: Res<Time<Fixed>>,
     spatial_query: SpatialQuery,
-    mut simulations: Query<(Entity, &mut MoveableSimulation, &mut Transform)>,
+    mut simulations: Query<(Entity, &mut MoveableSimulation, &mut Transform), Without<SimulationDisabled>>,
 ) {
     for (entity, mut simulation, mut transform) in simulations.iter_mut() {
         move_simulation(
@@ -0,0 +1,313 @@
+use std::f32::consts::{PI, TAU};
+
+use bevy::prelude::*;
+
+/// Why a packed message couldn't be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackError {
+    /// The message ended before everything was read.
+    UnexpectedEnd,
+
+    /// A value was out of its range, e.g. a small-range int past its maximum.
+    Invalid(&'static str),
+}
+
+impl std::fmt::Display for PackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PackError::UnexpectedEnd => write!(f, "unexpected end of message"),
+            PackError::Invalid(what) => write!(f, "invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for PackError {}
+
+/// Maps floats in `[min, max]` onto `bits` bits, anything outside is clamped.
+/// The number of steps is even, so the middle of the range (e.g. 0 in `[-1, 1]`) comes back exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantization {
+    pub min: f32,
+    pub max: f32,
+    pub bits: u32,
+}
+
+impl Quantization {
+    pub const fn new(min: f32, max: f32, bits: u32) -> Self {
+        Self { min, max, bits }
+    }
+
+    fn steps(&self) -> u64 {
+        (1u64 << self.bits.clamp(2, 32)) - 2
+    }
+
+    pub fn quantize(&self, value: f32) -> u64 {
+        let t = (value.clamp(self.min, self.max) - self.min) / (self.max - self.min);
+        (t as f64 * self.steps() as f64).round() as u64
+    }
+
+    pub fn dequantize(&self, value: u64) -> f32 {
+        let t = value.min(self.steps()) as f64 / self.steps() as f64;
+        (self.min as f64 + (self.max - self.min) as f64 * t) as f32
+    }
+
+    /// The value the other end will read back.
+    pub fn round_trip(&self, value: f32) -> f32 {
+        self.dequantize(self.quantize(value))
+    }
+}
+
+/// Maps an angle (in radians) onto `bits` bits.
+pub fn quantize_angle_bits(angle: f32, bits: u32) -> u64 {
+    let steps = 1u64 << bits;
+    ((angle.rem_euclid(TAU) / TAU) as f64 * steps as f64).round() as u64 % steps
+}
+
+/// Gives back an angle in the range [-PI, PI).
+pub fn dequantize_angle_bits(angle: u64, bits: u32) -> f32 {
+    let angle = (angle as f64 / (1u64 << bits) as f64 * TAU as f64) as f32;
+    if angle >= PI {
+        angle - TAU
+    } else {
+        angle
+    }
+}
+
+/// Writes values packed down to the bit, least significant bit first.
+#[derive(Debug, Default, Clone)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    bit_len: usize,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many bits have been written so far.
+    pub fn bit_len(&self) -> usize {
+        self.bit_len
+    }
+
+    /// The packed bytes, the last one padded with zeroes.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    /// Writes the low `bits` bits of `value`.
+    pub fn write_bits(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 64);
+        for i in 0..bits {
+            if self.bit_len % 8 == 0 {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                self.bytes[self.bit_len / 8] |= 1 << (self.bit_len % 8);
+            }
+            self.bit_len += 1;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u64, 1);
+    }
+
+    /// An int between 0 and `max` inclusive, in as few bits as that range needs.
+    pub fn write_ranged(&mut self, value: u64, max: u64) {
+        debug_assert!(value <= max);
+        self.write_bits(value.min(max), bits_for(max));
+    }
+
+    /// Seven bits at a time, each group followed by a bit saying whether more follow.
+    /// Small values are cheap, ids that keep counting up stay reasonable.
+    pub fn write_var_u64(&mut self, mut value: u64) {
+        loop {
+            self.write_bits(value & 0x7f, 7);
+            value >>= 7;
+            self.write_bool(value != 0);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+
+    /// Zigzag encoded, so small negative values are as cheap as small positive ones.
+    pub fn write_var_i64(&mut self, value: i64) {
+        self.write_var_u64(((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_bits(value.to_bits() as u64, 32);
+    }
+
+    pub fn write_quantized(&mut self, value: f32, quantization: &Quantization) {
+        self.write_bits(quantization.quantize(value), quantization.bits);
+    }
+
+    pub fn write_angle(&mut self, angle: f32, bits: u32) {
+        self.write_bits(quantize_angle_bits(angle, bits), bits);
+    }
+
+    /// A 2D unit vector as its angle.
+    pub fn write_unit_vec2(&mut self, value: Vec2, bits: u32) {
+        self.write_angle(value.y.atan2(value.x), bits);
+    }
+
+    /// A 3D unit vector folded onto an octahedron, `bits` per component for the two that are sent.
+    pub fn write_unit_vec3(&mut self, value: Vec3, bits: u32) {
+        let folded = fold_octahedron(value);
+        let quantization = Quantization::new(-1.0, 1.0, bits);
+        self.write_quantized(folded.x, &quantization);
+        self.write_quantized(folded.y, &quantization);
+    }
+
+    /// A length followed by the bytes themselves.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_var_u64(bytes.len() as u64);
+        for byte in bytes {
+            self.write_bits(*byte as u64, 8);
+        }
+    }
+}
+
+/// Reads back what a `BitWriter` wrote, in the same order.
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    /// Bits left to read, including the padding at the end.
+    pub fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u64, PackError> {
+        debug_assert!(bits <= 64);
+        if bits as usize > self.remaining_bits() {
+            return Err(PackError::UnexpectedEnd);
+        }
+
+        let mut value = 0;
+        for i in 0..bits {
+            let bit = (self.bytes[self.position / 8] >> (self.position % 8)) & 1;
+            value |= (bit as u64) << i;
+            self.position += 1;
+        }
+        Ok(value)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, PackError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_ranged(&mut self, max: u64) -> Result<u64, PackError> {
+        let value = self.read_bits(bits_for(max))?;
+        if value > max {
+            return Err(PackError::Invalid("ranged int"));
+        }
+        Ok(value)
+    }
+
+    pub fn read_var_u64(&mut self) -> Result<u64, PackError> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let group = self.read_bits(7)?;
+            // The tenth group only has room for the top bit of a u64.
+            if shift > 63 || (shift == 63 && group > 1) {
+                return Err(PackError::Invalid("var int"));
+            }
+            value |= group << shift;
+            shift += 7;
+            if !self.read_bool()? {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn read_var_i64(&mut self) -> Result<i64, PackError> {
+        let value = self.read_var_u64()?;
+        Ok(((value >> 1) as i64) ^ -((value & 1) as i64))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, PackError> {
+        Ok(f32::from_bits(self.read_bits(32)? as u32))
+    }
+
+    pub fn read_quantized(&mut self, quantization: &Quantization) -> Result<f32, PackError> {
+        Ok(quantization.dequantize(self.read_bits(quantization.bits)?))
+    }
+
+    pub fn read_angle(&mut self, bits: u32) -> Result<f32, PackError> {
+        Ok(dequantize_angle_bits(self.read_bits(bits)?, bits))
+    }
+
+    pub fn read_unit_vec2(&mut self, bits: u32) -> Result<Vec2, PackError> {
+        let angle = self.read_angle(bits)?;
+        Ok(Vec2::new(angle.cos(), angle.sin()))
+    }
+
+    pub fn read_unit_vec3(&mut self, bits: u32) -> Result<Vec3, PackError> {
+        let quantization = Quantization::new(-1.0, 1.0, bits);
+        let x = self.read_quantized(&quantization)?;
+        let y = self.read_quantized(&quantization)?;
+        Ok(unfold_octahedron(Vec2::new(x, y)))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<Vec<u8>, PackError> {
+        let len = self.read_var_u64()? as usize;
+        if len.saturating_mul(8) > self.remaining_bits() {
+            return Err(PackError::UnexpectedEnd);
+        }
+
+        let mut bytes = Vec::with_capacity(len);
+        for _ in 0..len {
+            bytes.push(self.read_bits(8)? as u8);
+        }
+        Ok(bytes)
+    }
+}
+
+/// How many bits it takes to write every value up to `max`.
+pub fn bits_for(max: u64) -> u32 {
+    64 - max.leading_zeros()
+}
+
+fn sign_not_zero(value: f32) -> f32 {
+    if value >= 0.0 { 1.0 } else { -1.0 }
+}
+
+/// Projects a unit vector onto the octahedron and unfolds it into the [-1, 1] square.
+fn fold_octahedron(value: Vec3) -> Vec2 {
+    let sum = value.x.abs() + value.y.abs() + value.z.abs();
+    if !sum.is_normal() {
+        return Vec2::new(0.0, 0.0);
+    }
+
+    let projected = value / sum;
+    if projected.z >= 0.0 {
+        projected.truncate()
+    } else {
+        Vec2::new(
+            (1.0 - projected.y.abs()) * sign_not_zero(projected.x),
+            (1.0 - projected.x.abs()) * sign_not_zero(projected.y),
+        )
+    }
+}
+
+fn unfold_octahedron(value: Vec2) -> Vec3 {
+    let z = 1.0 - value.x.abs() - value.y.abs();
+    let (x, y) = if z >= 0.0 {
+        (value.x, value.y)
+    } else {
+        ((1.0 - value.y.abs()) * sign_not_zero(value.x), (1.0 - value.x.abs()) * sign_not_zero(value.y))
+    };
+    Vec3::new(x, y, z).normalize_or(Vec3::Z)
+}
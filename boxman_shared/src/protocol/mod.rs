@@ -0,0 +1,346 @@
+pub mod bits;
+pub mod packed;
+
+use bevy_renet::netcode::NETCODE_USER_DATA_BYTES;
+use serde::{Deserialize, Serialize};
+
+use bits::{BitReader, BitWriter, PackError};
+use crate::{chat::{ChatChannel, ChatLine}, roster::{PlayerInfo, PlayerProfile}, character::{PlayerInput, CharacterDespawnEvent, CharacterSpawnEvent}, health::CharacterDeathEvent, data::CharacterConfig, level::CurrentLevel, snapshot::SnapshotDiff, weapons::{WeaponConfig, WeaponFiredEvent}};
+
+/// Identifies our netcode traffic, clients and servers with a different id can't connect to each other.
+/// Netcode drops those silently, so this stays the same between builds and `PROTOCOL_VERSION` is bumped instead.
+pub const PROTOCOL_ID: u64 = 0x426f_786d_616e;
+
+/// Bump this whenever a message changes. Clients and servers on different versions refuse each other in the handshake.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// Most a client speeds up its ticks (as a fraction) to keep the server's input buffer on target, whatever its config says.
+/// The server's input rate limit allows for this much.
+pub const MAX_TICK_RATE_ADJUSTMENT: f32 = 0.25;
+
+/// What this build supports, sent in the handshake.
+pub const FEATURES: &[&str] = &["packed_snapshots", "relevancy", "replication", "server_gameplay_config"];
+
+/// Features the other side has to support as well.
+pub const REQUIRED_FEATURES: &[&str] = &["packed_snapshots", "relevancy", "replication", "server_gameplay_config"];
+
+/// The first byte of every message says how the rest is encoded.
+/// Messages sent every tick are bit packed, everything else goes through bincode.
+const BINCODE_MESSAGE: u8 = 0;
+const PACKED_MESSAGE: u8 = 1;
+
+/// Handshakes and kicks have a format of their own that never changes,
+/// so a client from another build can still read why it's being turned away.
+const HANDSHAKE_MESSAGE: u8 = 2;
+const KICKED_MESSAGE: u8 = 3;
+
+/// Why a message couldn't be encoded or decoded.
+#[derive(Debug)]
+pub enum ProtocolError {
+    Bincode(bincode::Error),
+    Pack(PackError),
+    Empty,
+    UnknownFormat(u8),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Bincode(e) => write!(f, "{}", e),
+            ProtocolError::Pack(e) => write!(f, "{}", e),
+            ProtocolError::Empty => write!(f, "empty message"),
+            ProtocolError::UnknownFormat(format) => write!(f, "unknown message format {}", format),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<bincode::Error> for ProtocolError {
+    fn from(e: bincode::Error) -> Self {
+        ProtocolError::Bincode(e)
+    }
+}
+
+impl From<PackError> for ProtocolError {
+    fn from(e: PackError) -> Self {
+        ProtocolError::Pack(e)
+    }
+}
+
+/// FNV-1a over the bincode encoding. Unlike std's hasher it's the same on every platform and build.
+pub fn content_hash<T: Serialize>(value: &T) -> u64 {
+    let bytes = bincode::serialize(value).unwrap_or_default();
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// A level by name, with a hash of its contents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LevelHash {
+    pub name: String,
+    pub hash: u64,
+}
+
+impl LevelHash {
+    pub fn new(current_level: &CurrentLevel) -> Self {
+        Self {
+            name: current_level.name.clone(),
+            hash: content_hash(&current_level.level),
+        }
+    }
+}
+
+/// The first thing both sides send after connecting, to find out whether they can play together.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Handshake {
+    /// Always first, so it can be read even when the rest of the layout changed between versions.
+    pub version: u32,
+
+    /// The level the server is running, None from clients.
+    pub level: Option<LevelHash>,
+
+    pub features: Vec<String>,
+}
+
+impl Handshake {
+    pub fn new(current_level: Option<&CurrentLevel>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            level: current_level.map(LevelHash::new),
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        }
+    }
+
+    /// Whether the other side's handshake is compatible with ours, and why not if it isn't.
+    pub fn check(&self, local: &Handshake) -> Result<(), String> {
+        if self.version != local.version {
+            return Err(format!("protocol version {}, expected {}", self.version, local.version));
+        }
+
+        let missing: Vec<&str> = REQUIRED_FEATURES.iter()
+            .filter(|feature| !self.features.iter().any(|f| f == *feature))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("missing features: {}", missing.join(", ")));
+        }
+
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut bytes = vec![HANDSHAKE_MESSAGE];
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Other versions may have changed everything after the version, so only that is read from them.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let version: u32 = bincode::deserialize(bytes)?;
+        if version != PROTOCOL_VERSION {
+            return Ok(Self {
+                version,
+                ..Default::default()
+            });
+        }
+
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Longest player name (in bytes) that fits in the netcode user data.
+pub const MAX_PLAYER_NAME_BYTES: usize = 64;
+
+/// Who a client is, sent in the netcode user data when connecting.
+/// With secure authentication this comes from the connect token, so the client can't change it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerUserData {
+    pub player_id: u64,
+    pub name: String,
+}
+
+impl PlayerUserData {
+    /// The player id, then the length of the name and the name itself. Long names are cut short.
+    pub fn to_bytes(&self) -> [u8; NETCODE_USER_DATA_BYTES] {
+        let mut name_len = self.name.len().min(MAX_PLAYER_NAME_BYTES);
+        while !self.name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+
+        let mut bytes = [0; NETCODE_USER_DATA_BYTES];
+        bytes[0..8].copy_from_slice(&self.player_id.to_le_bytes());
+        bytes[8] = name_len as u8;
+        bytes[9..9 + name_len].copy_from_slice(&self.name.as_bytes()[..name_len]);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<Self> {
+        let player_id = u64::from_le_bytes(bytes[0..8].try_into().ok()?);
+        let name_len = bytes[8] as usize;
+        if name_len > MAX_PLAYER_NAME_BYTES {
+            return None;
+        }
+
+        let name = std::str::from_utf8(&bytes[9..9 + name_len]).ok()?;
+        Some(Self {
+            player_id,
+            name: name.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerToClientMessage {
+    /// Sent first thing on connect, the client disconnects if it can't play with this server.
+    Handshake(Handshake),
+    PlayerJoined(PlayerInfo),
+    PlayerLeft {
+        id: u64,
+    },
+
+    /// A player changed their name or cosmetics.
+    PlayerUpdated(PlayerInfo),
+    SnapshotDiff(SnapshotDiff),
+    /// The configs that affect gameplay. Sent on connect and whenever they change on the server,
+    /// clients predict with these rather than their own files.
+    GameplayConfig {
+        tick_rate: f64,
+
+        /// How many snapshots the server encodes against, clients keep as many of the ones they decoded.
+        snapshot_history: u32,
+        character: CharacterConfig,
+        weapons: WeaponConfig,
+    },
+    LoadLevel {
+        name: String,
+
+        /// The server's copy, a client with a different one can't play on it.
+        hash: u64,
+    },
+    SpawnCharacter(CharacterSpawnEvent),
+    DespawnCharacter(CharacterDespawnEvent),
+    WeaponFired(WeaponFiredEvent),
+    CharacterDied(CharacterDeathEvent),
+
+    /// The answer to a ping, sent straight back.
+    Pong {
+        /// Echoed from the ping.
+        client_time: f64,
+
+        /// The id of the newest snapshot taken.
+        server_tick: u64,
+
+        /// Seconds since the first snapshot, in the same scale as snapshot ids times the tick length.
+        server_time: f64,
+
+        /// How many of the client's inputs are waiting to be consumed.
+        buffered_inputs: u32,
+    },
+
+    /// Sent right before the server disconnects the client.
+    Kicked {
+        reason: String,
+    },
+
+    /// A line of chat, from a player or the server itself.
+    Chat(ChatLine),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientToServerMessage {
+    /// Sent first thing on connect, the server kicks clients it can't play with.
+    Handshake(Handshake),
+
+    /// The newest inputs the server hasn't acked yet, oldest first.
+    /// Sending them more than once means a lost packet doesn't lose an input.
+    PlayerInputs(Vec<PlayerInput>),
+
+    /// Sent every so often to measure round trip time and the server's clock.
+    /// `client_time` is the client's real time, it's echoed back in the pong.
+    Ping {
+        client_time: f64,
+    },
+
+    /// Sent when connecting and whenever the player changes it.
+    SetProfile(PlayerProfile),
+
+    /// Something the player typed into chat. The server checks it before passing it on.
+    Chat {
+        channel: ChatChannel,
+        text: String,
+    },
+}
+
+impl ServerToClientMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            ServerToClientMessage::SnapshotDiff(snapshot_diff) => {
+                let mut writer = BitWriter::new();
+                writer.write_bits(PACKED_MESSAGE as u64, 8);
+                packed::pack_snapshot_diff(&mut writer, snapshot_diff);
+                Ok(writer.into_bytes())
+            }
+            ServerToClientMessage::Handshake(handshake) => handshake.to_bytes(),
+            ServerToClientMessage::Kicked { reason } => {
+                let mut bytes = vec![KICKED_MESSAGE];
+                bincode::serialize_into(&mut bytes, reason)?;
+                Ok(bytes)
+            }
+            _ => to_bincode(self),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        match bytes.first() {
+            Some(&PACKED_MESSAGE) => {
+                let mut reader = BitReader::new(&bytes[1..]);
+                Ok(ServerToClientMessage::SnapshotDiff(packed::unpack_snapshot_diff(&mut reader)?))
+            }
+            Some(&HANDSHAKE_MESSAGE) => Ok(ServerToClientMessage::Handshake(Handshake::from_bytes(&bytes[1..])?)),
+            Some(&KICKED_MESSAGE) => Ok(ServerToClientMessage::Kicked {
+                reason: bincode::deserialize(&bytes[1..])?,
+            }),
+            _ => from_bincode(bytes),
+        }
+    }
+}
+
+impl ClientToServerMessage {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            ClientToServerMessage::PlayerInputs(inputs) => {
+                let mut writer = BitWriter::new();
+                writer.write_bits(PACKED_MESSAGE as u64, 8);
+                packed::pack_player_inputs(&mut writer, inputs);
+                Ok(writer.into_bytes())
+            }
+            ClientToServerMessage::Handshake(handshake) => handshake.to_bytes(),
+            _ => to_bincode(self),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        match bytes.first() {
+            Some(&PACKED_MESSAGE) => {
+                let mut reader = BitReader::new(&bytes[1..]);
+                Ok(ClientToServerMessage::PlayerInputs(packed::unpack_player_inputs(&mut reader)?))
+            }
+            Some(&HANDSHAKE_MESSAGE) => Ok(ClientToServerMessage::Handshake(Handshake::from_bytes(&bytes[1..])?)),
+            _ => from_bincode(bytes),
+        }
+    }
+}
+
+fn to_bincode<T: Serialize>(message: &T) -> Result<Vec<u8>, ProtocolError> {
+    let mut bytes = vec![BINCODE_MESSAGE];
+    bincode::serialize_into(&mut bytes, message)?;
+    Ok(bytes)
+}
+
+fn from_bincode<T: for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<T, ProtocolError> {
+    match bytes.split_first() {
+        Some((&BINCODE_MESSAGE, rest)) => Ok(bincode::deserialize(rest)?),
+        Some((format, _)) => Err(ProtocolError::UnknownFormat(*format)),
+        None => Err(ProtocolError::Empty),
+    }
+}
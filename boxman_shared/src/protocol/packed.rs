@@ -0,0 +1,303 @@
+use bevy::prelude::*;
+
+use crate::{
+    character::{CharacterJump, PlayerInput},
+    replication::{ComponentSnapshot, EntitySnapshotDiff, NetworkId},
+    snapshot::{CharacterSnapshot, CharacterSnapshotDiff, SnapshotDiff, HEALTH_PRECISION},
+};
+
+use super::bits::{BitReader, BitWriter, PackError};
+
+/// Bits for the yaw of an input, about 0.005 degrees per step.
+pub const INPUT_YAW_BITS: u32 = 16;
+
+/// Bits for the direction an input moves in.
+pub const INPUT_DIRECTION_BITS: u32 = 12;
+
+/// Most inputs a single packet may carry, anything claiming more is rejected.
+pub const MAX_PACKED_INPUTS: u64 = 64;
+
+/// Most inputs a client sends in one packet, whatever its config says. The server counts more as a violation.
+pub const MAX_INPUTS_PER_PACKET: usize = 16;
+
+/// Quantizes an input the same way packing does, so the client predicts with exactly what the server gets.
+pub fn quantize_player_input(input: &mut PlayerInput) {
+    let mut writer = BitWriter::new();
+    write_input_state(&mut writer, input);
+    let bytes = writer.into_bytes();
+    let mut reader = BitReader::new(&bytes);
+    if let Ok((yaw, wish_dir)) = read_input_state(&mut reader) {
+        input.yaw = yaw;
+        input.wish_dir = wish_dir;
+    }
+}
+
+/// Yaw and movement direction. `wish_dir` is either zero or normalized, only its direction is sent.
+fn write_input_state(writer: &mut BitWriter, input: &PlayerInput) {
+    writer.write_angle(input.yaw, INPUT_YAW_BITS);
+    let moving = input.wish_dir != Vec2::ZERO;
+    writer.write_bool(moving);
+    if moving {
+        writer.write_unit_vec2(input.wish_dir, INPUT_DIRECTION_BITS);
+    }
+}
+
+fn read_input_state(reader: &mut BitReader) -> Result<(f32, Vec2), PackError> {
+    let yaw = reader.read_angle(INPUT_YAW_BITS)?;
+    let wish_dir = if reader.read_bool()? {
+        reader.read_unit_vec2(INPUT_DIRECTION_BITS)?
+    } else {
+        Vec2::ZERO
+    };
+    Ok((yaw, wish_dir))
+}
+
+/// Inputs are sent a few at a time with consecutive ids, so after the first only the step to the next id is written.
+pub fn pack_player_inputs(writer: &mut BitWriter, inputs: &[PlayerInput]) {
+    writer.write_var_u64(inputs.len() as u64);
+
+    let mut previous_id = None;
+    for input in inputs {
+        match previous_id {
+            Some(previous_id) => writer.write_var_u64(input.id.wrapping_sub(previous_id) as u64),
+            None => writer.write_var_u64(input.id as u64),
+        }
+        previous_id = Some(input.id);
+
+        writer.write_bool(input.snapshot_id.is_some());
+        if let Some(snapshot_id) = input.snapshot_id {
+            writer.write_var_u64(snapshot_id);
+        }
+
+        // The view is usually a few snapshots behind the newest one.
+        writer.write_bool(input.view_snapshot_id.is_some());
+        if let Some(view_snapshot_id) = input.view_snapshot_id {
+            match input.snapshot_id {
+                Some(snapshot_id) => writer.write_var_i64(view_snapshot_id.wrapping_sub(snapshot_id) as i64),
+                None => writer.write_var_u64(view_snapshot_id),
+            }
+        }
+
+        write_input_state(writer, input);
+        writer.write_bool(input.wish_jump);
+        writer.write_bool(input.wish_fire);
+        writer.write_var_u64(input.active_weapon as u64);
+        writer.write_f32(input.timestamp);
+    }
+}
+
+pub fn unpack_player_inputs(reader: &mut BitReader) -> Result<Vec<PlayerInput>, PackError> {
+    let count = reader.read_var_u64()?;
+    if count > MAX_PACKED_INPUTS {
+        return Err(PackError::Invalid("input count"));
+    }
+
+    let mut inputs: Vec<PlayerInput> = Vec::new();
+    for _ in 0..count {
+        let id = match inputs.last() {
+            Some(previous) => previous.id.wrapping_add(read_u32(reader)?),
+            None => read_u32(reader)?,
+        };
+
+        let snapshot_id = if reader.read_bool()? { Some(reader.read_var_u64()?) } else { None };
+        let view_snapshot_id = if reader.read_bool()? {
+            Some(match snapshot_id {
+                Some(snapshot_id) => snapshot_id.wrapping_add(reader.read_var_i64()? as u64),
+                None => reader.read_var_u64()?,
+            })
+        } else {
+            None
+        };
+
+        let (yaw, wish_dir) = read_input_state(reader)?;
+        let wish_jump = reader.read_bool()?;
+        let wish_fire = reader.read_bool()?;
+        let active_weapon = read_u32(reader)?;
+        let timestamp = reader.read_f32()?;
+
+        inputs.push(PlayerInput {
+            id,
+            snapshot_id,
+            view_snapshot_id,
+            yaw,
+            wish_dir,
+            wish_jump,
+            wish_fire,
+            active_weapon,
+            timestamp,
+            send_count: 0,
+            post_move_velocity: Vec3::ZERO,
+            post_move_position: Vec3::ZERO,
+            post_move_grounded: false,
+            post_move_jump: CharacterJump::default(),
+        });
+    }
+
+    Ok(inputs)
+}
+
+pub fn pack_snapshot_diff(writer: &mut BitWriter, snapshot_diff: &SnapshotDiff) {
+    writer.write_var_u64(snapshot_diff.id);
+
+    // Baselines are always older, and usually only a round trip behind.
+    writer.write_bool(snapshot_diff.baseline_id.is_some());
+    if let Some(baseline_id) = snapshot_diff.baseline_id {
+        writer.write_var_u64(snapshot_diff.id.wrapping_sub(baseline_id));
+    }
+
+    writer.write_bool(snapshot_diff.acked_input_id.is_some());
+    if let Some(acked_input_id) = snapshot_diff.acked_input_id {
+        writer.write_var_u64(acked_input_id as u64);
+    }
+
+    writer.write_var_u64(snapshot_diff.character_snapshots.len() as u64);
+    for character in snapshot_diff.character_snapshots.iter() {
+        pack_character_diff(writer, character);
+    }
+
+    write_client_ids(writer, &snapshot_diff.removed_client_ids);
+    write_client_ids(writer, &snapshot_diff.stale_client_ids);
+
+    writer.write_var_u64(snapshot_diff.entities.len() as u64);
+    for entity in snapshot_diff.entities.iter() {
+        writer.write_var_u64(entity.id.0 as u64);
+        writer.write_var_u64(entity.changed.len() as u64);
+        for component in entity.changed.iter() {
+            writer.write_var_u64(component.kind as u64);
+            writer.write_bytes(&component.data);
+        }
+        writer.write_var_u64(entity.removed_kinds.len() as u64);
+        for kind in entity.removed_kinds.iter() {
+            writer.write_var_u64(*kind as u64);
+        }
+    }
+
+    writer.write_var_u64(snapshot_diff.removed_entity_ids.len() as u64);
+    for id in snapshot_diff.removed_entity_ids.iter() {
+        writer.write_var_u64(id.0 as u64);
+    }
+}
+
+pub fn unpack_snapshot_diff(reader: &mut BitReader) -> Result<SnapshotDiff, PackError> {
+    let id = reader.read_var_u64()?;
+    let baseline_id = if reader.read_bool()? { Some(id.wrapping_sub(reader.read_var_u64()?)) } else { None };
+    let acked_input_id = if reader.read_bool()? { Some(read_u32(reader)?) } else { None };
+
+    let mut character_snapshots = Vec::new();
+    for _ in 0..reader.read_var_u64()? {
+        character_snapshots.push(unpack_character_diff(reader)?);
+    }
+
+    let removed_client_ids = read_client_ids(reader)?;
+    let stale_client_ids = read_client_ids(reader)?;
+
+    let mut entities = Vec::new();
+    for _ in 0..reader.read_var_u64()? {
+        let id = NetworkId(read_u32(reader)?);
+
+        let mut changed = Vec::new();
+        for _ in 0..reader.read_var_u64()? {
+            let kind = read_u16(reader)?;
+            let data = reader.read_bytes()?;
+            changed.push(ComponentSnapshot { kind, data });
+        }
+
+        let mut removed_kinds = Vec::new();
+        for _ in 0..reader.read_var_u64()? {
+            removed_kinds.push(read_u16(reader)?);
+        }
+
+        entities.push(EntitySnapshotDiff { id, changed, removed_kinds });
+    }
+
+    let mut removed_entity_ids = Vec::new();
+    for _ in 0..reader.read_var_u64()? {
+        removed_entity_ids.push(NetworkId(read_u32(reader)?));
+    }
+
+    Ok(SnapshotDiff {
+        id,
+        baseline_id,
+        character_snapshots,
+        removed_client_ids,
+        acked_input_id,
+        stale_client_ids,
+        entities,
+        removed_entity_ids,
+    })
+}
+
+/// How many bits a character's diff takes up once packed.
+pub fn packed_character_diff_bits(diff: &CharacterSnapshotDiff) -> usize {
+    let mut writer = BitWriter::new();
+    pack_character_diff(&mut writer, diff);
+    writer.bit_len()
+}
+
+/// Client ids are random, so they're written in full. Everything else is already quantized in the snapshot.
+fn pack_character_diff(writer: &mut BitWriter, diff: &CharacterSnapshotDiff) {
+    writer.write_bits(diff.client_id, 64);
+    writer.write_bits(diff.mask as u64, CharacterSnapshotDiff::ALL.count_ones());
+    if diff.has(CharacterSnapshotDiff::POSITION) { write_ivec3(writer, diff.position); }
+    if diff.has(CharacterSnapshotDiff::VELOCITY) { write_ivec3(writer, diff.velocity); }
+    if diff.has(CharacterSnapshotDiff::YAW) { writer.write_bits(diff.yaw as u64, 16); }
+    if diff.has(CharacterSnapshotDiff::PITCH) { writer.write_bits(diff.pitch as u64, 16); }
+    if diff.has(CharacterSnapshotDiff::GROUNDED) { writer.write_bool(diff.grounded); }
+    if diff.has(CharacterSnapshotDiff::HEALTH) { writer.write_var_i64((diff.health / HEALTH_PRECISION) as i64); }
+    if diff.has(CharacterSnapshotDiff::ARMOR) { writer.write_var_i64((diff.armor / HEALTH_PRECISION) as i64); }
+}
+
+fn unpack_character_diff(reader: &mut BitReader) -> Result<CharacterSnapshotDiff, PackError> {
+    let mut diff = CharacterSnapshotDiff {
+        mask: 0,
+        state: CharacterSnapshot::default(),
+    };
+
+    diff.state.client_id = reader.read_bits(64)?;
+    diff.mask = reader.read_bits(CharacterSnapshotDiff::ALL.count_ones())? as u8;
+    if diff.has(CharacterSnapshotDiff::POSITION) { diff.state.position = read_ivec3(reader)?; }
+    if diff.has(CharacterSnapshotDiff::VELOCITY) { diff.state.velocity = read_ivec3(reader)?; }
+    if diff.has(CharacterSnapshotDiff::YAW) { diff.state.yaw = reader.read_bits(16)? as u16; }
+    if diff.has(CharacterSnapshotDiff::PITCH) { diff.state.pitch = reader.read_bits(16)? as u16; }
+    if diff.has(CharacterSnapshotDiff::GROUNDED) { diff.state.grounded = reader.read_bool()?; }
+    if diff.has(CharacterSnapshotDiff::HEALTH) { diff.state.health = reader.read_var_i64()? as f32 * HEALTH_PRECISION; }
+    if diff.has(CharacterSnapshotDiff::ARMOR) { diff.state.armor = reader.read_var_i64()? as f32 * HEALTH_PRECISION; }
+    Ok(diff)
+}
+
+fn write_ivec3(writer: &mut BitWriter, value: IVec3) {
+    writer.write_var_i64(value.x as i64);
+    writer.write_var_i64(value.y as i64);
+    writer.write_var_i64(value.z as i64);
+}
+
+fn read_ivec3(reader: &mut BitReader) -> Result<IVec3, PackError> {
+    Ok(IVec3::new(read_i32(reader)?, read_i32(reader)?, read_i32(reader)?))
+}
+
+fn write_client_ids(writer: &mut BitWriter, client_ids: &[u64]) {
+    writer.write_var_u64(client_ids.len() as u64);
+    for client_id in client_ids {
+        writer.write_bits(*client_id, 64);
+    }
+}
+
+fn read_client_ids(reader: &mut BitReader) -> Result<Vec<u64>, PackError> {
+    let mut client_ids = Vec::new();
+    for _ in 0..reader.read_var_u64()? {
+        client_ids.push(reader.read_bits(64)?);
+    }
+    Ok(client_ids)
+}
+
+fn read_u32(reader: &mut BitReader) -> Result<u32, PackError> {
+    u32::try_from(reader.read_var_u64()?).map_err(|_| PackError::Invalid("u32"))
+}
+
+fn read_u16(reader: &mut BitReader) -> Result<u16, PackError> {
+    u16::try_from(reader.read_var_u64()?).map_err(|_| PackError::Invalid("u16"))
+}
+
+fn read_i32(reader: &mut BitReader) -> Result<i32, PackError> {
+    i32::try_from(reader.read_var_i64()?).map_err(|_| PackError::Invalid("i32"))
+}
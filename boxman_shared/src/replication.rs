@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use bevy::{ecs::system::EntityCommands, prelude::*};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// Identifies a replicated entity on the wire. Assigned by the server, the client maps it to its own entity.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NetworkId(pub u32);
+
+/// Marks an entity for replication. On the server it's given a `NetworkId` and its replicated
+/// components go out in every snapshot, on the client it marks entities spawned from a snapshot.
+#[derive(Component, Debug, Clone, Copy, Default)]
+pub struct Replicated;
+
+/// How to capture, insert and remove one replicated component type.
+pub struct ReplicatedComponent {
+    pub name: &'static str,
+    capture: fn(&World, Entity) -> Option<Vec<u8>>,
+    insert: fn(&mut EntityCommands, &[u8]) -> Result<(), bincode::Error>,
+    remove: fn(&mut EntityCommands),
+}
+
+/// Every component type that's replicated. A component's kind on the wire is its index here,
+/// so the server and client have to register the same components in the same order.
+#[derive(Resource, Default)]
+pub struct ReplicationRegistry {
+    components: Vec<ReplicatedComponent>,
+}
+
+impl ReplicationRegistry {
+    pub fn components(&self) -> &[ReplicatedComponent] {
+        &self.components
+    }
+
+    /// The replicated components `entity` has, serialized and ordered by kind.
+    pub fn capture(&self, world: &World, entity: Entity) -> Vec<ComponentSnapshot> {
+        self.components.iter()
+            .enumerate()
+            .filter_map(|(kind, component)| (component.capture)(world, entity)
+                .map(|data| ComponentSnapshot { kind: kind as u16, data }))
+            .collect()
+    }
+
+    /// Inserts a component captured on the server, replacing the current value.
+    pub fn insert(&self, entity: &mut EntityCommands, component: &ComponentSnapshot) -> Result<(), String> {
+        let replicated = self.components.get(component.kind as usize)
+            .ok_or_else(|| format!("Unknown replicated component kind {}", component.kind))?;
+        (replicated.insert)(entity, &component.data)
+            .map_err(|e| format!("Failed to deserialize {}: {}", replicated.name, e))
+    }
+
+    pub fn remove(&self, entity: &mut EntityCommands, kind: u16) {
+        if let Some(replicated) = self.components.get(kind as usize) {
+            (replicated.remove)(entity);
+        }
+    }
+}
+
+pub trait ReplicationAppExt {
+    /// Replicates `C` on every entity marked `Replicated`. Register the same components in the same order on both sides.
+    fn replicate<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self;
+}
+
+impl ReplicationAppExt for App {
+    fn replicate<C: Component + Serialize + DeserializeOwned>(&mut self) -> &mut Self {
+        self.world_mut().get_resource_or_insert_with(ReplicationRegistry::default).components.push(ReplicatedComponent {
+            name: std::any::type_name::<C>(),
+            capture: |world, entity| world.get::<C>(entity).and_then(|component| bincode::serialize(component).ok()),
+            insert: |entity, data| {
+                entity.insert(bincode::deserialize::<C>(data)?);
+                Ok(())
+            },
+            remove: |entity| {
+                entity.remove::<C>();
+            },
+        });
+        self
+    }
+}
+
+/// Client side, which local entity each replicated entity was spawned as.
+#[derive(Resource, Default)]
+pub struct NetworkEntityMap {
+    entities: HashMap<NetworkId, Entity>,
+}
+
+impl NetworkEntityMap {
+    pub fn get(&self, id: NetworkId) -> Option<Entity> {
+        self.entities.get(&id).copied()
+    }
+
+    pub fn insert(&mut self, id: NetworkId, entity: Entity) {
+        self.entities.insert(id, entity);
+    }
+
+    pub fn remove(&mut self, id: NetworkId) -> Option<Entity> {
+        self.entities.remove(&id)
+    }
+}
+
+/// Server side, hands out network ids. They're never reused, so a stale id can't point at a new entity.
+#[derive(Resource, Default)]
+pub struct NetworkIdAllocator {
+    next_id: u32,
+}
+
+impl NetworkIdAllocator {
+    pub fn allocate(&mut self) -> NetworkId {
+        let id = NetworkId(self.next_id);
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+}
+
+/// One replicated component, serialized.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ComponentSnapshot {
+    /// The component's index in the `ReplicationRegistry`.
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+/// The replicated state of an entity, its components ordered by kind.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntitySnapshot {
+    pub id: NetworkId,
+    pub components: Vec<ComponentSnapshot>,
+}
+
+impl EntitySnapshot {
+    /// The components that changed or were added since the baseline, and the kinds that were removed.
+    /// None if nothing changed.
+    pub fn diff(&self, baseline: Option<&Self>) -> Option<EntitySnapshotDiff> {
+        let baseline_components = baseline.map_or(&[][..], |baseline| &baseline.components[..]);
+
+        let changed: Vec<ComponentSnapshot> = self.components.iter()
+            .filter(|component| !baseline_components.contains(component))
+            .cloned()
+            .collect();
+
+        let removed_kinds: Vec<u16> = baseline_components.iter()
+            .filter(|b| !self.components.iter().any(|c| c.kind == b.kind))
+            .map(|b| b.kind)
+            .collect();
+
+        if baseline.is_some() && changed.is_empty() && removed_kinds.is_empty() {
+            return None;
+        }
+
+        Some(EntitySnapshotDiff {
+            id: self.id,
+            changed,
+            removed_kinds,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntitySnapshotDiff {
+    pub id: NetworkId,
+    pub changed: Vec<ComponentSnapshot>,
+    pub removed_kinds: Vec<u16>,
+}
+
+impl EntitySnapshotDiff {
+    pub fn apply(&self, baseline: Option<&EntitySnapshot>) -> EntitySnapshot {
+        let mut components: Vec<ComponentSnapshot> = baseline
+            .map(|baseline| baseline.components.iter()
+                .filter(|c| !self.removed_kinds.contains(&c.kind) && !self.changed.iter().any(|changed| changed.kind == c.kind))
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+
+        components.extend(self.changed.iter().cloned());
+        components.sort_by_key(|component| component.kind);
+
+        EntitySnapshot {
+            id: self.id,
+            components,
+        }
+    }
+}
+
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ReplicationRegistry>();
+        app.init_resource::<NetworkEntityMap>();
+        app.init_resource::<NetworkIdAllocator>();
+    }
+}
@@ -0,0 +1,53 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// What a player picks for themselves. Clients send it to the server when they connect and whenever it changes.
+#[derive(Resource, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerProfile {
+    pub name: String,
+    pub color: Color,
+}
+
+impl Default for PlayerProfile {
+    fn default() -> Self {
+        Self {
+            name: "Player".to_string(),
+            color: DEFAULT_PLAYER_COLOR,
+        }
+    }
+}
+
+pub const DEFAULT_PLAYER_COLOR: Color = Color::srgb(0.9, 0.1, 0.1);
+
+/// A player as everyone else sees them. The name has been validated and made unique by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlayerInfo {
+    pub id: u64,
+    pub name: String,
+    pub color: Color,
+}
+
+/// Everyone connected to the server, in the order they joined. Replicated from the server on clients.
+#[derive(Resource, Default, Debug)]
+pub struct Roster {
+    pub players: Vec<PlayerInfo>,
+}
+
+impl Roster {
+    pub fn get(&self, id: u64) -> Option<&PlayerInfo> {
+        self.players.iter().find(|player| player.id == id)
+    }
+
+    /// Adds the player, or replaces them if they're already here.
+    pub fn insert(&mut self, info: PlayerInfo) {
+        match self.players.iter_mut().find(|player| player.id == info.id) {
+            Some(player) => *player = info,
+            None => self.players.push(info),
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<PlayerInfo> {
+        let index = self.players.iter().position(|player| player.id == id)?;
+        Some(self.players.remove(index))
+    }
+}
@@ -1,123 +1,361 @@
+use std::f32::consts::TAU;
+
 use bevy::prelude::*;
+use serde::{de::{self, SeqAccess, Visitor}, ser::SerializeTuple, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::replication::{EntitySnapshot, EntitySnapshotDiff, NetworkId};
+
+/// World units per quantized position step.
+pub const POSITION_PRECISION: f32 = 1.0 / 512.0;
+
+/// World units per second per quantized velocity step.
+pub const VELOCITY_PRECISION: f32 = 1.0 / 256.0;
+
+/// Health and armor per quantized step.
+pub const HEALTH_PRECISION: f32 = 1.0 / 64.0;
+
+pub fn quantize_vec3(value: Vec3, precision: f32) -> IVec3 {
+    (value / precision).round().as_ivec3()
+}
+
+pub fn dequantize_vec3(value: IVec3, precision: f32) -> Vec3 {
+    value.as_vec3() * precision
+}
+
+/// Rounded up, so a character that's barely alive isn't sent as dead.
+pub fn quantize_health(value: f32) -> f32 {
+    (value / HEALTH_PRECISION).ceil() * HEALTH_PRECISION
+}
 
-#[derive(Debug)]
+/// Maps an angle (in radians) onto the full range of a u16.
+pub fn quantize_angle(angle: f32) -> u16 {
+    ((angle.rem_euclid(TAU) / TAU) * 65536.0).round() as u32 as u16
+}
+
+/// Gives back an angle in the range [-PI, PI).
+pub fn dequantize_angle(angle: u16) -> f32 {
+    let angle = angle as f32 / 65536.0 * TAU;
+    if angle >= std::f32::consts::PI {
+        angle - TAU
+    } else {
+        angle
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Snapshot {
     pub id: u64,
     pub character_snapshots: Vec<CharacterSnapshot>,
+
+    /// Every other replicated entity, see `replication`.
+    pub entities: Vec<EntitySnapshot>,
 }
 
 impl Snapshot {
-    pub fn diff(&self, other: &Self) -> SnapshotDiff {
+    /// Encodes this snapshot against a baseline the client is known to have.
+    /// Without a baseline every field of every character is sent.
+    pub fn diff(&self, baseline: Option<&Self>) -> SnapshotDiff {
+        let mut character_snapshots = Vec::new();
+        for character in self.character_snapshots.iter() {
+            let baseline_character = baseline
+                .and_then(|baseline| baseline.character_snapshots.iter().find(|c| c.client_id == character.client_id));
+
+            if let Some(diff) = character.diff(baseline_character) {
+                character_snapshots.push(diff);
+            }
+        }
+
+        let removed_client_ids = baseline
+            .map(|baseline| baseline.character_snapshots.iter()
+                .filter(|b| !self.character_snapshots.iter().any(|c| c.client_id == b.client_id))
+                .map(|b| b.client_id)
+                .collect())
+            .unwrap_or_default();
+
+        let mut entities = Vec::new();
+        for entity in self.entities.iter() {
+            let baseline_entity = baseline
+                .and_then(|baseline| baseline.entities.iter().find(|e| e.id == entity.id));
+
+            if let Some(diff) = entity.diff(baseline_entity) {
+                entities.push(diff);
+            }
+        }
+
+        let removed_entity_ids = baseline
+            .map(|baseline| baseline.entities.iter()
+                .filter(|b| !self.entities.iter().any(|e| e.id == b.id))
+                .map(|b| b.id)
+                .collect())
+            .unwrap_or_default();
+
         SnapshotDiff {
             id: self.id,
+            baseline_id: baseline.map(|baseline| baseline.id),
             acked_input_id: None, // Should be filled in after calling this function.
-            character_snapshots: {
-                let mut out = Vec::new();
-                for controller in self.character_snapshots.iter() {
-                    let other_controller = other.character_snapshots.iter().find(|c| c.client_id == controller.client_id);
-                    if let Some(other_controller) = other_controller {
-                        if let Some(diff) = controller.diff(other_controller) {
-                            out.push(diff);
-                        }
-                    } else {
-                        out.push(controller.into());
-                    }
-                }
-                out
-            },
+            character_snapshots,
+            removed_client_ids,
+            stale_client_ids: Vec::new(),
+            entities,
+            removed_entity_ids,
         }
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SnapshotDiff {
     pub id: u64,
+
+    /// The snapshot this was encoded against, None if it holds the full state.
+    pub baseline_id: Option<u64>,
+
     pub character_snapshots: Vec<CharacterSnapshotDiff>,
+
+    /// Characters that are in the baseline but not in this snapshot.
+    pub removed_client_ids: Vec<u64>,
+
+    /// Characters whose state was carried over from the baseline because there wasn't room to update them.
+    /// They're still there, but their state isn't from this snapshot's tick.
+    pub stale_client_ids: Vec<u64>,
+
+    /// Replicated entities that changed since the baseline.
+    pub entities: Vec<EntitySnapshotDiff>,
+
+    /// Replicated entities that are in the baseline but not in this snapshot.
+    pub removed_entity_ids: Vec<NetworkId>,
+
     pub acked_input_id: Option<u32>,
 }
 
-impl From<&Snapshot> for SnapshotDiff {
-    fn from(snapshot: &Snapshot) -> Self {
-        SnapshotDiff {
-            id: snapshot.id,
-            acked_input_id: None,
-            character_snapshots: snapshot.character_snapshots.iter().map(|c| c.into()).collect(),
+impl SnapshotDiff {
+    /// Rebuilds the full snapshot. Returns None if this needs a baseline and we weren't given the right one.
+    pub fn apply(&self, baseline: Option<&Snapshot>) -> Option<Snapshot> {
+        let baseline = match (self.baseline_id, baseline) {
+            (None, _) => None,
+            (Some(baseline_id), Some(baseline)) if baseline.id == baseline_id => Some(baseline),
+            _ => return None,
+        };
+
+        let mut character_snapshots: Vec<CharacterSnapshot> = baseline
+            .map(|baseline| baseline.character_snapshots.iter()
+                .filter(|c| !self.removed_client_ids.contains(&c.client_id))
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+
+        for diff in self.character_snapshots.iter() {
+            if let Some(existing) = character_snapshots.iter_mut().find(|c| c.client_id == diff.client_id) {
+                *existing = diff.apply(Some(existing));
+            } else {
+                character_snapshots.push(diff.apply(None));
+            }
+        }
+
+        let mut entities: Vec<EntitySnapshot> = baseline
+            .map(|baseline| baseline.entities.iter()
+                .filter(|e| !self.removed_entity_ids.contains(&e.id))
+                .cloned()
+                .collect())
+            .unwrap_or_default();
+
+        for diff in self.entities.iter() {
+            if let Some(existing) = entities.iter_mut().find(|e| e.id == diff.id) {
+                *existing = diff.apply(Some(existing));
+            } else {
+                entities.push(diff.apply(None));
+            }
         }
+
+        Some(Snapshot {
+            id: self.id,
+            character_snapshots,
+            entities,
+        })
     }
 }
 
-#[derive(Debug)]
+/// The replicated state of a character, already quantized so that
+/// the server and client compare and rebuild exactly the same values.
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct CharacterSnapshot {
     pub client_id: u64,
-    pub translation: Vec3,
-    pub velocity: Vec3,
-    pub yaw: f32,
-    pub pitch: f32,
+    pub position: IVec3,
+    pub velocity: IVec3,
+    pub yaw: u16,
+    pub pitch: u16,
     pub grounded: bool,
+    pub health: f32,
+    pub armor: f32,
 }
 
 impl CharacterSnapshot {
-    pub fn diff(&self, other: &Self) -> Option<CharacterSnapshotDiff> {
-        let out = CharacterSnapshotDiff {
-            client_id: self.client_id,
-            position: if self.translation != other.translation {
-                Some(self.translation)
-            } else {
-                None
-            },
-            velocity: if self.velocity != other.velocity {
-                Some(self.velocity)
-            } else {
-                None
-            },
-            yaw: if self.yaw != other.yaw {
-                Some(self.yaw)
-            } else {
-                None
-            },
-            pitch: if self.pitch != other.pitch {
-                Some(self.pitch)
-            } else {
-                None
-            },
-            grounded: if self.grounded != other.grounded {
-                Some(self.grounded)
-            } else {
-                None
-            },
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        client_id: u64,
+        translation: Vec3,
+        velocity: Vec3,
+        yaw: f32,
+        pitch: f32,
+        grounded: bool,
+        health: f32,
+        armor: f32,
+    ) -> Self {
+        Self {
+            client_id,
+            position: quantize_vec3(translation, POSITION_PRECISION),
+            velocity: quantize_vec3(velocity, VELOCITY_PRECISION),
+            yaw: quantize_angle(yaw),
+            pitch: quantize_angle(pitch),
+            grounded,
+            health: quantize_health(health),
+            armor: quantize_health(armor),
+        }
+    }
+
+    pub fn translation(&self) -> Vec3 {
+        dequantize_vec3(self.position, POSITION_PRECISION)
+    }
+
+    pub fn velocity(&self) -> Vec3 {
+        dequantize_vec3(self.velocity, VELOCITY_PRECISION)
+    }
+
+    pub fn yaw(&self) -> f32 {
+        dequantize_angle(self.yaw)
+    }
+
+    pub fn pitch(&self) -> f32 {
+        dequantize_angle(self.pitch)
+    }
+
+    /// The fields that changed since the baseline, or None if nothing did.
+    pub fn diff(&self, baseline: Option<&Self>) -> Option<CharacterSnapshotDiff> {
+        let mask = match baseline {
+            Some(baseline) => {
+                let mut mask = 0;
+                if self.position != baseline.position { mask |= CharacterSnapshotDiff::POSITION; }
+                if self.velocity != baseline.velocity { mask |= CharacterSnapshotDiff::VELOCITY; }
+                if self.yaw != baseline.yaw { mask |= CharacterSnapshotDiff::YAW; }
+                if self.pitch != baseline.pitch { mask |= CharacterSnapshotDiff::PITCH; }
+                if self.grounded != baseline.grounded { mask |= CharacterSnapshotDiff::GROUNDED; }
+                if self.health != baseline.health { mask |= CharacterSnapshotDiff::HEALTH; }
+                if self.armor != baseline.armor { mask |= CharacterSnapshotDiff::ARMOR; }
+                mask
+            }
+            None => CharacterSnapshotDiff::ALL,
         };
 
-        if out.position.is_some() 
-            || out.velocity.is_some() 
-            || out.yaw.is_some() 
-            || out.pitch.is_some() 
-            || out.grounded.is_some() {
-            Some(out)
-        } else {
-            None
+        if mask == 0 {
+            return None;
         }
+
+        Some(CharacterSnapshotDiff {
+            mask,
+            state: self.clone(),
+        })
     }
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+/// A character's changed fields. Only the fields set in `mask` are sent over the network,
+/// the others are taken from the baseline when applying it.
+#[derive(Debug, Clone)]
 pub struct CharacterSnapshotDiff {
-    pub client_id: u64,
-    pub position: Option<Vec3>,
-    pub velocity: Option<Vec3>,
-    pub yaw: Option<f32>,
-    pub pitch: Option<f32>,
-    pub grounded: Option<bool>,
+    pub mask: u8,
+    pub state: CharacterSnapshot,
 }
 
-impl From<&CharacterSnapshot> for CharacterSnapshotDiff     {
-    fn from(snapshot: &CharacterSnapshot) -> Self {
-        Self {
-            client_id: snapshot.client_id,
-            position: Some(snapshot.translation),
-            velocity: Some(snapshot.velocity),
-            yaw: Some(snapshot.yaw),
-            pitch: Some(snapshot.pitch),
-            grounded: Some(snapshot.grounded),
+impl std::ops::Deref for CharacterSnapshotDiff {
+    type Target = CharacterSnapshot;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl CharacterSnapshotDiff {
+    pub const POSITION: u8 = 1 << 0;
+    pub const VELOCITY: u8 = 1 << 1;
+    pub const YAW: u8 = 1 << 2;
+    pub const PITCH: u8 = 1 << 3;
+    pub const GROUNDED: u8 = 1 << 4;
+    pub const HEALTH: u8 = 1 << 5;
+    pub const ARMOR: u8 = 1 << 6;
+    pub const ALL: u8 = (1 << 7) - 1;
+
+    pub fn has(&self, field: u8) -> bool {
+        self.mask & field != 0
+    }
+
+    pub fn apply(&self, baseline: Option<&CharacterSnapshot>) -> CharacterSnapshot {
+        let default = CharacterSnapshot::default();
+        let baseline = baseline.unwrap_or(&default);
+        CharacterSnapshot {
+            client_id: self.client_id,
+            position: if self.has(Self::POSITION) { self.position } else { baseline.position },
+            velocity: if self.has(Self::VELOCITY) { self.velocity } else { baseline.velocity },
+            yaw: if self.has(Self::YAW) { self.yaw } else { baseline.yaw },
+            pitch: if self.has(Self::PITCH) { self.pitch } else { baseline.pitch },
+            grounded: if self.has(Self::GROUNDED) { self.grounded } else { baseline.grounded },
+            health: if self.has(Self::HEALTH) { self.health } else { baseline.health },
+            armor: if self.has(Self::ARMOR) { self.armor } else { baseline.armor },
         }
     }
 }
+
+impl Serialize for CharacterSnapshotDiff {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(2 + self.mask.count_ones() as usize)?;
+        tuple.serialize_element(&self.client_id)?;
+        tuple.serialize_element(&self.mask)?;
+        if self.has(Self::POSITION) { tuple.serialize_element(&self.position)?; }
+        if self.has(Self::VELOCITY) { tuple.serialize_element(&self.velocity)?; }
+        if self.has(Self::YAW) { tuple.serialize_element(&self.yaw)?; }
+        if self.has(Self::PITCH) { tuple.serialize_element(&self.pitch)?; }
+        if self.has(Self::GROUNDED) { tuple.serialize_element(&self.grounded)?; }
+        if self.has(Self::HEALTH) { tuple.serialize_element(&self.health)?; }
+        if self.has(Self::ARMOR) { tuple.serialize_element(&self.armor)?; }
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CharacterSnapshotDiff {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DiffVisitor;
+
+        impl<'de> Visitor<'de> for DiffVisitor {
+            type Value = CharacterSnapshotDiff;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a character snapshot diff")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let mut index = 0;
+                let mut diff = CharacterSnapshotDiff {
+                    mask: 0,
+                    state: CharacterSnapshot::default(),
+                };
+
+                diff.state.client_id = next_field(&mut seq, &mut index)?;
+                diff.mask = next_field(&mut seq, &mut index)?;
+                if diff.has(CharacterSnapshotDiff::POSITION) { diff.state.position = next_field(&mut seq, &mut index)?; }
+                if diff.has(CharacterSnapshotDiff::VELOCITY) { diff.state.velocity = next_field(&mut seq, &mut index)?; }
+                if diff.has(CharacterSnapshotDiff::YAW) { diff.state.yaw = next_field(&mut seq, &mut index)?; }
+                if diff.has(CharacterSnapshotDiff::PITCH) { diff.state.pitch = next_field(&mut seq, &mut index)?; }
+                if diff.has(CharacterSnapshotDiff::GROUNDED) { diff.state.grounded = next_field(&mut seq, &mut index)?; }
+                if diff.has(CharacterSnapshotDiff::HEALTH) { diff.state.health = next_field(&mut seq, &mut index)?; }
+                if diff.has(CharacterSnapshotDiff::ARMOR) { diff.state.armor = next_field(&mut seq, &mut index)?; }
+                Ok(diff)
+            }
+        }
+
+        fn next_field<'de, T: Deserialize<'de>, A: SeqAccess<'de>>(seq: &mut A, index: &mut usize) -> Result<T, A::Error> {
+            let value = seq.next_element()?
+                .ok_or_else(|| de::Error::invalid_length(*index, &"a character snapshot diff"));
+            *index += 1;
+            value
+        }
+
+        deserializer.deserialize_tuple(2 + CharacterSnapshotDiff::ALL.count_ones() as usize, DiffVisitor)
+    }
+}
@@ -10,4 +10,18 @@ pub struct GameClient;
 pub struct ServerPort(pub u16);
 
 #[derive(Resource)]
-pub struct ServerIp(pub String);
\ No newline at end of file
+pub struct ServerIp(pub String);
+
+/// The name of the level the server loads when it starts.
+#[derive(Resource)]
+pub struct ServerLevel(pub String);
+
+/// The port of the connect token service. The server runs it there,
+/// and clients with this set ask it for a token instead of connecting unsecured.
+#[derive(Resource)]
+pub struct AuthPort(pub u16);
+
+/// Whoever inserted this passes the packets of the app's `RenetServer` or `RenetClient` along themselves,
+/// e.g. tests running the server and clients in one process. No socket is opened.
+#[derive(Resource)]
+pub struct InMemoryTransport;
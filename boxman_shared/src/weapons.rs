@@ -0,0 +1,186 @@
+use avian3d::prelude::*;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::character::PlayerInput;
+
+pub struct WeaponsPlugin;
+
+impl Plugin for WeaponsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<WeaponFiredEvent>();
+        app.add_event::<WeaponHitEvent>();
+    }
+}
+
+#[derive(Asset, TypePath, Debug, Clone, Resource, Serialize, Deserialize)]
+pub struct WeaponConfig {
+    /// Indexed by `PlayerInput::active_weapon`.
+    pub weapons: Vec<Weapon>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Weapon {
+    pub name: String,
+
+    /// Shots per second.
+    pub fire_rate: f32,
+
+    pub damage: f32,
+
+    /// Half angle of the cone shots are spread within, in degrees.
+    pub spread: f32,
+
+    /// How far a shot travels (in world units) before it stops.
+    pub range: f32,
+}
+
+impl Default for WeaponConfig {
+    fn default() -> Self {
+        Self {
+            weapons: vec![
+                Weapon {
+                    name: "Pistol".to_string(),
+                    fire_rate: 4.0,
+                    damage: 25.0,
+                    spread: 1.0,
+                    range: 50.0,
+                },
+            ],
+        }
+    }
+}
+
+impl WeaponConfig {
+    pub fn weapon(&self, index: u32) -> Option<&Weapon> {
+        self.weapons.get(index as usize)
+    }
+}
+
+#[derive(Component, Default)]
+pub struct WeaponState {
+    /// Seconds until the next shot is allowed.
+    pub cooldown: f32,
+}
+
+impl WeaponState {
+    /// Ticks the cooldown and returns the weapon that fired, if any.
+    /// Only `is_new_input` inputs can fire, so an input being reused doesn't shoot again.
+    pub fn update<'a>(
+        &mut self,
+        input: &PlayerInput,
+        weapon_config: &'a WeaponConfig,
+        delta_secs: f32,
+        is_new_input: bool,
+    ) -> Option<&'a Weapon> {
+        self.cooldown = (self.cooldown - delta_secs).max(0.0);
+
+        if !input.wish_fire || !is_new_input || self.cooldown > 0.0 {
+            return None;
+        }
+
+        let weapon = weapon_config.weapon(input.active_weapon)?;
+        self.cooldown = 1.0 / weapon.fire_rate.max(f32::EPSILON);
+        Some(weapon)
+    }
+}
+
+/// Sent whenever a shot is fired, predicted locally for our own character and received
+/// from the server for everyone else. Only used for effects.
+#[derive(Event, Debug, Clone, Serialize, Deserialize)]
+pub struct WeaponFiredEvent {
+    pub client_id: u64,
+    pub weapon: u32,
+    pub origin: Vec3,
+    pub end: Vec3,
+}
+
+/// Sent on the server when a shot hits a character.
+#[derive(Event, Debug, Clone)]
+pub struct WeaponHitEvent {
+    pub shooter_id: u64,
+    pub target_id: u64,
+    pub damage: f32,
+    pub point: Vec3,
+}
+
+/// The direction a shot travels in. The spread is derived from the input id, so the client's
+/// prediction and the server agree on where a shot went without sending it.
+pub fn shot_direction(yaw: f32, spread_degrees: f32, seed: u32) -> Vec3 {
+    let forward = Quat::from_rotation_y(yaw) * Vec3::NEG_Z;
+    if spread_degrees <= 0.0 {
+        return forward;
+    }
+
+    let angle = hash_to_unit(seed) * spread_degrees.to_radians();
+    let roll = hash_to_unit(seed.wrapping_add(0x9e37_79b9)) * std::f32::consts::TAU;
+    let deviation = Quat::from_axis_angle(forward, roll) * Quat::from_axis_angle(Vec3::Y, angle);
+    (deviation * forward).normalize()
+}
+
+/// Cheap integer hash mapped to [0.0, 1.0).
+fn hash_to_unit(mut x: u32) -> f32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    (x >> 8) as f32 / (1u32 << 24) as f32
+}
+
+/// Distance along the ray to the first piece of level geometry, capped at `range`.
+pub fn trace_world(spatial_query: &SpatialQuery, origin: Vec3, direction: Vec3, range: f32) -> f32 {
+    let Ok(direction) = Dir3::new(direction) else {
+        return range;
+    };
+
+    spatial_query
+        .cast_ray(origin, direction, range, true, &SpatialQueryFilter::default())
+        .map(|hit| hit.distance)
+        .unwrap_or(range)
+}
+
+/// Distance along the ray to a vertical cylinder centered at `center`, the same shape used for
+/// the moveable simulation's collision.
+pub fn ray_cylinder_distance(
+    origin: Vec3,
+    direction: Vec3,
+    center: Vec3,
+    radius: f32,
+    height: f32,
+) -> Option<f32> {
+    let half_height = height * 0.5;
+    let local = origin - center;
+    let mut closest: Option<f32> = None;
+
+    // Side
+    let a = direction.x * direction.x + direction.z * direction.z;
+    if a > f32::EPSILON {
+        let b = 2.0 * (local.x * direction.x + local.z * direction.z);
+        let c = local.x * local.x + local.z * local.z - radius * radius;
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant >= 0.0 {
+            let t = (-b - discriminant.sqrt()) / (2.0 * a);
+            let y = local.y + direction.y * t;
+            if t >= 0.0 && y.abs() <= half_height {
+                closest = Some(t);
+            }
+        }
+    }
+
+    // Caps
+    if direction.y.abs() > f32::EPSILON {
+        for cap_y in [-half_height, half_height] {
+            let t = (cap_y - local.y) / direction.y;
+            if t < 0.0 {
+                continue;
+            }
+            let point = local + direction * t;
+            if point.x * point.x + point.z * point.z <= radius * radius {
+                closest = Some(closest.map_or(t, |closest| closest.min(t)));
+            }
+        }
+    }
+
+    closest
+}
@@ -0,0 +1,117 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use boxman_shared::conditioner::{LinkConditioner, NetworkConditions, MAX_SIMULATED_DELAY};
+
+/// A socket that sends back whatever it gets, on its own thread.
+fn spawn_echo() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buffer = [0; 2048];
+        while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+            let _ = socket.send_to(&buffer[..len], from);
+        }
+    });
+    addr
+}
+
+fn conditioner(conditions: NetworkConditions) -> LinkConditioner {
+    LinkConditioner::spawn(UdpSocket::bind("127.0.0.1:0").unwrap(), spawn_echo(), conditions).unwrap()
+}
+
+fn client(timeout: Duration) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(timeout)).unwrap();
+    socket
+}
+
+/// Everything that comes back before the socket's read timeout.
+fn receive_all(socket: &UdpSocket) -> Vec<Vec<u8>> {
+    let mut buffer = [0; 2048];
+    let mut packets = Vec::new();
+    while let Ok(len) = socket.recv(&mut buffer) {
+        packets.push(buffer[..len].to_vec());
+    }
+    packets
+}
+
+#[test]
+fn latency_is_added_both_ways() {
+    let conditioner = conditioner(NetworkConditions {
+        latency: 0.05,
+        ..Default::default()
+    });
+    let socket = client(Duration::from_secs(1));
+
+    let sent_at = Instant::now();
+    socket.send_to(b"ping", conditioner.local_addr()).unwrap();
+    let mut buffer = [0; 16];
+    let len = socket.recv(&mut buffer).unwrap();
+
+    assert_eq!(&buffer[..len], b"ping");
+    assert!(sent_at.elapsed() >= Duration::from_millis(100), "{:?}", sent_at.elapsed());
+}
+
+#[test]
+fn lost_and_duplicated_packets() {
+    let conditioner = conditioner(NetworkConditions {
+        duplication: 1.0,
+        seed: Some(7),
+        ..Default::default()
+    });
+    let socket = client(Duration::from_millis(200));
+
+    // Duplicated on the way there and back.
+    socket.send_to(b"twice", conditioner.local_addr()).unwrap();
+    assert_eq!(receive_all(&socket).len(), 4);
+
+    // Changed at runtime, packets already on their way keep going.
+    conditioner.set_conditions(&NetworkConditions {
+        loss: 1.0,
+        ..Default::default()
+    });
+    socket.send_to(b"never", conditioner.local_addr()).unwrap();
+    assert!(receive_all(&socket).is_empty());
+}
+
+#[test]
+fn reordered_packets_are_overtaken() {
+    let conditioner = conditioner(NetworkConditions {
+        reorder: 1.0,
+        ..Default::default()
+    });
+    let socket = client(Duration::from_millis(300));
+
+    socket.send_to(b"first", conditioner.local_addr()).unwrap();
+    // Give the relay time to pick it up before the conditions change, it's held for longer than this.
+    thread::sleep(Duration::from_millis(20));
+    conditioner.set_conditions(&NetworkConditions::default());
+    socket.send_to(b"second", conditioner.local_addr()).unwrap();
+
+    assert_eq!(receive_all(&socket), vec![b"second".to_vec(), b"first".to_vec()]);
+}
+
+#[test]
+fn conditions_are_sanitized() {
+    let conditions = NetworkConditions {
+        latency: 1e30,
+        jitter: f32::NAN,
+        loss: 2.0,
+        duplication: -1.0,
+        reorder: 0.5,
+        seed: Some(7),
+    }.sanitized();
+
+    assert_eq!(conditions, NetworkConditions {
+        latency: MAX_SIMULATED_DELAY,
+        jitter: 0.0,
+        loss: 1.0,
+        duplication: 0.0,
+        reorder: 0.5,
+        seed: Some(7),
+    });
+}
@@ -0,0 +1,314 @@
+use std::f32::consts::PI;
+
+use bevy::prelude::*;
+use boxman_shared::{
+    character::{CharacterJump, PlayerInput},
+    protocol::{
+        bits::{BitReader, BitWriter, PackError, Quantization},
+        packed::{quantize_player_input, INPUT_DIRECTION_BITS},
+        ClientToServerMessage, Handshake, LevelHash, ServerToClientMessage, FEATURES, PROTOCOL_VERSION,
+    },
+    replication::{ComponentSnapshot, EntitySnapshot, NetworkId},
+    snapshot::{CharacterSnapshot, Snapshot, SnapshotDiff},
+};
+
+fn input(id: u32, yaw: f32, wish_dir: Vec2) -> PlayerInput {
+    PlayerInput {
+        id,
+        snapshot_id: Some(500),
+        view_snapshot_id: Some(497),
+        yaw,
+        wish_dir,
+        wish_jump: id % 2 == 0,
+        wish_fire: id % 3 == 0,
+        active_weapon: 0,
+        timestamp: id as f32 / 64.0,
+        send_count: 0,
+        post_move_velocity: Vec3::ZERO,
+        post_move_position: Vec3::ZERO,
+        post_move_grounded: false,
+        post_move_jump: CharacterJump::default(),
+    }
+}
+
+fn character(client_id: u64, index: i32) -> CharacterSnapshot {
+    CharacterSnapshot::new(
+        client_id,
+        Vec3::new(-50.0 + index as f32 * 12.5, 1.0, 40.0 - index as f32 * 9.0),
+        Vec3::new(4.0, -2.5, 3.0),
+        index as f32 * 0.7,
+        0.0,
+        index % 2 == 0,
+        100.0 - index as f32 * 7.3,
+        50.0,
+    )
+}
+
+fn snapshot(id: u64, characters: usize) -> Snapshot {
+    Snapshot {
+        id,
+        character_snapshots: (0..characters)
+            .map(|index| character(0x9e37_79b9_7f4a_7c15_u64.wrapping_mul(index as u64 + 1), index as i32))
+            .collect(),
+        entities: vec![EntitySnapshot {
+            id: NetworkId(3),
+            components: vec![ComponentSnapshot { kind: 0, data: vec![1, 2, 3, 4] }],
+        }],
+    }
+}
+
+fn round_trip_server(message: &ServerToClientMessage) -> ServerToClientMessage {
+    ServerToClientMessage::from_bytes(&message.to_bytes().unwrap()).unwrap()
+}
+
+fn snapshot_diff_bytes(snapshot_diff: SnapshotDiff) -> usize {
+    ServerToClientMessage::SnapshotDiff(snapshot_diff).to_bytes().unwrap().len()
+}
+
+#[test]
+fn bits_round_trip() {
+    let mut writer = BitWriter::new();
+    writer.write_bool(true);
+    writer.write_bits(0b101, 3);
+    writer.write_ranged(9, 10);
+    writer.write_var_u64(0);
+    writer.write_var_u64(300);
+    writer.write_var_u64(u64::MAX);
+    writer.write_var_i64(-1);
+    writer.write_var_i64(i64::MIN);
+    writer.write_f32(-1.5);
+    writer.write_bytes(&[0xde, 0xad]);
+    let bytes = writer.into_bytes();
+
+    let mut reader = BitReader::new(&bytes);
+    assert!(reader.read_bool().unwrap());
+    assert_eq!(reader.read_bits(3).unwrap(), 0b101);
+    assert_eq!(reader.read_ranged(10).unwrap(), 9);
+    assert_eq!(reader.read_var_u64().unwrap(), 0);
+    assert_eq!(reader.read_var_u64().unwrap(), 300);
+    assert_eq!(reader.read_var_u64().unwrap(), u64::MAX);
+    assert_eq!(reader.read_var_i64().unwrap(), -1);
+    assert_eq!(reader.read_var_i64().unwrap(), i64::MIN);
+    assert_eq!(reader.read_f32().unwrap(), -1.5);
+    assert_eq!(reader.read_bytes().unwrap(), vec![0xde, 0xad]);
+    assert!(reader.remaining_bits() < 8);
+}
+
+#[test]
+fn truncated_messages_are_rejected() {
+    let mut writer = BitWriter::new();
+    writer.write_var_u64(u32::MAX as u64 + 1);
+    let bytes = writer.into_bytes();
+
+    let mut reader = BitReader::new(&bytes[..bytes.len() - 1]);
+    assert_eq!(reader.read_var_u64(), Err(PackError::UnexpectedEnd));
+
+    let mut writer = BitWriter::new();
+    writer.write_bits(15, 4);
+    let bytes = writer.into_bytes();
+    let mut reader = BitReader::new(&bytes);
+    assert_eq!(reader.read_ranged(10), Err(PackError::Invalid("ranged int")));
+
+    let snapshot_diff = snapshot(10, 4).diff(None);
+    let bytes = ServerToClientMessage::SnapshotDiff(snapshot_diff).to_bytes().unwrap();
+    assert!(ServerToClientMessage::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+}
+
+#[test]
+fn quantization_keeps_the_ends_and_middle_exact() {
+    let quantization = Quantization::new(-1.0, 1.0, 10);
+    assert_eq!(quantization.round_trip(-1.0), -1.0);
+    assert_eq!(quantization.round_trip(0.0), 0.0);
+    assert_eq!(quantization.round_trip(1.0), 1.0);
+    assert_eq!(quantization.round_trip(5.0), 1.0);
+    assert!((quantization.round_trip(0.3) - 0.3).abs() <= 1.0 / 1022.0);
+}
+
+#[test]
+fn angles_and_unit_vectors_round_trip() {
+    for i in 0..64 {
+        let angle = -PI + i as f32 * 0.1;
+        let mut writer = BitWriter::new();
+        writer.write_angle(angle, 16);
+        writer.write_unit_vec2(Vec2::from_angle(angle), INPUT_DIRECTION_BITS);
+
+        let direction = Vec3::new(angle.cos(), (angle * 3.0).sin(), angle.sin() - 0.4).normalize();
+        writer.write_unit_vec3(direction, 12);
+        let bytes = writer.into_bytes();
+
+        let mut reader = BitReader::new(&bytes);
+        let read_angle = reader.read_angle(16).unwrap();
+        assert!(Vec2::from_angle(read_angle).angle_to(Vec2::from_angle(angle)).abs() < 0.0001);
+
+        let read_direction = reader.read_unit_vec2(INPUT_DIRECTION_BITS).unwrap();
+        assert!(read_direction.angle_to(Vec2::from_angle(angle)).abs() < 0.002);
+
+        let read_direction = reader.read_unit_vec3(12).unwrap();
+        assert!((read_direction.length() - 1.0).abs() < 0.0001);
+        assert!(read_direction.angle_between(direction) < 0.005, "{} vs {}", read_direction, direction);
+    }
+}
+
+#[test]
+fn player_inputs_round_trip() {
+    let mut inputs = vec![
+        input(1000, 1.2, Vec2::new(0.0, -1.0)),
+        input(1001, -2.9, Vec2::new(1.0, 1.0).normalize()),
+        input(1002, 0.0, Vec2::ZERO),
+    ];
+    inputs[2].snapshot_id = None;
+    inputs[2].view_snapshot_id = None;
+    inputs[1].active_weapon = 3;
+    for input in inputs.iter_mut() {
+        quantize_player_input(input);
+    }
+
+    let message = ClientToServerMessage::PlayerInputs(inputs.clone());
+    let Ok(ClientToServerMessage::PlayerInputs(decoded)) = ClientToServerMessage::from_bytes(&message.to_bytes().unwrap()) else {
+        panic!("Expected player inputs");
+    };
+
+    assert_eq!(decoded.len(), inputs.len());
+    for (decoded, input) in decoded.iter().zip(inputs.iter()) {
+        // Already quantized, so the server gets exactly what the client predicted with.
+        assert_eq!(decoded.id, input.id);
+        assert_eq!(decoded.snapshot_id, input.snapshot_id);
+        assert_eq!(decoded.view_snapshot_id, input.view_snapshot_id);
+        assert_eq!(decoded.yaw, input.yaw);
+        assert_eq!(decoded.wish_dir, input.wish_dir);
+        assert_eq!(decoded.wish_jump, input.wish_jump);
+        assert_eq!(decoded.wish_fire, input.wish_fire);
+        assert_eq!(decoded.active_weapon, input.active_weapon);
+        assert_eq!(decoded.timestamp, input.timestamp);
+    }
+}
+
+#[test]
+fn snapshot_diffs_round_trip() {
+    let baseline = snapshot(100, 8);
+    let mut current = snapshot(104, 8);
+    current.character_snapshots[2] = character(current.character_snapshots[2].client_id, 5);
+    current.character_snapshots[5].health = -12.5;
+    current.character_snapshots.remove(7);
+    current.entities[0].components[0].data = vec![9];
+    current.entities.push(EntitySnapshot {
+        id: NetworkId(70_000),
+        components: vec![ComponentSnapshot { kind: 2, data: Vec::new() }],
+    });
+
+    for baseline in [None, Some(&baseline)] {
+        let mut snapshot_diff = current.diff(baseline);
+        snapshot_diff.acked_input_id = Some(77);
+        snapshot_diff.stale_client_ids = vec![current.character_snapshots[1].client_id];
+
+        let ServerToClientMessage::SnapshotDiff(decoded) = round_trip_server(&ServerToClientMessage::SnapshotDiff(snapshot_diff)) else {
+            panic!("Expected a snapshot diff");
+        };
+
+        assert_eq!(decoded.id, 104);
+        assert_eq!(decoded.baseline_id, baseline.map(|baseline| baseline.id));
+        assert_eq!(decoded.acked_input_id, Some(77));
+        assert_eq!(decoded.stale_client_ids, vec![current.character_snapshots[1].client_id]);
+
+        let rebuilt = decoded.apply(baseline).unwrap();
+        assert_eq!(rebuilt.character_snapshots, current.character_snapshots);
+        assert_eq!(rebuilt.entities, current.entities);
+    }
+}
+
+#[test]
+fn other_messages_still_round_trip() {
+    let message = ServerToClientMessage::Kicked { reason: "Banned".to_string() };
+    let ServerToClientMessage::Kicked { reason } = round_trip_server(&message) else {
+        panic!("Expected a kick");
+    };
+    assert_eq!(reason, "Banned");
+
+    let message = ClientToServerMessage::Ping { client_time: 12.5 };
+    let Ok(ClientToServerMessage::Ping { client_time }) = ClientToServerMessage::from_bytes(&message.to_bytes().unwrap()) else {
+        panic!("Expected a ping");
+    };
+    assert_eq!(client_time, 12.5);
+}
+
+fn handshake() -> Handshake {
+    Handshake {
+        version: PROTOCOL_VERSION,
+        level: Some(LevelHash { name: "arena".to_string(), hash: 42 }),
+        features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+    }
+}
+
+#[test]
+fn handshakes_round_trip() {
+    let local = handshake();
+    let ServerToClientMessage::Handshake(decoded) = round_trip_server(&ServerToClientMessage::Handshake(local.clone())) else {
+        panic!("Expected a handshake");
+    };
+    assert_eq!(decoded, local);
+    assert_eq!(decoded.check(&local), Ok(()));
+
+    let mut remote = handshake();
+    remote.features.pop();
+    assert!(remote.check(&local).is_err());
+}
+
+#[test]
+fn other_versions_only_need_their_version_read() {
+    // A newer build with a field we don't know about, after the version.
+    let mut bytes = ServerToClientMessage::Handshake(handshake()).to_bytes().unwrap();
+    bytes[1..5].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+    bytes.truncate(5);
+    bytes.extend_from_slice(&[0xff; 3]);
+
+    let Ok(ServerToClientMessage::Handshake(decoded)) = ServerToClientMessage::from_bytes(&bytes) else {
+        panic!("Expected a handshake");
+    };
+    assert_eq!(decoded.version, PROTOCOL_VERSION + 1);
+    assert!(decoded.check(&handshake()).unwrap_err().contains("version"));
+}
+
+// The sizes below are what the current format comes to, with a little slack.
+// If one of these fails, the wire format got bigger, make sure that was on purpose.
+
+#[test]
+fn input_packet_size() {
+    let inputs: Vec<PlayerInput> = (0..3).map(|i| input(1000 + i, 1.0, Vec2::X)).collect();
+    let packed = ClientToServerMessage::PlayerInputs(inputs.clone()).to_bytes().unwrap();
+
+    // About 14 bytes per input, bincode takes over 40.
+    assert!(packed.len() <= 48, "{} bytes", packed.len());
+    assert!(packed.len() * 3 < bincode::serialize(&ClientToServerMessage::PlayerInputs(inputs)).unwrap().len());
+}
+
+#[test]
+fn full_snapshot_size() {
+    let snapshot_diff = snapshot(1000, 8).diff(None);
+    let bincode_size = bincode::serialize(&snapshot_diff).unwrap().len();
+    let packed = snapshot_diff_bytes(snapshot_diff);
+
+    // About 32 bytes per character, nearly all of it the position and the 64 bit client id.
+    assert!(packed <= 8 * 33 + 24, "{} bytes", packed);
+    assert!(packed < bincode_size);
+}
+
+#[test]
+fn unchanged_snapshot_size() {
+    let baseline = snapshot(1000, 8);
+    let current = snapshot(1004, 8);
+
+    // Nothing changed, so it's just the ids and empty lists.
+    let packed = snapshot_diff_bytes(current.diff(Some(&baseline)));
+    assert!(packed <= 10, "{} bytes", packed);
+}
+
+#[test]
+fn moving_character_size() {
+    let baseline = snapshot(1000, 8);
+    let mut current = snapshot(1004, 8);
+    current.character_snapshots[3].position += IVec3::new(40, 0, -40);
+
+    // The client id, the mask and a position on top of an unchanged snapshot.
+    let packed = snapshot_diff_bytes(current.diff(Some(&baseline)));
+    assert!(packed <= 10 + 17, "{} bytes", packed);
+}